@@ -1,21 +1,201 @@
-use crate::lexer::TokenType;
+use std::collections::HashMap;
+
+use crate::{keycode::Keycode, lexer::TokenType, parser};
 
 pub struct AST {
     pub statements: Vec<StatementEnum>,
+    /// Object-like `#define` macros found anywhere in the file, by name
+    pub aliases: HashMap<String, Alias>,
+    /// Layers that couldn't be parsed in keymap arrays that never close
+    pub errors: Vec<ParseError>,
+}
+
+/// What an object-like `#define` such as `#define HOME_A LGUI_T(KC_A) // GUI/A` stands for
+#[derive(Debug, PartialEq, Clone)]
+pub struct Alias {
+    pub value: String,
+    /// The comment after the definition, used as a friendly name for the key
+    pub label: Option<String>,
 }
 
+/// A statement along with the byte range the formatter rewrites, which is everything between
+/// the braces for arrays and enums, and the whole definition for combo key lists and key
+/// overrides
 #[derive(Debug, PartialEq)]
 pub enum StatementEnum {
     KeymapStatement(KeymapStatement),
-    Keymaps(usize, usize, KeymapStatements),
+    Keymaps(usize, usize, KeymapNodes),
+    Enum(usize, usize, EnumStatement),
+    EncoderMap(usize, usize, Vec<EncoderMapStatement>),
+    ComboKeys(usize, usize, ComboKeysStatement),
+    Combos(usize, usize, Vec<ComboStatement>),
+    TapDances(usize, usize, Vec<TapDanceStatement>),
+    KeyOverride(usize, usize, KeyOverrideStatement),
+    /// The entries of the `key_overrides` array, e.g. `&delete_key_override`
+    KeyOverrides(usize, usize, Vec<String>),
+}
+
+/// A key override definition, e.g.
+/// `const key_override_t delete_key_override = ko_make_basic(MOD_MASK_SHIFT, KC_BSPC, KC_DEL);`
+#[derive(Debug, PartialEq)]
+pub struct KeyOverrideStatement {
+    pub token: TokenType,
+    /// The `ko_make_*` macro the override is made with
+    pub call: TokenType,
+    pub args: Vec<String>,
+}
+
+/// An entry in `tap_dance_actions`, e.g. `[TD_ESC_CAPS] = ACTION_TAP_DANCE_DOUBLE(KC_ESC, KC_CAPS)`
+#[derive(Debug, PartialEq)]
+pub struct TapDanceStatement {
+    pub index: Option<TokenType>,
+    /// The macro the dance is declared with, such as `ACTION_TAP_DANCE_DOUBLE`
+    pub token: TokenType,
+    pub args: Vec<String>,
+}
+
+impl TapDanceStatement {
+    /// What a single and a double tap do, when the dance is one of the built in kinds
+    pub fn actions(&self) -> Option<(String, String)> {
+        let [key, other] = self.args.as_slice() else {
+            return None;
+        };
+
+        match self.token.to_string().as_str() {
+            "ACTION_TAP_DANCE_DOUBLE" => Some((key.to_string(), other.to_string())),
+            "ACTION_TAP_DANCE_LAYER_MOVE" => Some((key.to_string(), format!("TO({})", other))),
+            "ACTION_TAP_DANCE_LAYER_TOGGLE" => Some((key.to_string(), format!("TG({})", other))),
+            _ => None,
+        }
+    }
+}
+
+/// The keys that trigger a combo, e.g. `const uint16_t PROGMEM combo_jk[] = {KC_J, KC_K, COMBO_END};`
+#[derive(Debug, PartialEq)]
+pub struct ComboKeysStatement {
+    pub token: TokenType,
+    /// The name with its square brackets as written, e.g. `combo_jk[]`
+    pub declarator: String,
+    pub keys: Vec<String>,
+    /// Byte offset in the source of the start of each key
+    pub positions: Vec<usize>,
+}
+
+/// An entry in `key_combos`, e.g. `[JK_ESC] = COMBO(combo_jk, KC_ESC)`
+#[derive(Debug, PartialEq)]
+pub struct ComboStatement {
+    /// The designated index in front of the entry, if there is one
+    pub index: Option<TokenType>,
+    /// The macro the combo is declared with, such as `COMBO` or `COMBO_ACTION`
+    pub token: TokenType,
+    pub args: Vec<String>,
+}
+
+impl ComboStatement {
+    /// The name of the key list the combo is triggered by
+    pub fn keys_name(&self) -> &str {
+        self.args.first().map(|x| x.as_str()).unwrap_or("")
+    }
+
+    /// The keycode the combo sends, only known for `COMBO`
+    pub fn result(&self) -> Option<&str> {
+        match self.token.to_string() == "COMBO" {
+            true => self.args.get(1).map(|x| x.as_str()),
+            false => None,
+        }
+    }
+}
+
+/// A layer's entry in `encoder_map`, e.g. `[_BASE] = { ENCODER_CCW_CW(KC_VOLD, KC_VOLU) }`
+#[derive(Debug, PartialEq)]
+pub struct EncoderMapStatement {
+    pub token: TokenType,
+    pub encoders: Vec<Encoder>,
+    /// The layer number the index resolves to, when it can be worked out
+    pub index: Option<usize>,
 }
 
-type KeymapStatements = Vec<KeymapStatement>;
+impl EncoderMapStatement {
+    /// Whether this entry holds the encoder bindings for a keymap layer
+    pub fn is_for(&self, keymap: &KeymapStatement) -> bool {
+        match (self.index, keymap.index) {
+            (Some(x), Some(y)) => x == y,
+            _ => self.token.to_string() == keymap.token.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Encoder {
+    /// Byte offset in the source of the `ENCODER_CCW_CW`
+    pub position: usize,
+    pub ccw: String,
+    pub cw: String,
+}
+
+/// An `enum` declaration, such as the one naming the layers
+#[derive(Debug, PartialEq)]
+pub struct EnumStatement {
+    /// The enum's name, missing for an anonymous enum
+    pub token: Option<TokenType>,
+    pub members: Vec<EnumMember>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct EnumMember {
+    pub token: TokenType,
+    /// The member's value, when it can be worked out from `= N` values and earlier members
+    pub value: Option<i64>,
+    /// Byte range in the source of the expression after the member's `=`
+    pub expression: Option<(usize, usize)>,
+}
+
+type KeymapNodes = Vec<KeymapNode>;
+
+/// An entry between the braces of the keymaps array
+#[derive(Debug, PartialEq)]
+pub enum KeymapNode {
+    Keymap(KeymapStatement),
+    Conditional(Conditional),
+    /// A preprocessor line that isn't part of a conditional, e.g. `#define`
+    Directive(TokenType),
+    /// A layer that couldn't be parsed, which is left exactly as it was written
+    Error(ParseError),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    /// Byte range in the source of the broken layer
+    pub start: usize,
+    pub end: usize,
+    pub message: String,
+}
+
+/// An `#if`/`#ifdef` block with its `#elif`/`#else` branches
+#[derive(Debug, PartialEq)]
+pub struct Conditional {
+    pub branches: Vec<ConditionalBranch>,
+    /// The closing `#endif`, missing when the block is never closed
+    pub end: Option<TokenType>,
+}
 
 #[derive(Debug, PartialEq)]
+pub struct ConditionalBranch {
+    pub directive: TokenType,
+    pub nodes: KeymapNodes,
+    /// Whether the branch is compiled, only known when the defined symbols were supplied
+    pub active: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct KeymapStatement {
     pub token: TokenType,
     pub layout_statement: LayoutStatement,
+    /// The layer number the index resolves to, when it can be worked out
+    pub index: Option<usize>,
+    /// Byte range in the source of the whole entry, from its `[` to just past the comma
+    /// after it
+    pub span: (usize, usize),
 }
 
 impl KeymapStatement {
@@ -23,24 +203,232 @@ impl KeymapStatement {
         Self {
             token,
             layout_statement,
+            index: None,
+            span: (0, 0),
         }
     }
+
+    pub fn with_span(mut self, start: usize, end: usize) -> Self {
+        self.span = (start, end);
+        self
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct LayoutStatement {
     pub token: TokenType,
     pub keys: Vec<String>,
+    /// Byte offset in the source of the start of each key
+    pub positions: Vec<usize>,
+    /// Preprocessor lines found between the keys
+    pub directives: Vec<TokenType>,
 }
 
 impl LayoutStatement {
     pub fn new(token: TokenType, keys: Vec<String>) -> Self {
-        Self { token, keys }
+        Self {
+            token,
+            keys,
+            positions: vec![],
+            directives: vec![],
+        }
+    }
+
+    pub fn with_positions(mut self, positions: Vec<usize>) -> Self {
+        self.positions = positions;
+        self
     }
 }
 
 impl AST {
     pub fn new() -> Self {
-        Self { statements: vec![] }
+        Self {
+            statements: vec![],
+            aliases: HashMap::new(),
+            errors: vec![],
+        }
+    }
+
+    /// Replaces any aliases in a key with the keycode they stand for
+    pub fn expand_key(&self, key: &str) -> String {
+        let resolve = |name: &str| self.aliases.get(name).map(|x| x.value.as_str());
+        match self.aliases.is_empty() {
+            true => key.to_string(),
+            false => Keycode::parse(key).expand(&resolve).to_string(),
+        }
+    }
+
+    /// The layers from `keymaps` with every alias expanded, for checks that need to know
+    /// what each key actually does
+    pub fn expanded_keymaps(&self) -> Vec<KeymapStatement> {
+        self.keymaps()
+            .into_iter()
+            .map(|keymap| {
+                let mut keymap = keymap.clone();
+                for key in keymap.layout_statement.keys.iter_mut() {
+                    *key = self.expand_key(key);
+                }
+                keymap
+            })
+            .collect()
+    }
+
+    /// All layers from every `Keymaps` statement in source order, skipping conditional
+    /// branches known to be inactive
+    pub fn keymaps(&self) -> Vec<&KeymapStatement> {
+        let mut keymaps: Vec<&KeymapStatement> = vec![];
+        for statement in &self.statements {
+            if let StatementEnum::Keymaps(_, _, nodes) = statement {
+                collect_keymaps(nodes, &mut keymaps);
+            }
+        }
+
+        keymaps
+    }
+
+    /// Every layer that couldn't be parsed, in source order
+    pub fn errors(&self) -> Vec<&ParseError> {
+        let mut errors: Vec<&ParseError> = vec![];
+        for statement in &self.statements {
+            if let StatementEnum::Keymaps(_, _, nodes) = statement {
+                collect_errors(nodes, &mut errors);
+            }
+        }
+        errors.extend(&self.errors);
+
+        errors
+    }
+
+    /// The entries of the first `encoder_map` in the file
+    pub fn encoder_map(&self) -> Option<&[EncoderMapStatement]> {
+        self.statements
+            .iter()
+            .find_map(|statement| match statement {
+                StatementEnum::EncoderMap(_, _, x) => Some(x.as_slice()),
+                _ => None,
+            })
+    }
+
+    /// Every combo key list in the file, in source order
+    pub fn combo_keys(&self) -> Vec<(usize, usize, &ComboKeysStatement)> {
+        self.statements
+            .iter()
+            .filter_map(|statement| match statement {
+                StatementEnum::ComboKeys(start, end, x) => Some((*start, *end, x)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The entries of the first `key_combos` in the file
+    pub fn combos(&self) -> Option<&[ComboStatement]> {
+        self.statements
+            .iter()
+            .find_map(|statement| match statement {
+                StatementEnum::Combos(_, _, x) => Some(x.as_slice()),
+                _ => None,
+            })
+    }
+
+    /// Every key override definition in the file, in source order
+    pub fn key_overrides(&self) -> Vec<(usize, usize, &KeyOverrideStatement)> {
+        self.statements
+            .iter()
+            .filter_map(|statement| match statement {
+                StatementEnum::KeyOverride(start, end, x) => Some((*start, *end, x)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The entries of the first `tap_dance_actions` in the file
+    pub fn tap_dances(&self) -> Option<&[TapDanceStatement]> {
+        self.statements
+            .iter()
+            .find_map(|statement| match statement {
+                StatementEnum::TapDances(_, _, x) => Some(x.as_slice()),
+                _ => None,
+            })
+    }
+
+    /// The dance a `TD(...)` index refers to
+    pub fn get_tap_dance(&self, index: &str) -> Option<&TapDanceStatement> {
+        self.tap_dances()?
+            .iter()
+            .find(|x| x.index.as_ref().is_some_and(|x| x.to_string() == index))
+    }
+
+    /// The single and double tap actions of a `TD(...)` key, e.g. `KC_ESC/KC_CAPS`
+    pub fn get_tap_dance_label(&self, key: &str) -> Option<String> {
+        let Keycode::Function(name, args) = Keycode::parse(key) else {
+            return None;
+        };
+        let [index] = args.as_slice() else {
+            return None;
+        };
+        if name != "TD" {
+            return None;
+        }
+
+        let (single, double) = self.get_tap_dance(&index.to_string())?.actions()?;
+        Some(format!("{}/{}", single, double))
+    }
+
+    /// The key list a combo entry is triggered by
+    pub fn get_combo_keys(&self, combo: &ComboStatement) -> Option<&ComboKeysStatement> {
+        self.combo_keys()
+            .into_iter()
+            .map(|(_, _, x)| x)
+            .find(|x| x.token.to_string() == combo.keys_name())
+    }
+
+    /// Every enum member and numeric alias whose value is known, for resolving layer numbers
+    pub fn layer_symbols(&self) -> HashMap<String, i64> {
+        let mut symbols: HashMap<String, i64> = HashMap::new();
+        for statement in &self.statements {
+            if let StatementEnum::Enum(_, _, x) = statement {
+                for member in &x.members {
+                    if let Some(value) = member.value {
+                        symbols.insert(member.token.to_string(), value);
+                    }
+                }
+            }
+        }
+
+        for (name, alias) in &self.aliases {
+            if let Some(value) = parser::evaluate_constant(&alias.value) {
+                symbols.entry(name.to_string()).or_insert(value);
+            }
+        }
+
+        symbols
+    }
+}
+
+fn collect_keymaps<'a>(nodes: &'a [KeymapNode], keymaps: &mut Vec<&'a KeymapStatement>) {
+    for node in nodes {
+        match node {
+            KeymapNode::Keymap(x) => keymaps.push(x),
+            KeymapNode::Conditional(x) => {
+                for branch in x.branches.iter().filter(|x| x.active != Some(false)) {
+                    collect_keymaps(&branch.nodes, keymaps);
+                }
+            }
+            KeymapNode::Directive(..) | KeymapNode::Error(..) => {}
+        }
+    }
+}
+
+fn collect_errors<'a>(nodes: &'a [KeymapNode], errors: &mut Vec<&'a ParseError>) {
+    for node in nodes {
+        match node {
+            KeymapNode::Error(x) => errors.push(x),
+            KeymapNode::Conditional(x) => {
+                for branch in &x.branches {
+                    collect_errors(&branch.nodes, errors);
+                }
+            }
+            KeymapNode::Keymap(..) | KeymapNode::Directive(..) => {}
+        }
     }
 }
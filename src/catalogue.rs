@@ -0,0 +1,448 @@
+use std::sync::OnceLock;
+
+use Argument::*;
+
+// Keycodes that QMK defines, beyond the generated letter, number and function key names
+const BASIC_KEYCODES: &[&str] = &[
+    "KC_NO",
+    "XXXXXXX",
+    "KC_TRNS",
+    "KC_TRANSPARENT",
+    "KC_ENT",
+    "KC_ENTER",
+    "KC_ESC",
+    "KC_ESCAPE",
+    "KC_BSPC",
+    "KC_BACKSPACE",
+    "KC_TAB",
+    "KC_SPC",
+    "KC_SPACE",
+    "KC_MINS",
+    "KC_MINUS",
+    "KC_EQL",
+    "KC_EQUAL",
+    "KC_LBRC",
+    "KC_LEFT_BRACKET",
+    "KC_RBRC",
+    "KC_RIGHT_BRACKET",
+    "KC_BSLS",
+    "KC_BACKSLASH",
+    "KC_NUHS",
+    "KC_SCLN",
+    "KC_SEMICOLON",
+    "KC_QUOT",
+    "KC_QUOTE",
+    "KC_GRV",
+    "KC_GRAVE",
+    "KC_COMM",
+    "KC_COMMA",
+    "KC_DOT",
+    "KC_SLSH",
+    "KC_SLASH",
+    "KC_CAPS",
+    "KC_CAPS_LOCK",
+    "KC_PSCR",
+    "KC_PRINT_SCREEN",
+    "KC_SCRL",
+    "KC_SCROLL_LOCK",
+    "KC_PAUS",
+    "KC_PAUSE",
+    "KC_BRK",
+    "KC_INS",
+    "KC_INSERT",
+    "KC_HOME",
+    "KC_PGUP",
+    "KC_PAGE_UP",
+    "KC_DEL",
+    "KC_DELETE",
+    "KC_END",
+    "KC_PGDN",
+    "KC_PAGE_DOWN",
+    "KC_EXEC",
+    "KC_EXECUTE",
+    "KC_RGHT",
+    "KC_RIGHT",
+    "KC_LEFT",
+    "KC_DOWN",
+    "KC_UP",
+    "KC_NUM",
+    "KC_NUM_LOCK",
+    "KC_PSLS",
+    "KC_PAST",
+    "KC_PMNS",
+    "KC_PPLS",
+    "KC_PENT",
+    "KC_PDOT",
+    "KC_PEQL",
+    "KC_PCMM",
+    "KC_NUBS",
+    "KC_APP",
+    "KC_APPLICATION",
+    "KC_LCTL",
+    "KC_LEFT_CTRL",
+    "KC_LSFT",
+    "KC_LEFT_SHIFT",
+    "KC_LALT",
+    "KC_LEFT_ALT",
+    "KC_LOPT",
+    "KC_LGUI",
+    "KC_LEFT_GUI",
+    "KC_LCMD",
+    "KC_LWIN",
+    "KC_RCTL",
+    "KC_RIGHT_CTRL",
+    "KC_RSFT",
+    "KC_RIGHT_SHIFT",
+    "KC_RALT",
+    "KC_RIGHT_ALT",
+    "KC_ROPT",
+    "KC_ALGR",
+    "KC_RGUI",
+    "KC_RIGHT_GUI",
+    "KC_RCMD",
+    "KC_RWIN",
+    "KC_TILD",
+    "KC_TILDE",
+    "KC_EXLM",
+    "KC_EXCLAIM",
+    "KC_AT",
+    "KC_HASH",
+    "KC_DLR",
+    "KC_DOLLAR",
+    "KC_PERC",
+    "KC_PERCENT",
+    "KC_CIRC",
+    "KC_CIRCUMFLEX",
+    "KC_AMPR",
+    "KC_AMPERSAND",
+    "KC_ASTR",
+    "KC_ASTERISK",
+    "KC_LPRN",
+    "KC_LEFT_PAREN",
+    "KC_RPRN",
+    "KC_RIGHT_PAREN",
+    "KC_UNDS",
+    "KC_UNDERSCORE",
+    "KC_PLUS",
+    "KC_LCBR",
+    "KC_LEFT_CURLY_BRACE",
+    "KC_RCBR",
+    "KC_RIGHT_CURLY_BRACE",
+    "KC_PIPE",
+    "KC_COLN",
+    "KC_COLON",
+    "KC_DQUO",
+    "KC_DOUBLE_QUOTE",
+    "KC_DQT",
+    "KC_LABK",
+    "KC_LT",
+    "KC_RABK",
+    "KC_GT",
+    "KC_QUES",
+    "KC_QUESTION",
+    "KC_MUTE",
+    "KC_AUDIO_MUTE",
+    "KC_VOLU",
+    "KC_AUDIO_VOL_UP",
+    "KC_VOLD",
+    "KC_AUDIO_VOL_DOWN",
+    "KC_MNXT",
+    "KC_MEDIA_NEXT_TRACK",
+    "KC_MPRV",
+    "KC_MEDIA_PREV_TRACK",
+    "KC_MSTP",
+    "KC_MEDIA_STOP",
+    "KC_MPLY",
+    "KC_MEDIA_PLAY_PAUSE",
+    "KC_MSEL",
+    "KC_EJCT",
+    "KC_MAIL",
+    "KC_CALC",
+    "KC_MYCM",
+    "KC_WSCH",
+    "KC_WHOM",
+    "KC_WBAK",
+    "KC_WFWD",
+    "KC_WSTP",
+    "KC_WREF",
+    "KC_WFAV",
+    "KC_MFFD",
+    "KC_MRWD",
+    "KC_BRIU",
+    "KC_BRID",
+    "KC_PWR",
+    "KC_SLEP",
+    "KC_WAKE",
+    "KC_MS_U",
+    "KC_MS_D",
+    "KC_MS_L",
+    "KC_MS_R",
+    "KC_BTN1",
+    "KC_BTN2",
+    "KC_BTN3",
+    "KC_BTN4",
+    "KC_BTN5",
+    "KC_WH_U",
+    "KC_WH_D",
+    "KC_WH_L",
+    "KC_WH_R",
+    "KC_ACL0",
+    "KC_ACL1",
+    "KC_ACL2",
+    "MS_UP",
+    "MS_DOWN",
+    "MS_LEFT",
+    "MS_RGHT",
+    "MS_BTN1",
+    "MS_BTN2",
+    "MS_BTN3",
+    "MS_WHLU",
+    "MS_WHLD",
+    "MS_WHLL",
+    "MS_WHLR",
+    "MS_ACL0",
+    "MS_ACL1",
+    "MS_ACL2",
+    "KC_MEH",
+    "KC_HYPR",
+    "KC_LSPO",
+    "KC_RSPC",
+    "KC_LCPO",
+    "KC_RCPC",
+    "KC_LAPO",
+    "KC_RAPC",
+    "KC_SFTENT",
+    "QK_BOOT",
+    "QK_BOOTLOADER",
+    "RESET",
+    "QK_RBT",
+    "QK_REBOOT",
+    "QK_DEBUG_TOGGLE",
+    "DB_TOGG",
+    "QK_CLEAR_EEPROM",
+    "EE_CLR",
+    "QK_MAKE",
+    "QK_GESC",
+    "QK_GRAVE_ESCAPE",
+    "QK_CAPS_WORD_TOGGLE",
+    "CW_TOGG",
+    "QK_LEAD",
+    "QK_LEADER",
+    "QK_LOCK",
+    "QK_REP",
+    "QK_REPEAT_KEY",
+    "QK_AREP",
+    "QK_ALT_REPEAT_KEY",
+    "QK_LLCK",
+    "QK_LAYER_LOCK",
+    "AS_TOGG",
+    "AS_UP",
+    "AS_DOWN",
+    "CM_TOGG",
+    "NK_TOGG",
+    "AC_TOGG",
+    "RGB_TOG",
+    "RGB_MOD",
+    "RGB_RMOD",
+    "RGB_HUI",
+    "RGB_HUD",
+    "RGB_SAI",
+    "RGB_SAD",
+    "RGB_VAI",
+    "RGB_VAD",
+    "RGB_SPI",
+    "RGB_SPD",
+    "RM_TOGG",
+    "RM_NEXT",
+    "RM_PREV",
+    "RM_HUEU",
+    "RM_HUED",
+    "RM_SATU",
+    "RM_SATD",
+    "RM_VALU",
+    "RM_VALD",
+    "RM_SPDU",
+    "RM_SPDD",
+    "UG_TOGG",
+    "UG_NEXT",
+    "UG_PREV",
+    "UG_HUEU",
+    "UG_HUED",
+    "UG_SATU",
+    "UG_SATD",
+    "UG_VALU",
+    "UG_VALD",
+    "UG_SPDU",
+    "UG_SPDD",
+    "BL_TOGG",
+    "BL_STEP",
+    "BL_ON",
+    "BL_OFF",
+    "BL_UP",
+    "BL_DOWN",
+    "BL_BRTG",
+    "OU_AUTO",
+    "OU_USB",
+    "OU_BT",
+    "DM_REC1",
+    "DM_REC2",
+    "DM_RSTP",
+    "DM_PLY1",
+    "DM_PLY2",
+    "SAFE_RANGE",
+];
+
+const MODIFIERS: &[&str] = &[
+    "MOD_LCTL", "MOD_LSFT", "MOD_LALT", "MOD_LGUI", "MOD_RCTL", "MOD_RSFT", "MOD_RALT", "MOD_RGUI",
+    "MOD_HYPR", "MOD_MEH",
+];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Argument {
+    Keycode,
+    Layer,
+    Modifier,
+    /// The index of an entry in `tap_dance_actions`
+    TapDance,
+    Any,
+}
+
+const FUNCTION_KEYCODES: &[(&str, &[Argument])] = &[
+    ("MO", &[Layer]),
+    ("TG", &[Layer]),
+    ("TO", &[Layer]),
+    ("TT", &[Layer]),
+    ("DF", &[Layer]),
+    ("PDF", &[Layer]),
+    ("OSL", &[Layer]),
+    ("LT", &[Layer, Keycode]),
+    ("LM", &[Layer, Modifier]),
+    ("OSM", &[Modifier]),
+    ("MT", &[Modifier, Keycode]),
+    ("LCTL", &[Keycode]),
+    ("LSFT", &[Keycode]),
+    ("LALT", &[Keycode]),
+    ("LGUI", &[Keycode]),
+    ("LOPT", &[Keycode]),
+    ("LCMD", &[Keycode]),
+    ("LWIN", &[Keycode]),
+    ("RCTL", &[Keycode]),
+    ("RSFT", &[Keycode]),
+    ("RALT", &[Keycode]),
+    ("RGUI", &[Keycode]),
+    ("ROPT", &[Keycode]),
+    ("RCMD", &[Keycode]),
+    ("RWIN", &[Keycode]),
+    ("ALGR", &[Keycode]),
+    ("C", &[Keycode]),
+    ("S", &[Keycode]),
+    ("A", &[Keycode]),
+    ("G", &[Keycode]),
+    ("HYPR", &[Keycode]),
+    ("MEH", &[Keycode]),
+    ("LCAG", &[Keycode]),
+    ("LSG", &[Keycode]),
+    ("SGUI", &[Keycode]),
+    ("LAG", &[Keycode]),
+    ("LCA", &[Keycode]),
+    ("LSA", &[Keycode]),
+    ("RSA", &[Keycode]),
+    ("RCS", &[Keycode]),
+    ("LCTL_T", &[Keycode]),
+    ("CTL_T", &[Keycode]),
+    ("LSFT_T", &[Keycode]),
+    ("SFT_T", &[Keycode]),
+    ("LALT_T", &[Keycode]),
+    ("ALT_T", &[Keycode]),
+    ("LOPT_T", &[Keycode]),
+    ("LGUI_T", &[Keycode]),
+    ("GUI_T", &[Keycode]),
+    ("LCMD_T", &[Keycode]),
+    ("LWIN_T", &[Keycode]),
+    ("RCTL_T", &[Keycode]),
+    ("RSFT_T", &[Keycode]),
+    ("RALT_T", &[Keycode]),
+    ("ALGR_T", &[Keycode]),
+    ("RGUI_T", &[Keycode]),
+    ("RCMD_T", &[Keycode]),
+    ("C_S_T", &[Keycode]),
+    ("MEH_T", &[Keycode]),
+    ("HYPR_T", &[Keycode]),
+    ("ALL_T", &[Keycode]),
+    ("LCA_T", &[Keycode]),
+    ("LSG_T", &[Keycode]),
+    ("SGUI_T", &[Keycode]),
+    ("LCAG_T", &[Keycode]),
+    ("TD", &[TapDance]),
+    ("QK_KB", &[Any]),
+    ("QK_USER", &[Any]),
+    ("UC", &[Any]),
+    ("UM", &[Any]),
+    ("UP", &[Any, Any]),
+];
+
+pub fn basic_keycodes() -> &'static [String] {
+    static KEYCODES: OnceLock<Vec<String>> = OnceLock::new();
+
+    KEYCODES.get_or_init(|| {
+        let mut keycodes: Vec<String> = BASIC_KEYCODES.iter().map(|x| x.to_string()).collect();
+        keycodes.extend(('A'..='Z').map(|x| format!("KC_{}", x)));
+        keycodes.extend(('0'..='9').map(|x| format!("KC_{}", x)));
+        keycodes.extend(('0'..='9').map(|x| format!("KC_P{}", x)));
+        keycodes.extend(('0'..='9').map(|x| format!("KC_KP_{}", x)));
+        keycodes.extend((1..=9).map(|x| format!("KC_INT{}", x)));
+        keycodes.extend((1..=9).map(|x| format!("KC_LNG{}", x)));
+        keycodes.extend((1..=24).map(|x| format!("KC_F{}", x)));
+
+        keycodes
+    })
+}
+
+pub fn is_basic_keycode(name: &str) -> bool {
+    basic_keycodes().iter().any(|x| x == name)
+}
+
+pub fn is_modifier(name: &str) -> bool {
+    MODIFIERS.contains(&name)
+}
+
+pub fn function_keycodes() -> Vec<String> {
+    FUNCTION_KEYCODES
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// Returns the expected arguments for a function keycode such as `LT`, or `None` if it isn't one
+pub fn function_arguments(name: &str) -> Option<&'static [Argument]> {
+    FUNCTION_KEYCODES
+        .iter()
+        .find(|(x, _)| *x == name)
+        .map(|(_, args)| *args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_keycodes() {
+        assert!(is_basic_keycode("KC_ENT"));
+        assert!(is_basic_keycode("KC_F24"));
+        assert!(is_basic_keycode("QK_BOOT"));
+        assert!(is_basic_keycode("RESET"));
+        assert!(is_basic_keycode("KC_KP_7"));
+        assert!(is_basic_keycode("KC_INT1"));
+        assert!(is_basic_keycode("KC_LNG2"));
+        assert!(is_basic_keycode("KC_EXEC"));
+        assert!(!is_basic_keycode("KC_CPYP"));
+        assert!(!is_basic_keycode("KC_ENTR"));
+    }
+
+    #[test]
+    fn test_function_arguments() {
+        assert_eq!(Some(&[Layer, Keycode][..]), function_arguments("LT"));
+        assert_eq!(Some(&[Modifier][..]), function_arguments("OSM"));
+        assert_eq!(None, function_arguments("KC_A"));
+    }
+}
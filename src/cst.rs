@@ -0,0 +1,464 @@
+use crate::lexer::{Lexer, TokenType};
+
+/// Source text between tokens that the parser doesn't care about
+#[derive(Debug, PartialEq, Clone)]
+pub enum Trivia {
+    Whitespace(String),
+    Comment(String),
+}
+
+impl std::fmt::Display for Trivia {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trivia::Whitespace(x) | Trivia::Comment(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// A token along with the trivia around it. Trailing trivia runs up to and including the end
+/// of the token's line, everything else before a token is its leading trivia.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SyntaxToken {
+    pub leading: Vec<Trivia>,
+    /// `EOF` for a token that only holds the trivia at the end of a node
+    pub token: TokenType,
+    /// The token exactly as it was written
+    pub text: String,
+    pub trailing: Vec<Trivia>,
+    /// Byte offset in the source of the start of the leading trivia
+    pub start: usize,
+}
+
+impl std::fmt::Display for SyntaxToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for trivia in &self.leading {
+            write!(f, "{}", trivia)?;
+        }
+        write!(f, "{}", self.text)?;
+        for trivia in &self.trailing {
+            write!(f, "{}", trivia)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SyntaxNode {
+    /// Source the formatter doesn't own, printed back exactly as it was written
+    Opaque(Vec<SyntaxToken>),
+    /// A region the formatter owns, printed as `text` in place of its tokens
+    Owned {
+        tokens: Vec<SyntaxToken>,
+        text: String,
+    },
+}
+
+/// A lossless view of a file, where printing the tree without handing any of it to the
+/// formatter gives back the source byte for byte
+#[derive(Debug, PartialEq)]
+pub struct SyntaxTree {
+    pub nodes: Vec<SyntaxNode>,
+}
+
+impl SyntaxTree {
+    pub fn new(content: &str) -> Self {
+        Self {
+            nodes: vec![SyntaxNode::Opaque(group_pieces(get_pieces(content)))],
+        }
+    }
+
+    /// Hands the source in `start..end` over to the formatter, to be printed as `text`.
+    /// Fails when the range cuts through a token or a region that's already owned.
+    pub fn own(&mut self, start: usize, end: usize, text: String) -> Result<(), ()> {
+        let index = self
+            .nodes
+            .iter()
+            .position(|node| match node {
+                SyntaxNode::Opaque(tokens) => {
+                    let (node_start, node_end) = get_span(tokens);
+                    node_start <= start && end <= node_end
+                }
+                SyntaxNode::Owned { .. } => false,
+            })
+            .ok_or(())?;
+
+        let SyntaxNode::Opaque(tokens) = &self.nodes[index] else {
+            return Err(());
+        };
+
+        let mut before: Vec<Piece> = vec![];
+        let mut inside: Vec<Piece> = vec![];
+        let mut after: Vec<Piece> = vec![];
+        for piece in flatten_tokens(tokens) {
+            for piece in split_piece(piece, start, end)? {
+                match piece.start {
+                    x if x < start => before.push(piece),
+                    x if x < end => inside.push(piece),
+                    _ => after.push(piece),
+                }
+            }
+        }
+
+        let mut nodes: Vec<SyntaxNode> = vec![];
+        if !before.is_empty() {
+            nodes.push(SyntaxNode::Opaque(group_pieces(before)));
+        }
+        nodes.push(SyntaxNode::Owned {
+            tokens: group_pieces(inside),
+            text,
+        });
+        if !after.is_empty() {
+            nodes.push(SyntaxNode::Opaque(group_pieces(after)));
+        }
+        self.nodes.splice(index..index + 1, nodes);
+
+        Ok(())
+    }
+
+    /// Widens `start..end` to take in the leading trivia of the token starting at `start` and
+    /// the trailing trivia of the token ending at `end`, so a copy of the range keeps the
+    /// comments and indentation before it and the rest of its last line
+    pub fn get_full_span(&self, start: usize, end: usize) -> (usize, usize) {
+        let mut span = (start, end);
+        for node in &self.nodes {
+            let tokens = match node {
+                SyntaxNode::Opaque(x) | SyntaxNode::Owned { tokens: x, .. } => x,
+            };
+
+            for token in tokens.iter().filter(|x| x.token != TokenType::EOF) {
+                let text_start = token.start + get_trivia_len(&token.leading);
+                let text_end = text_start + token.text.len();
+                if text_start == start {
+                    span.0 = token.start;
+                }
+                if text_end == end {
+                    span.1 = text_end + get_trivia_len(&token.trailing);
+                }
+            }
+        }
+
+        span
+    }
+
+    /// The source in `start..end` as it was written, printed from the tree's tokens
+    pub fn text(&self, start: usize, end: usize) -> String {
+        let mut text = String::new();
+        for node in &self.nodes {
+            let tokens = match node {
+                SyntaxNode::Opaque(x) | SyntaxNode::Owned { tokens: x, .. } => x,
+            };
+
+            for piece in flatten_tokens(tokens) {
+                let piece_end = piece.start + piece.text.len();
+                if piece_end <= start || end <= piece.start {
+                    continue;
+                }
+
+                let from = start.saturating_sub(piece.start);
+                let to = piece.text.len() - piece_end.saturating_sub(end);
+                text += piece.text.get(from..to).unwrap_or(&piece.text);
+            }
+        }
+
+        text
+    }
+}
+
+impl std::fmt::Display for SyntaxTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for node in &self.nodes {
+            match node {
+                SyntaxNode::Opaque(tokens) => {
+                    for token in tokens {
+                        write!(f, "{}", token)?;
+                    }
+                }
+                SyntaxNode::Owned { text, .. } => write!(f, "{}", text)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum PieceKind {
+    Whitespace,
+    Comment,
+    Token(TokenType),
+}
+
+/// A run of the source that's either a single token or a single piece of trivia
+#[derive(Debug, PartialEq, Clone)]
+struct Piece {
+    start: usize,
+    text: String,
+    kind: PieceKind,
+}
+
+/// Cuts the source into tokens and the whitespace between them, so that joining the pieces
+/// gives back the source
+fn get_pieces(content: &str) -> Vec<Piece> {
+    let mut pieces: Vec<Piece> = vec![];
+    let mut lexer = Lexer::new(content);
+    let mut copied = 0;
+
+    loop {
+        let token = lexer.next_token();
+        let Some(start) = token.start() else {
+            break;
+        };
+
+        // Tokens that start inside the previous one, e.g. the rest of a multi-byte character
+        if start < copied {
+            continue;
+        }
+
+        let mut end = lexer.offset().max(start + 1).min(content.len());
+        while !content.is_char_boundary(end) {
+            end += 1;
+        }
+
+        if copied < start {
+            pieces.push(Piece {
+                start: copied,
+                text: content[copied..start].to_string(),
+                kind: PieceKind::Whitespace,
+            });
+        }
+
+        let kind = match token {
+            TokenType::Comment(..) => PieceKind::Comment,
+            _ => PieceKind::Token(token),
+        };
+        pieces.push(Piece {
+            start,
+            text: content[start..end].to_string(),
+            kind,
+        });
+        copied = end;
+    }
+
+    if copied < content.len() {
+        pieces.push(Piece {
+            start: copied,
+            text: content[copied..].to_string(),
+            kind: PieceKind::Whitespace,
+        });
+    }
+
+    pieces
+}
+
+/// Attaches each piece of trivia to a token, ending with a token that holds whatever trivia
+/// is left over at the end
+fn group_pieces(pieces: Vec<Piece>) -> Vec<SyntaxToken> {
+    let mut tokens: Vec<SyntaxToken> = vec![];
+    let mut leading: Vec<Trivia> = vec![];
+    let mut start: Option<usize> = None;
+    // Whether trivia still belongs to the end of the previous token's line
+    let mut trailing = false;
+
+    for piece in pieces {
+        let trivia = match piece.kind {
+            PieceKind::Whitespace => Trivia::Whitespace(piece.text),
+            PieceKind::Comment => Trivia::Comment(piece.text),
+            PieceKind::Token(token) => {
+                tokens.push(SyntaxToken {
+                    leading: std::mem::take(&mut leading),
+                    token,
+                    text: piece.text,
+                    trailing: vec![],
+                    start: start.take().unwrap_or(piece.start),
+                });
+                trailing = true;
+                continue;
+            }
+        };
+
+        match (trailing, tokens.last_mut()) {
+            (true, Some(token)) => {
+                // Split whitespace so the line break ends the token's line and the
+                // indentation after it leads the next token
+                if let Trivia::Whitespace(text) = &trivia {
+                    if let Some(x) = text.find('\n') {
+                        let (own, rest) = text.split_at(x + 1);
+                        token.trailing.push(Trivia::Whitespace(own.to_string()));
+                        trailing = false;
+                        if !rest.is_empty() {
+                            start = Some(piece.start + x + 1);
+                            leading.push(Trivia::Whitespace(rest.to_string()));
+                        }
+                        continue;
+                    }
+                }
+                token.trailing.push(trivia);
+            }
+            _ => {
+                start.get_or_insert(piece.start);
+                leading.push(trivia);
+            }
+        }
+    }
+
+    if !leading.is_empty() {
+        tokens.push(SyntaxToken {
+            leading,
+            token: TokenType::EOF,
+            text: String::new(),
+            trailing: vec![],
+            start: start.unwrap_or(0),
+        });
+    }
+
+    tokens
+}
+
+/// The pieces a run of tokens was built from, in source order
+fn flatten_tokens(tokens: &[SyntaxToken]) -> Vec<Piece> {
+    let mut pieces: Vec<Piece> = vec![];
+    for token in tokens {
+        let mut position = token.start;
+        push_trivia(&token.leading, &mut position, &mut pieces);
+
+        if token.token != TokenType::EOF {
+            pieces.push(Piece {
+                start: position,
+                text: token.text.to_string(),
+                kind: PieceKind::Token(token.token.clone()),
+            });
+        }
+
+        position += token.text.len();
+        push_trivia(&token.trailing, &mut position, &mut pieces);
+    }
+
+    pieces
+}
+
+fn push_trivia(trivia: &[Trivia], position: &mut usize, pieces: &mut Vec<Piece>) {
+    for trivia in trivia {
+        let (text, kind) = match trivia {
+            Trivia::Whitespace(x) => (x, PieceKind::Whitespace),
+            Trivia::Comment(x) => (x, PieceKind::Comment),
+        };
+        pieces.push(Piece {
+            start: *position,
+            text: text.to_string(),
+            kind,
+        });
+        *position += text.len();
+    }
+}
+
+/// Cuts a piece of whitespace where the range starts or ends inside it
+fn split_piece(piece: Piece, start: usize, end: usize) -> Result<Vec<Piece>, ()> {
+    let piece_end = piece.start + piece.text.len();
+    let cuts: Vec<usize> = [start, end]
+        .into_iter()
+        .filter(|x| piece.start < *x && *x < piece_end)
+        .collect();
+
+    if cuts.is_empty() {
+        return Ok(vec![piece]);
+    }
+    if piece.kind != PieceKind::Whitespace {
+        return Err(());
+    }
+
+    let mut pieces: Vec<Piece> = vec![];
+    let mut from = piece.start;
+    for cut in cuts.into_iter().chain([piece_end]) {
+        pieces.push(Piece {
+            start: from,
+            text: piece.text[from - piece.start..cut - piece.start].to_string(),
+            kind: PieceKind::Whitespace,
+        });
+        from = cut;
+    }
+
+    Ok(pieces)
+}
+
+fn get_trivia_len(trivia: &[Trivia]) -> usize {
+    trivia.iter().map(|x| x.to_string().len()).sum()
+}
+
+/// The byte range a run of tokens covers in the source
+fn get_span(tokens: &[SyntaxToken]) -> (usize, usize) {
+    let start = tokens.first().map(|x| x.start).unwrap_or(0);
+    let end = tokens
+        .last()
+        .map(|x| x.start + x.to_string().len())
+        .unwrap_or(start);
+
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let content = "// top comment\r\n#ifdef FOO\nconst uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {\n    // ╭───╮ diagram\n    [_BASE] = LAYOUT(  KC_A ,\tKC_B  ),  // trailing\n};\n#endif\nconst char *name = \"Ünïcode\";\r\n\n   // no newline at the end";
+
+        let tree = SyntaxTree::new(content);
+
+        let start = content.find("[_BASE]").unwrap();
+        assert_eq!(content, tree.to_string());
+        assert_eq!("[_BASE] = LAYOUT(", tree.text(start, start + 17));
+    }
+
+    #[test]
+    fn test_trivia() {
+        let tree = SyntaxTree::new("{ // open\n    KC_A,\n}\n");
+
+        let SyntaxNode::Opaque(tokens) = &tree.nodes[0] else {
+            panic!("Expected an opaque node");
+        };
+        assert_eq!(
+            vec![
+                Trivia::Whitespace(" ".to_string()),
+                Trivia::Comment("// open".to_string()),
+                Trivia::Whitespace("\n".to_string()),
+            ],
+            tokens[0].trailing
+        );
+        assert_eq!(
+            vec![Trivia::Whitespace("    ".to_string())],
+            tokens[1].leading
+        );
+        assert_eq!(10, tokens[1].start);
+        assert_eq!("KC_A", tokens[1].text);
+    }
+
+    #[test]
+    fn test_full_span() {
+        let content = "{\n\n    // diagram\n    KC_A,  // note\n    KC_B\n}";
+        let tree = SyntaxTree::new(content);
+
+        let start = content.find("KC_A").unwrap();
+        let (full_start, full_end) = tree.get_full_span(start, start + 5);
+        assert_eq!(
+            "\n    // diagram\n    KC_A,  // note\n",
+            &content[full_start..full_end]
+        );
+        assert_eq!((3, 4), tree.get_full_span(3, 4));
+    }
+
+    #[test]
+    fn test_own() {
+        let content = "before { KC_A ,  KC_B } after\n";
+        let mut tree = SyntaxTree::new(content);
+
+        tree.own(8, 22, "\nKC_A, KC_B\n".to_string()).unwrap();
+
+        assert_eq!("before {\nKC_A, KC_B\n} after\n", tree.to_string());
+        assert_eq!(3, tree.nodes.len());
+        assert_eq!(" KC_A ,  KC_B ", tree.text(8, 22));
+        assert!(tree.own(10, 20, String::new()).is_err());
+        assert!(tree.own(1, 4, String::new()).is_err());
+    }
+}
@@ -1,6 +1,20 @@
-use crate::ast::KeymapStatement;
+use std::{collections::HashSet, ops::Range};
+
+use clap::ValueEnum;
+
+use crate::{
+    ast::{
+        ComboKeysStatement, ComboStatement, EncoderMapStatement, EnumStatement,
+        KeyOverrideStatement, KeymapStatement, LayoutStatement,
+    },
+    keycode::Keycode,
+    lexer::TokenType,
+};
 use Mark::*;
 
+/// What each diagram line starts with unless it's configured otherwise
+pub const DEFAULT_LINE_START: &str = "//    ";
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Element {
     TopLeft,
@@ -35,7 +49,7 @@ impl Element {
             Element::RightT => "┤".to_string(),
             Element::Plus => "┼".to_string(),
             Element::Newline => "\n".to_string(),
-            Element::LineStart => "//    ".to_string(),
+            Element::LineStart => DEFAULT_LINE_START.to_string(),
             Element::Space => " ".to_string(),
             Element::Key(x) => x.to_string(),
         }
@@ -46,13 +60,312 @@ impl Element {
 pub enum Mark {
     K,
     B,
+    /// Where a split keyboard is cut in two, drawn like a blank column
+    S,
 }
 
 pub type Layout = Vec<Vec<Mark>>;
 
-pub fn get_keymap_format(keymap: &KeymapStatement, layout: Layout) -> (Vec<Element>, Vec<Element>) {
-    // Add an extra blank column on the end to make formatting easier
-    let mut layout = layout.clone();
+/// What the diagram shows for keys that use a `#define` alias
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum AliasDisplay {
+    /// The alias name as written in the layer
+    #[default]
+    Name,
+    /// The keycode the alias stands for
+    Expanded,
+    /// The comment after the alias definition, falling back to its name
+    Label,
+}
+
+/// How lines end in a file
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LineEnding {
+    /// `\n`, as on Linux and macOS
+    Lf,
+    /// `\r\n`, as on Windows
+    Crlf,
+}
+
+impl LineEnding {
+    /// The line ending most lines of the file use, LF when there are no line breaks
+    pub fn detect(content: &str) -> Self {
+        let crlf = content.matches("\r\n").count();
+        match crlf > 0 && crlf * 2 >= content.matches('\n').count() {
+            true => LineEnding::Crlf,
+            false => LineEnding::Lf,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+
+    /// Rewrites every line break in `text` to end this way
+    pub fn apply(&self, text: &str) -> String {
+        let text = text.replace("\r\n", "\n");
+        match self {
+            LineEnding::Lf => text,
+            LineEnding::Crlf => text.replace('\n', "\r\n"),
+        }
+    }
+}
+
+/// Where a layer's diagram goes
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum DiagramPosition {
+    /// In `//` comments above the layer
+    #[default]
+    Above,
+    /// In `//` comments after the layer
+    Below,
+    /// Left out, keeping only the encoder and combo notes
+    None,
+    /// In a `/* */` block after the layer
+    BlockComment,
+}
+
+/// How the keys inside `LAYOUT(...)` are laid out
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum CodeAlignment {
+    /// Padded into the columns of the diagram
+    #[default]
+    Grid,
+    /// One line per row of the keyboard, without padding
+    Compact,
+}
+
+/// How keys that fall through to the layer below are written
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum TransparentSpelling {
+    #[value(name = "_______")]
+    Underscores,
+    #[value(name = "KC_TRNS")]
+    KcTrns,
+    #[value(name = "KC_TRANSPARENT")]
+    KcTransparent,
+}
+
+impl TransparentSpelling {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransparentSpelling::Underscores => "_______",
+            TransparentSpelling::KcTrns => "KC_TRNS",
+            TransparentSpelling::KcTransparent => "KC_TRANSPARENT",
+        }
+    }
+}
+
+/// How keys that do nothing are written
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum NoOpSpelling {
+    #[value(name = "XXXXXXX")]
+    Crosses,
+    #[value(name = "KC_NO")]
+    KcNo,
+}
+
+impl NoOpSpelling {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NoOpSpelling::Crosses => "XXXXXXX",
+            NoOpSpelling::KcNo => "KC_NO",
+        }
+    }
+}
+
+/// How the two halves of a split keyboard are drawn
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Split {
+    /// Next to each other, with the closest keys of the halves `gutter` spaces apart
+    SideBySide { gutter: usize },
+    /// The left half's diagram above the right half's
+    Stacked,
+}
+
+/// How the layers inside the keymaps array are indented
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Indent {
+    Spaces(usize),
+    Tab,
+    /// Whatever the first layer in the file is indented with
+    Inherit,
+}
+
+impl Default for Indent {
+    fn default() -> Self {
+        Indent::Spaces(0)
+    }
+}
+
+impl std::str::FromStr for Indent {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "tab" => Ok(Indent::Tab),
+            "inherit" => Ok(Indent::Inherit),
+            _ => match value.parse() {
+                Ok(x) => Ok(Indent::Spaces(x)),
+                Err(_) => Err(format!(
+                    "expected `tab`, `inherit` or a number of spaces, found `{}`",
+                    value
+                )),
+            },
+        }
+    }
+}
+
+impl Indent {
+    /// The text each line of a layer starts with, given the file the layers are in and
+    /// where its first layer starts
+    pub fn get_text(&self, content: &str, first_layer: Option<usize>) -> String {
+        match self {
+            Indent::Spaces(x) => " ".repeat(*x),
+            Indent::Tab => "\t".to_string(),
+            Indent::Inherit => {
+                let Some(start) = first_layer else {
+                    return String::new();
+                };
+                let line_start = content[..start].rfind('\n').map_or(0, |x| x + 1);
+                content[line_start..start]
+                    .chars()
+                    .take_while(|x| *x == ' ' || *x == '\t')
+                    .collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct FormatOptions {
+    /// Symbols to evaluate conditional directives with, or `None` to format every branch
+    pub defines: Option<HashSet<String>>,
+    pub aliases: AliasDisplay,
+    /// Sort layers into the order of their layer numbers
+    pub reorder_layers: bool,
+    /// Draw the encoder bindings of each layer under its diagram
+    pub encoder_glyphs: bool,
+    /// List the keys each combo is made of under the base layer's diagram
+    pub combo_markers: bool,
+    /// Label tap dance keys in the diagrams with their single and double tap actions
+    pub tap_dance_labels: bool,
+    /// Line ending to rewrite the whole file with, or `None` to follow the file
+    pub line_ending: Option<LineEnding>,
+    /// How long the lines of a layer can get before it's drawn more compactly
+    pub max_line_width: Option<usize>,
+    /// Draw the halves of a split keyboard as separate blocks
+    pub split: Option<Split>,
+    pub diagram_position: DiagramPosition,
+    pub code_alignment: CodeAlignment,
+    pub indent: Indent,
+    /// What each diagram line starts with, or `None` for `//` and four spaces
+    pub diagram_prefix: Option<String>,
+    /// Spelling to rewrite transparent keys with, or `None` to keep them as written
+    pub transparent: Option<TransparentSpelling>,
+    /// Spelling to rewrite no-op keys with, or `None` to keep them as written
+    pub no_op: Option<NoOpSpelling>,
+}
+
+impl FormatOptions {
+    pub fn line_start(&self) -> &str {
+        self.diagram_prefix.as_deref().unwrap_or(DEFAULT_LINE_START)
+    }
+
+    /// The configured spelling of a transparent or no-op key, if it's to be rewritten
+    pub fn get_blank_spelling(&self, key: &str) -> Option<&'static str> {
+        let keycode = Keycode::parse(key);
+        match (self.transparent, self.no_op) {
+            (Some(x), _) if keycode.is_transparent() => Some(x.as_str()),
+            (_, Some(x)) if keycode.is_no_op() => Some(x.as_str()),
+            _ => None,
+        }
+    }
+}
+
+/// What the diagram shows for transparent and no-op keys, which are told apart by an empty
+/// cell and a cross
+pub fn get_blank_label(key: &str) -> Option<&'static str> {
+    let keycode = Keycode::parse(key);
+    match (keycode.is_transparent(), keycode.is_no_op()) {
+        (true, _) => Some(""),
+        (_, true) => Some("✕"),
+        _ => None,
+    }
+}
+
+/// How wide each column of the layout is drawn, in the diagram and in the code
+#[derive(Debug, PartialEq, Clone)]
+pub struct ColumnWidths {
+    pub diagram: Vec<usize>,
+    pub code: Vec<usize>,
+}
+
+/// Works out the column widths for a keymap, either giving every column room for the
+/// longest key in the layer, or sizing each column to the keys in it
+pub fn get_column_widths(
+    keys: &[String],
+    labels: &[String],
+    layout: &Layout,
+    per_column: bool,
+) -> ColumnWidths {
+    let columns = layout.iter().map(|x| x.len()).max().unwrap_or(0);
+    let mut diagram = vec![0; columns];
+    let mut code = vec![0; columns];
+
+    let mut count = 0;
+    for row in layout {
+        for (j, col) in row.iter().enumerate() {
+            if let Mark::K = col {
+                let key = keys.get(count).map(|x| x.as_str()).unwrap_or_default();
+                let key = if key.is_empty() { "_______" } else { key };
+                let label = labels.get(count).map(|x| x.as_str()).unwrap_or_default();
+                diagram[j] = diagram[j].max(label.chars().count());
+                code[j] = code[j].max(key.chars().count());
+                count += 1;
+            }
+        }
+    }
+
+    if per_column {
+        return ColumnWidths { diagram, code };
+    }
+
+    let max_width = keys
+        .iter()
+        .map(|x| x.chars().count())
+        .chain(labels.iter().map(|x| x.chars().count()))
+        .max()
+        .unwrap_or(0);
+    ColumnWidths {
+        diagram: vec![max_width; columns],
+        code: vec![max_width; columns],
+    }
+}
+
+/// Formats a keymap with the diagram showing `labels` in place of the keys
+pub fn get_keymap_format(
+    keymap: &KeymapStatement,
+    labels: &[String],
+    layout: Layout,
+    widths: &ColumnWidths,
+) -> (Vec<Element>, Vec<Element>) {
+    // The split is drawn like any other blank column, and an extra blank column on the end
+    // only holds the right edge of the last keys
+    let mut layout: Layout = layout
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|x| match x {
+                    S => B,
+                    x => x.clone(),
+                })
+                .collect()
+        })
+        .collect();
     for row in layout.iter_mut() {
         row.push(Mark::B)
     }
@@ -61,11 +374,8 @@ pub fn get_keymap_format(keymap: &KeymapStatement, layout: Layout) -> (Vec<Eleme
     let mut output2: Vec<Element> = vec![];
 
     let keys = &keymap.layout_statement.keys;
-    let max_width = keys
-        .iter()
-        .map(|x| x.len())
-        .max()
-        .expect("Can't find longest keycode");
+    let width = |i: usize| widths.diagram.get(i).copied().unwrap_or(0);
+    let code_width = |i: usize| widths.code.get(i).copied().unwrap_or(0);
 
     // Create top row
     for (i, col) in layout[0].iter().enumerate() {
@@ -90,16 +400,19 @@ pub fn get_keymap_format(keymap: &KeymapStatement, layout: Layout) -> (Vec<Eleme
             (B, K, K) => output.push(Element::TopLeft),
             (B, K, B) => output.push(Element::TopLeft),
             (B, B, K) => output.push(Element::Space),
-            (B, B, B) => output.push(Element::Space),
+            _ => output.push(Element::Space),
         };
+        if i == layout[0].len() - 1 {
+            break;
+        }
 
         // Get filler character
         let filler = match col {
             Mark::K => Element::Horizontal,
-            Mark::B => Element::Space,
+            _ => Element::Space,
         };
 
-        for _ in 0..max_width + 2 {
+        for _ in 0..width(i) + 2 {
             output.push(filler.clone());
         }
     }
@@ -123,22 +436,25 @@ pub fn get_keymap_format(keymap: &KeymapStatement, layout: Layout) -> (Vec<Eleme
                 (K, K) => output.push(Element::Vertical),
                 (K, B) => output.push(Element::Vertical),
                 (B, K) => output.push(Element::Vertical),
-                (B, B) => output.push(Element::Space),
+                _ => output.push(Element::Space),
             };
+            if j == row.len() - 1 {
+                break;
+            }
 
             output.push(Element::Space);
             output2.push(Element::Space);
 
-            let key = match col {
+            let label = match col {
                 Mark::K => {
-                    let key = &keys[count];
-                    output.push(Element::Key(key.to_string()));
-                    key
+                    let label = &labels[count];
+                    output.push(Element::Key(label.to_string()));
+                    label
                 }
-                Mark::B => "",
+                _ => "",
             };
 
-            for _ in key.len()..max_width {
+            for _ in label.chars().count()..width(j) {
                 output.push(Element::Space);
             }
 
@@ -155,10 +471,10 @@ pub fn get_keymap_format(keymap: &KeymapStatement, layout: Layout) -> (Vec<Eleme
                         "_______"
                     }
                 }
-                Mark::B => "",
+                _ => "",
             };
 
-            for _ in key.len()..max_width {
+            for _ in key.chars().count()..code_width(j) {
                 output2.push(Element::Space);
             }
 
@@ -271,16 +587,19 @@ pub fn get_keymap_format(keymap: &KeymapStatement, layout: Layout) -> (Vec<Eleme
                     (_, _, _, _, K, _, _, _, _) => output.push(Element::Plus),
                     _ => output.push(Element::Space),
                 };
+                if j == row.len() - 1 {
+                    break;
+                }
 
                 // Get filler character
                 let filler = match (col, eighth) {
                     (K, K) => Element::Horizontal,
                     (K, B) => Element::Horizontal,
                     (B, K) => Element::Horizontal,
-                    (B, B) => Element::Space,
+                    _ => Element::Space,
                 };
 
-                for _ in 0..max_width + 2 {
+                for _ in 0..width(j) + 2 {
                     output.push(filler.clone());
                 }
             }
@@ -312,16 +631,19 @@ pub fn get_keymap_format(keymap: &KeymapStatement, layout: Layout) -> (Vec<Eleme
             (B, K, K) => output.push(Element::BottomLeft),
             (B, K, B) => output.push(Element::BottomLeft),
             (B, B, K) => output.push(Element::Space),
-            (B, B, B) => output.push(Element::Space),
+            _ => output.push(Element::Space),
         };
+        if i == layout[layout.len() - 1].len() - 1 {
+            break;
+        }
 
         // Get filler character
         let filler = match col {
             Mark::K => Element::Horizontal,
-            Mark::B => Element::Space,
+            _ => Element::Space,
         };
 
-        for _ in 0..max_width + 2 {
+        for _ in 0..width(i) + 2 {
             output.push(filler.clone());
         }
     }
@@ -329,8 +651,610 @@ pub fn get_keymap_format(keymap: &KeymapStatement, layout: Layout) -> (Vec<Eleme
     (output, output2)
 }
 
-pub fn get_keymap_string(keymap_format: Vec<Element>) -> String {
-    keymap_format.iter().map(|x| x.to_string()).collect()
+/// Renders the elements, leaving off the padding of blank cells at the end of each line
+pub fn get_keymap_string(keymap_format: Vec<Element>, line_start: &str) -> String {
+    let text: String = keymap_format
+        .iter()
+        .map(|x| match x {
+            Element::LineStart => line_start.to_string(),
+            _ => x.to_string(),
+        })
+        .collect();
+    let lines: Vec<&str> = text.split('\n').map(|x| x.trim_end()).collect();
+
+    lines.join("\n")
+}
+
+/// The ways a layer can be drawn more compactly, tried in turn until it fits within the
+/// maximum line width
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Compaction {
+    /// Size each column to the keys in it instead of to the longest key in the layer
+    pub column_widths: bool,
+    /// Swap diagram labels longer than this for short tags, explained under the diagram
+    pub legend_width: Option<usize>,
+    /// Write each row of the `LAYOUT(...)` grid as one line per half of the keyboard
+    pub split_rows: bool,
+}
+
+/// A layer's diagram and the keys that go inside its `LAYOUT(...)`
+#[derive(Debug, PartialEq)]
+pub struct FormattedKeymap {
+    pub display: String,
+    pub code: String,
+}
+
+impl FormattedKeymap {
+    /// The length of the longest line
+    pub fn width(&self) -> usize {
+        self.display
+            .lines()
+            .chain(self.code.lines())
+            .map(|x| x.chars().count())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+pub fn get_formatted_keymap(
+    keymap: &KeymapStatement,
+    labels: &[String],
+    layout: &Layout,
+    options: &FormatOptions,
+    compaction: Compaction,
+) -> FormattedKeymap {
+    let (labels, legend) = match compaction.legend_width {
+        Some(width) => get_legend(labels, width, options.line_start()),
+        None => (labels.to_vec(), vec![]),
+    };
+    let keys = &keymap.layout_statement.keys;
+    let (left_end, right_start) = get_halves(layout);
+
+    let mut widths = get_column_widths(keys, &labels, layout, compaction.column_widths);
+    if let Some(Split::SideBySide { gutter }) = options.split {
+        let diagram = get_gutter_width(layout, &widths.diagram, gutter);
+        let code = get_gutter_width(layout, &widths.code, gutter);
+        for j in left_end..right_start {
+            widths.diagram[j] = diagram;
+            widths.code[j] = code;
+        }
+    }
+    let (display, code) = get_keymap_format(keymap, &labels, layout.clone(), &widths);
+
+    let mut display = get_keymap_string(display, options.line_start());
+    if let Some(Split::Stacked) = options.split {
+        let halves = [0..left_end, right_start..usize::MAX].map(|columns| {
+            let half = get_half_layout(layout, &columns);
+            let keymap = KeymapStatement::new(
+                keymap.token.clone(),
+                LayoutStatement::new(
+                    keymap.layout_statement.token.clone(),
+                    get_half_keys(layout, keys, &columns),
+                ),
+            );
+            let labels = get_half_keys(layout, &labels, &columns);
+            let widths = get_column_widths(
+                &keymap.layout_statement.keys,
+                &labels,
+                &half,
+                compaction.column_widths,
+            );
+
+            let display = get_keymap_format(&keymap, &labels, half, &widths).0;
+            get_keymap_string(display, options.line_start())
+        });
+        display = halves.join("\n");
+    }
+    for line in legend {
+        display += "\n";
+        display += &line;
+    }
+
+    // Stacked halves keep the keys in the order `LAYOUT(...)` takes them, a row of the left
+    // half followed by the same row of the right half
+    let mut code = get_keymap_string(code, "");
+    if options.code_alignment == CodeAlignment::Compact {
+        code = get_compact_rows(layout, keys);
+    } else if compaction.split_rows || options.split == Some(Split::Stacked) {
+        code = get_split_rows(&code, layout, &widths.code);
+    }
+
+    FormattedKeymap { display, code }
+}
+
+/// The columns of each row that fall within `columns`
+fn get_half_layout(layout: &Layout, columns: &Range<usize>) -> Layout {
+    layout
+        .iter()
+        .map(|row| {
+            row.iter()
+                .enumerate()
+                .filter(|(j, _)| columns.contains(j))
+                .map(|(_, x)| x.clone())
+                .collect()
+        })
+        .collect()
+}
+
+/// The entries of `keys`, given in layout order, for the keys that fall within `columns`
+fn get_half_keys(layout: &Layout, keys: &[String], columns: &Range<usize>) -> Vec<String> {
+    let positions = layout
+        .iter()
+        .flat_map(|row| row.iter().enumerate())
+        .filter(|(_, x)| matches!(x, K))
+        .map(|(j, _)| j);
+
+    positions
+        .zip(keys)
+        .filter(|(j, _)| columns.contains(j))
+        .map(|(_, x)| x.to_string())
+        .collect()
+}
+
+/// Formats a layer within `max_width` characters, falling back to per-column widths, then
+/// legends, then splitting the rows in half. When nothing fits, the narrowest attempt is
+/// returned as the error.
+pub fn get_fitted_keymap(
+    keymap: &KeymapStatement,
+    labels: &[String],
+    layout: &Layout,
+    options: &FormatOptions,
+) -> Result<FormattedKeymap, FormattedKeymap> {
+    let mut best = get_formatted_keymap(keymap, labels, layout, options, Compaction::default());
+    let Some(max_width) = options.max_line_width else {
+        return Ok(best);
+    };
+    if best.width() <= max_width {
+        return Ok(best);
+    }
+
+    let longest = labels.iter().map(|x| x.chars().count()).max().unwrap_or(0);
+    let legend_widths = std::iter::once(None).chain((3..longest).rev().map(Some));
+    let mut attempts: Vec<Compaction> = legend_widths
+        .clone()
+        .map(|legend_width| Compaction {
+            column_widths: true,
+            legend_width,
+            split_rows: false,
+        })
+        .collect();
+    attempts.extend(legend_widths.map(|legend_width| Compaction {
+        column_widths: true,
+        legend_width,
+        split_rows: true,
+    }));
+
+    for compaction in attempts {
+        let formatted = get_formatted_keymap(keymap, labels, layout, options, compaction);
+        if formatted.width() <= max_width {
+            return Ok(formatted);
+        }
+        if formatted.width() < best.width() {
+            best = formatted;
+        }
+    }
+
+    Err(best)
+}
+
+/// Swaps labels longer than `width` for numbered tags, returning the new labels and the
+/// legend lines that list what each tag stands for
+fn get_legend(labels: &[String], width: usize, line_start: &str) -> (Vec<String>, Vec<String>) {
+    let mut tagged: Vec<&String> = vec![];
+    let labels = labels
+        .iter()
+        .map(|label| {
+            if label.chars().count() <= width {
+                return label.to_string();
+            }
+
+            let index = match tagged.iter().position(|x| *x == label) {
+                Some(x) => x,
+                None => {
+                    tagged.push(label);
+                    tagged.len() - 1
+                }
+            };
+            format!("*{}", index + 1)
+        })
+        .collect();
+
+    let legend = tagged
+        .iter()
+        .enumerate()
+        .map(|(i, label)| format!("{}*{} {}", line_start, i + 1, label))
+        .collect();
+
+    (labels, legend)
+}
+
+/// Where the left half of the keyboard ends and the right half starts, on either side of the
+/// column marked as the split. A layout without one is cut down the middle.
+fn get_halves(layout: &Layout) -> (usize, usize) {
+    let split = layout
+        .iter()
+        .find_map(|row| row.iter().position(|x| matches!(x, S)));
+    match split {
+        Some(j) => (j, j + 1),
+        None => {
+            let middle = layout.iter().map(|x| x.len()).max().unwrap_or(0) / 2;
+            (middle, middle)
+        }
+    }
+}
+
+/// The width to give the split column so that in the row where the halves come closest,
+/// their keys are `gutter` characters apart. Every column takes up its width plus the border
+/// and the spaces around the key.
+fn get_gutter_width(layout: &Layout, widths: &[usize], gutter: usize) -> usize {
+    let (left_end, right_start) = get_halves(layout);
+    let closest = layout
+        .iter()
+        .filter_map(|row| {
+            let is_key = |j: &usize| matches!(row.get(*j), Some(K));
+            let left = (0..left_end).rev().find(is_key)?;
+            let right = (right_start..row.len()).find(is_key)?;
+
+            let between = (left + 1..right).filter(|j| !(left_end..right_start).contains(j));
+            Some(between.map(|j| widths[j] + 3).sum::<usize>())
+        })
+        .min()
+        .unwrap_or(0);
+
+    gutter.saturating_sub(closest + 2)
+}
+
+/// Breaks each line of the grid in two at the split between the halves of the keyboard
+fn get_split_rows(code: &str, layout: &Layout, widths: &[usize]) -> String {
+    let (left_end, right_start) = get_halves(layout);
+    // Every column takes up its width plus the spaces and comma around the key
+    let offset = |column: usize| -> usize { widths[..column].iter().map(|x| x + 3).sum() };
+
+    let mut lines: Vec<String> = vec![];
+    for line in code.lines() {
+        let chars: Vec<char> = line.chars().collect();
+        let left: String = chars.iter().take(offset(left_end)).collect();
+        let right: String = chars.iter().skip(offset(right_start)).collect();
+
+        lines.push(left.trim_end().to_string());
+        if !right.trim().is_empty() {
+            lines.push(right.trim_end().to_string());
+        }
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Writes the keys of each row of the layout on one line, without lining them up
+fn get_compact_rows(layout: &Layout, keys: &[String]) -> String {
+    let mut keys = keys.iter().map(|x| match x.is_empty() {
+        true => "_______",
+        false => x,
+    });
+    let rows: Vec<Vec<&str>> = layout
+        .iter()
+        .map(|row| {
+            let count = row.iter().filter(|x| matches!(x, K)).count();
+            keys.by_ref().take(count).collect()
+        })
+        .filter(|x: &Vec<&str>| !x.is_empty())
+        .collect();
+
+    rows.iter()
+        .map(|x| x.join(", "))
+        .collect::<Vec<_>>()
+        .join(",\n")
+        + "\n"
+}
+
+/// Turns `//` comment lines into a `/* */` block, keeping the diagram lined up
+pub fn get_block_comment(lines: &str) -> String {
+    let mut output = String::from("/*\n");
+    for line in lines.lines() {
+        let line = line.strip_prefix("//").unwrap_or(line);
+        output += format!(" *{}", line).trim_end();
+        output += "\n";
+    }
+
+    output + " */"
+}
+
+fn get_encoder_key(key: &str) -> &str {
+    match key.is_empty() {
+        true => "_______",
+        false => key,
+    }
+}
+
+/// Lines up the encoder bindings of every layer into columns
+pub fn get_encoder_map_string(entries: &[EncoderMapStatement]) -> String {
+    let index_width = entries
+        .iter()
+        .map(|x| x.token.to_string().chars().count())
+        .max()
+        .unwrap_or(0);
+    let count = entries.iter().map(|x| x.encoders.len()).max().unwrap_or(0);
+
+    let mut ccw_widths = vec![0; count];
+    let mut cw_widths = vec![0; count];
+    for entry in entries {
+        for (i, encoder) in entry.encoders.iter().enumerate() {
+            ccw_widths[i] = ccw_widths[i].max(get_encoder_key(&encoder.ccw).chars().count());
+            cw_widths[i] = cw_widths[i].max(get_encoder_key(&encoder.cw).chars().count());
+        }
+    }
+
+    let mut lines: Vec<String> = vec![];
+    for entry in entries {
+        let index = entry.token.to_string();
+        let mut line = format!(
+            "[{}]{} = {{",
+            index,
+            " ".repeat(index_width - index.chars().count())
+        );
+
+        for (i, encoder) in entry.encoders.iter().enumerate() {
+            let separator = match i + 1 < count {
+                true => ",",
+                false => "",
+            };
+            let ccw = format!("{},", get_encoder_key(&encoder.ccw));
+            let cw = format!("{}){}", get_encoder_key(&encoder.cw), separator);
+
+            line += &format!(
+                " ENCODER_CCW_CW({:<ccw_width$} {:<cw_width$}",
+                ccw,
+                cw,
+                ccw_width = ccw_widths[i] + 1,
+                cw_width = cw_widths[i] + 1 + separator.len()
+            );
+        }
+
+        line = line.trim_end_matches(' ').trim_end_matches(',').to_string();
+        line += " },";
+        lines.push(line);
+    }
+
+    lines.join("\n")
+}
+
+/// Pads every cell but the last in each row to the width of its column
+fn get_aligned_columns(rows: &[Vec<String>]) -> Vec<String> {
+    let count = rows.iter().map(|x| x.len()).max().unwrap_or(0);
+    let mut widths = vec![0; count];
+    for row in rows {
+        for (i, cell) in row.iter().enumerate().take(row.len().saturating_sub(1)) {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+
+    rows.iter()
+        .map(|row| {
+            let cells: Vec<String> = row
+                .iter()
+                .enumerate()
+                .map(|(i, cell)| match i + 1 < row.len() {
+                    true => format!("{:<width$}", cell, width = widths[i]),
+                    false => cell.to_string(),
+                })
+                .collect();
+            cells.join(" ")
+        })
+        .collect()
+}
+
+/// Lines up every combo key list, returning the text from each list's name to its closing brace
+pub fn get_combo_keys_strings(statements: &[&ComboKeysStatement]) -> Vec<String> {
+    let names: Vec<String> = statements
+        .iter()
+        .map(|x| x.declarator.to_string())
+        .collect();
+    let name_width = names.iter().map(|x| x.chars().count()).max().unwrap_or(0);
+
+    let rows: Vec<Vec<String>> = statements
+        .iter()
+        .map(|statement| {
+            let mut cells: Vec<String> = statement.keys.iter().map(|x| format!("{},", x)).collect();
+            if let Some(last) = cells.last_mut() {
+                last.pop();
+            }
+            cells
+        })
+        .collect();
+
+    names
+        .iter()
+        .zip(get_aligned_columns(&rows))
+        .map(|(name, keys)| format!("{:<width$} = {{{}}}", name, keys, width = name_width))
+        .collect()
+}
+
+/// An entry of a designated initializer table, such as the
+/// `[TD_ESC] = ACTION_TAP_DANCE_DOUBLE(KC_ESC, KC_CAPS)` of `tap_dance_actions` or the
+/// `FOO = SAFE_RANGE` of an enum
+pub struct TableRow {
+    /// What's in front of the `=`, e.g. `[TD_ESC]` or `FOO`
+    pub designator: Option<String>,
+    /// The value after the `=`, split into the parts that are lined up in columns
+    pub cells: Vec<String>,
+    /// The macro the value calls, only rows calling the same one are lined up with each other
+    pub call: Option<String>,
+}
+
+impl TableRow {
+    /// A row whose value is a macro call, with each argument in its own column
+    pub fn call(designator: Option<String>, call: &str, args: &[String]) -> Self {
+        let mut cells: Vec<String> = args.iter().map(|x| format!("{},", x)).collect();
+        match cells.last_mut() {
+            Some(last) => {
+                last.pop();
+                last.push(')');
+            }
+            None => cells.push(")".to_string()),
+        }
+        cells[0] = format!("{}({}", call, cells[0]);
+
+        Self {
+            designator,
+            cells,
+            call: Some(call.to_string()),
+        }
+    }
+
+    /// A row with a single value, or none at all like a plain enum member
+    pub fn value(designator: Option<String>, value: Option<String>) -> Self {
+        Self {
+            designator,
+            cells: value.into_iter().collect(),
+            call: None,
+        }
+    }
+}
+
+/// Lines up the rows of a designated initializer table, leaving off any separator
+pub fn get_table_lines(rows: &[TableRow]) -> Vec<String> {
+    let designator_width = rows
+        .iter()
+        .filter(|x| !x.cells.is_empty())
+        .filter_map(|x| x.designator.as_ref())
+        .map(|x| x.chars().count())
+        .max();
+    let mut values: Vec<String> = vec![String::new(); rows.len()];
+    let mut calls: Vec<&Option<String>> = rows.iter().map(|x| &x.call).collect();
+    calls.sort();
+    calls.dedup();
+    for call in calls {
+        let group: Vec<usize> = (0..rows.len()).filter(|x| rows[*x].call == *call).collect();
+        let cells: Vec<Vec<String>> = group.iter().map(|x| rows[*x].cells.clone()).collect();
+        for (i, value) in group.into_iter().zip(get_aligned_columns(&cells)) {
+            values[i] = value;
+        }
+    }
+
+    rows.iter()
+        .zip(values)
+        .map(|(row, value)| match (&row.designator, designator_width) {
+            (Some(designator), _) if row.cells.is_empty() => designator.to_string(),
+            (Some(designator), Some(width)) => {
+                format!("{:<width$} = {}", designator, value, width = width)
+            }
+            (None, Some(width)) => format!("{}{}", " ".repeat(width + 3), value),
+            (_, None) => value,
+        })
+        .collect()
+}
+
+/// Joins the lines of a table into the text between its braces, each line indented the same
+/// way and a comma after the last one only when asked for
+pub fn get_table_body(lines: &[String], indent: &str, trailing_comma: bool) -> String {
+    let mut body = lines
+        .iter()
+        .map(|x| format!("{}{}", indent, x))
+        .collect::<Vec<_>>()
+        .join(",\n");
+    if trailing_comma {
+        body += ",";
+    }
+
+    body
+}
+
+fn get_index_designator(index: &Option<TokenType>) -> Option<String> {
+    index.as_ref().map(|x| format!("[{}]", x.to_string()))
+}
+
+/// Lines up a table of macro calls, such as the entries of `key_combos` or
+/// `tap_dance_actions`, from the designator, call name and arguments of each entry
+pub fn get_call_table_lines(
+    entries: &[(&Option<TokenType>, &TokenType, &[String])],
+) -> Vec<String> {
+    let rows: Vec<TableRow> = entries
+        .iter()
+        .map(|(index, call, args)| {
+            TableRow::call(get_index_designator(index), &call.to_string(), args)
+        })
+        .collect();
+
+    get_table_lines(&rows)
+}
+
+/// Lines up the members of an enum, with the values they're assigned taken from the source
+pub fn get_enum_lines(content: &str, statement: &EnumStatement) -> Vec<String> {
+    let rows: Vec<TableRow> = statement
+        .members
+        .iter()
+        .map(|x| {
+            let value = x
+                .expression
+                .map(|(start, end)| content[start..end].trim().to_string());
+            TableRow::value(Some(x.token.to_string()), value)
+        })
+        .collect();
+
+    get_table_lines(&rows)
+}
+
+/// Lines up every key override definition, returning the text from each one's name to the
+/// closing parenthesis of its `ko_make_*` call
+pub fn get_key_override_strings(statements: &[&KeyOverrideStatement]) -> Vec<String> {
+    let rows: Vec<TableRow> = statements
+        .iter()
+        .map(|x| TableRow::call(Some(x.token.to_string()), &x.call.to_string(), &x.args))
+        .collect();
+
+    get_table_lines(&rows)
+}
+
+/// Diagram lines naming the position of each key in the combos made from a layer's keys
+pub fn get_combo_markers(
+    combos: &[(&ComboStatement, &ComboKeysStatement)],
+    keymap: &KeymapStatement,
+    position_names: &[String],
+    line_start: &str,
+) -> Vec<String> {
+    let keys = &keymap.layout_statement.keys;
+
+    combos
+        .iter()
+        .map(|(combo, combo_keys)| {
+            let positions: Vec<String> = combo_keys
+                .keys
+                .iter()
+                .filter(|x| *x != "COMBO_END")
+                .map(|key| match keys.iter().position(|x| x == key) {
+                    Some(i) => match position_names.get(i) {
+                        Some(name) => format!("{} ({})", key, name),
+                        None => key.to_string(),
+                    },
+                    None => format!("{} (missing)", key),
+                })
+                .collect();
+
+            let name = match combo.result() {
+                Some(x) => format!("{} → {}", combo.keys_name(), x),
+                None => combo.keys_name().to_string(),
+            };
+
+            format!("{}{}: {}", line_start, name, positions.join(" + "))
+        })
+        .collect()
+}
+
+/// A diagram line drawing each encoder as a knob between its two bindings
+pub fn get_encoder_glyphs(entry: &EncoderMapStatement, line_start: &str) -> String {
+    let knobs: Vec<String> = entry
+        .encoders
+        .iter()
+        .map(|x| {
+            format!(
+                "↺ {} ◉ {} ↻",
+                get_encoder_key(&x.ccw),
+                get_encoder_key(&x.cw)
+            )
+        })
+        .collect();
+
+    format!("{}{}", line_start, knobs.join("    "))
 }
 
 #[cfg(test)]
@@ -343,38 +1267,47 @@ mod tests {
     fn test_key_display() {
         let keymap = KeymapStatement {
             token: TokenType::Ident(4932, "_QWERTY".to_string()),
-            layout_statement: LayoutStatement {
-                token: TokenType::Layout(4943),
-                keys: vec!["KC_ESC".to_string()],
-            },
+            layout_statement: LayoutStatement::new(
+                TokenType::Layout(4943),
+                vec!["KC_ESC".to_string()],
+            ),
+            index: None,
+            span: (0, 0),
         };
 
         let layout: Vec<Vec<Mark>> = vec![vec![Mark::K]];
 
-        let (display, keymap) = get_keymap_format(&keymap, layout);
+        let keys = &keymap.layout_statement.keys;
+        let widths = get_column_widths(keys, keys, &layout, false);
+        let (display, keymap) = get_keymap_format(&keymap, keys, layout, &widths);
 
-        let display = get_keymap_string(display);
-        let expected_display =
-            "//    ╭────────╮        \n//    │ KC_ESC │        \n//    ╰────────╯";
+        let display = get_keymap_string(display, DEFAULT_LINE_START);
+        let expected_display = "//    ╭────────╮\n//    │ KC_ESC │\n//    ╰────────╯";
 
-        for (i, (ex, res)) in expected_display.chars().zip(display.chars()).enumerate() {
-            assert_eq!(ex, res, "Char index: {}", i);
-        }
+        assert_eq!(expected_display, display);
 
-        let keymap = get_keymap_string(keymap);
-        let expected_keymap = " KC_ESC";
-        for (i, (ex, res)) in keymap.chars().zip(expected_keymap.chars()).enumerate() {
-            assert_eq!(ex, res, "Char index: {}", i);
-        }
+        let keymap = get_keymap_string(keymap, DEFAULT_LINE_START);
+        let expected_keymap = " KC_ESC\n";
+        assert_eq!(expected_keymap, keymap);
+    }
+
+    #[test]
+    fn test_column_widths_count_characters() {
+        let keys = vec!["«".to_string(), "»".to_string()];
+        let layout = vec![vec![Mark::K, Mark::K]];
+
+        let widths = get_column_widths(&keys, &keys, &layout, false);
+        assert_eq!(vec![1, 1], widths.diagram);
+        assert_eq!(vec![1, 1], widths.code);
     }
 
     #[test]
     fn test_format() {
         let keymap = KeymapStatement {
             token: TokenType::Ident(4932, "_QWERTY".to_string()),
-            layout_statement: LayoutStatement {
-                token: TokenType::Layout(4943),
-                keys: vec![
+            layout_statement: LayoutStatement::new(
+                TokenType::Layout(4943),
+                vec![
                     "KC_ESC".to_string(),
                     "KC_Q".to_string(),
                     "KC_W".to_string(),
@@ -426,7 +1359,9 @@ mod tests {
                     "".to_string(),
                     "".to_string(),
                 ],
-            },
+            ),
+            index: None,
+            span: (0, 0),
         };
 
         let layout: Vec<Vec<Mark>> = vec![
@@ -436,19 +1371,187 @@ mod tests {
             vec![B, B, B, K, K, K, K, K, B, K, K, K, K, K, B, B, B],
         ];
 
-        let (display, keymap) = get_keymap_format(&keymap, layout);
+        let keys = &keymap.layout_statement.keys;
+        let widths = get_column_widths(keys, keys, &layout, false);
+        let (display, keymap) = get_keymap_format(&keymap, keys, layout, &widths);
+
+        let display = get_keymap_string(display, DEFAULT_LINE_START);
+        let expected = "//    ╭──────────┬──────────┬──────────┬──────────┬──────────┬──────────╮                                                      ╭──────────┬──────────┬──────────┬──────────┬──────────┬──────────╮\n//    │ KC_ESC   │ KC_Q     │ KC_W     │ KC_E     │ KC_R     │ KC_T     │                                                      │ KC_Y     │ KC_U     │ KC_I     │ KC_O     │ KC_P     │ KC_BSPC  │\n//    ├──────────┼──────────┼──────────┼──────────┼──────────┼──────────┤                                                      ├──────────┼──────────┼──────────┼──────────┼──────────┼──────────┤\n//    │ SFT_TAB  │ KC_A     │ KC_S     │ KC_D     │ KC_F     │ KC_G     │                                                      │ KC_H     │ KC_J     │ KC_K     │ KC_L     │ KC_SCLN  │ KC_QUOTE │\n//    ├──────────┼──────────┼──────────┼──────────┼──────────┼──────────┼──────────┬──────────╮          ╭──────────┬──────────┼──────────┼──────────┼──────────┼──────────┼──────────┼──────────┤\n//    │ KC_LCTL  │ KC_Z     │ KC_X     │ KC_C     │ KC_V     │ KC_B     │ KC_CPYP  │ ADJUST   │          │ FKEYS    │          │ KC_N     │ KC_M     │ KC_COMM  │ KC_DOT   │ KC_SLSH  │ KC_RSFT  │\n//    ╰──────────┴──────────┴──────────┼──────────┼──────────┼──────────┼──────────┼──────────┤          ├──────────┼──────────┼──────────┼──────────┼──────────┼──────────┴──────────┴──────────╯\n//                                     │ KC_LALT  │ NAV      │ SYM      │ KC_ENT   │ KC_LGUI  │          │ KC_RGUI  │ KC_SPC   │ NAV      │          │          │\n//                                     ╰──────────┴──────────┴──────────┴──────────┴──────────╯          ╰──────────┴──────────┴──────────┴──────────┴──────────╯";
+        assert_eq!(expected, display);
+
+        let keymap = get_keymap_string(keymap, DEFAULT_LINE_START);
+        let expected = " KC_ESC   , KC_Q     , KC_W     , KC_E     , KC_R     , KC_T     ,                                                        KC_Y     , KC_U     , KC_I     , KC_O     , KC_P     , KC_BSPC  ,\n SFT_TAB  , KC_A     , KC_S     , KC_D     , KC_F     , KC_G     ,                                                        KC_H     , KC_J     , KC_K     , KC_L     , KC_SCLN  , KC_QUOTE ,\n KC_LCTL  , KC_Z     , KC_X     , KC_C     , KC_V     , KC_B     , KC_CPYP  , ADJUST   ,            FKEYS    , _______  , KC_N     , KC_M     , KC_COMM  , KC_DOT   , KC_SLSH  , KC_RSFT  ,\n                                  KC_LALT  , NAV      , SYM      , KC_ENT   , KC_LGUI  ,            KC_RGUI  , KC_SPC   , NAV      , _______  , _______\n";
+
+        assert_eq!(expected, keymap);
+    }
 
-        let display = get_keymap_string(display);
-        let expected = "//    ╭──────────┬──────────┬──────────┬──────────┬──────────┬──────────╮                                                      ╭──────────┬──────────┬──────────┬──────────┬──────────┬──────────╮          \n//    │ KC_ESC   │ KC_Q     │ KC_W     │ KC_E     │ KC_R     │ KC_T     │                                                      │ KC_Y     │ KC_U     │ KC_I     │ KC_O     │ KC_P     │ KC_BSPC  │          \n//    ├──────────┼──────────┼──────────┼──────────┼──────────┼──────────┤                                                      ├──────────┼──────────┼──────────┼──────────┼──────────┼──────────┤          \n//    │ SFT_TAB  │ KC_A     │ KC_S     │ KC_D     │ KC_F     │ KC_G     │                                                      │ KC_H     │ KC_J     │ KC_K     │ KC_L     │ KC_SCLN  │ KC_QUOTE │          \n//    ├──────────┼──────────┼──────────┼──────────┼──────────┼──────────┼──────────┬──────────╮          ╭──────────┬──────────┼──────────┼──────────┼──────────┼──────────┼──────────┼──────────┤          \n//    │ KC_LCTL  │ KC_Z     │ KC_X     │ KC_C     │ KC_V     │ KC_B     │ KC_CPYP  │ ADJUST   │          │ FKEYS    │          │ KC_N     │ KC_M     │ KC_COMM  │ KC_DOT   │ KC_SLSH  │ KC_RSFT  │          \n//    ╰──────────┴──────────┴──────────┼──────────┼──────────┼──────────┼──────────┼──────────┤          ├──────────┼──────────┼──────────┼──────────┼──────────┼──────────┴──────────┴──────────╯          \n//                                     │ KC_LALT  │ NAV      │ SYM      │ KC_ENT   │ KC_LGUI  │          │ KC_RGUI  │ KC_SPC   │ NAV      │          │          │                                           \n//                                     ╰──────────┴──────────┴──────────┴──────────┴──────────╯          ╰──────────┴──────────┴──────────┴──────────┴──────────╯";
-        for (ex, res) in expected.chars().zip(display.chars()) {
-            assert_eq!(ex, res);
+    #[test]
+    fn test_no_trailing_whitespace() {
+        let layouts: Vec<Layout> = vec![
+            vec![vec![K]],
+            vec![vec![K, K, K]],
+            vec![vec![K], vec![K], vec![K]],
+            vec![vec![K, K, B, B, K, K], vec![K, K, B, B, K, K]],
+            vec![vec![B, K, K, B], vec![K, K, K, K], vec![B, B, K, B]],
+            vec![vec![K, K, K, B, B], vec![K, K], vec![K, K, K, K, K]],
+            vec![vec![K, K, K, K], vec![B, B, B, K]],
+        ];
+
+        let compactions = [
+            Compaction::default(),
+            Compaction {
+                column_widths: true,
+                legend_width: Some(3),
+                split_rows: true,
+            },
+        ];
+        for (layout, compaction) in layouts
+            .into_iter()
+            .flat_map(|x| compactions.map(|y| (x.clone(), y)))
+        {
+            let count = layout.iter().flatten().filter(|x| matches!(x, K)).count();
+            let keys: Vec<String> = (0..count).map(|x| format!("KC_{}", x)).collect();
+            let keymap = KeymapStatement {
+                token: TokenType::Ident(0, "_BASE".to_string()),
+                layout_statement: LayoutStatement::new(TokenType::Layout(0), keys.clone()),
+                index: None,
+                span: (0, 0),
+            };
+
+            let options = FormatOptions::default();
+            let formatted = get_formatted_keymap(&keymap, &keys, &layout, &options, compaction);
+            let display = formatted.display;
+            let code = formatted.code;
+
+            for line in display.lines().chain(code.lines()) {
+                assert_eq!(line.trim_end(), line, "{:?}", display);
+            }
+            for (line, row) in display.lines().skip(1).step_by(2).zip(&layout) {
+                let last = row.iter().rposition(|x| matches!(x, K));
+                assert_eq!(last.is_some(), line.ends_with('│'), "{}", display);
+            }
+            assert_eq!(count, code.matches("KC_").count());
         }
+    }
+
+    #[test]
+    fn test_fitted_keymap() {
+        let keys: Vec<String> = ["KC_A", "LT(_NAV,KC_SPC)", "KC_B", "KC_C", "KC_D", "KC_E"]
+            .map(|x| x.to_string())
+            .to_vec();
+        let mut labels = keys.clone();
+        labels[0] = "HOME_ROW_GUI_A".to_string();
+        let keymap = KeymapStatement {
+            token: TokenType::Ident(0, "_BASE".to_string()),
+            layout_statement: LayoutStatement::new(TokenType::Layout(0), keys),
+            index: None,
+            span: (0, 0),
+        };
+        let layout: Layout = vec![vec![K, K, K, S, K, K, K]];
+        let fit = |max_width| {
+            let options = FormatOptions {
+                max_line_width: Some(max_width),
+                ..FormatOptions::default()
+            };
+            get_fitted_keymap(&keymap, &labels, &layout, &options)
+        };
+
+        let formatted = fit(200).unwrap();
+        assert!(formatted
+            .display
+            .contains("│ HOME_ROW_GUI_A  │ LT(_NAV,KC_SPC) │ KC_B            │"));
+
+        let formatted = fit(80).unwrap();
+        assert_eq!(73, formatted.width());
+        assert!(formatted
+            .display
+            .contains("│ HOME_ROW_GUI_A │ LT(_NAV,KC_SPC) │ KC_B │  │ KC_C │"));
+        assert_eq!(
+            " KC_A , LT(_NAV,KC_SPC) , KC_B ,    KC_C , KC_D , KC_E\n",
+            formatted.code
+        );
 
-        let keymap = get_keymap_string(keymap);
-        let expected = " KC_ESC   , KC_Q     , KC_W     , KC_E     , KC_R     , KC_T     ,                                                        KC_Y     , KC_U     , KC_I     , KC_O     , KC_P     , KC_BSPC  ,           \n SFT_TAB  , KC_A     , KC_S     , KC_D     , KC_F     , KC_G     ,                                                        KC_H     , KC_J     , KC_K     , KC_L     , KC_SCLN  , KC_QUOTE ,           \n KC_LCTL  , KC_Z     , KC_X     , KC_C     , KC_V     , KC_B     , KC_CPYP  , ADJUST   ,            FKEYS    , _______  , KC_N     , KC_M     , KC_COMM  , KC_DOT   , KC_SLSH  , KC_RSFT  ,           \n                                  KC_LALT  , NAV      , SYM      , KC_ENT   , KC_LGUI  ,            KC_RGUI  , KC_SPC   , NAV      , _______  , _______";
+        let formatted = fit(60).unwrap();
+        assert!(formatted.display.contains("│ HOME_ROW_GUI_A │ *1 │ KC_B │"));
+        assert!(formatted.display.ends_with("\n//    *1 LT(_NAV,KC_SPC)"));
+        assert_eq!(1, formatted.code.lines().count());
+
+        let formatted = fit(50).unwrap();
+        assert!(formatted.display.contains("│ *1 │ *2 │ KC_B │  │ KC_C │"));
+        assert_eq!(
+            " KC_A , LT(_NAV,KC_SPC) , KC_B ,\n KC_C , KC_D , KC_E\n",
+            formatted.code
+        );
+
+        let formatted = fit(30).unwrap_err();
+        assert_eq!(40, formatted.width());
+    }
+
+    #[test]
+    fn test_split_halves() {
+        let keys: Vec<String> = (1..=8).map(|x| format!("KC_{}", x)).collect();
+        let keymap = KeymapStatement {
+            token: TokenType::Ident(0, "_BASE".to_string()),
+            layout_statement: LayoutStatement::new(TokenType::Layout(0), keys.clone()),
+            index: None,
+            span: (0, 0),
+        };
+        let layout: Layout = vec![vec![K, K, B, S, B, K, K], vec![B, K, K, S, K, K, B]];
+        let format = |split| {
+            let options = FormatOptions {
+                split: Some(split),
+                ..FormatOptions::default()
+            };
+            get_formatted_keymap(&keymap, &keys, &layout, &options, Compaction::default())
+        };
+
+        let formatted = format(Split::SideBySide { gutter: 2 });
+        assert_eq!(
+            "//    ╭──────┬──────╮                ╭──────┬──────╮\n//    │ KC_1 │ KC_2 │                │ KC_3 │ KC_4 │\n//    ╰──────┼──────┼──────╮  ╭──────┼──────┼──────╯\n//           │ KC_5 │ KC_6 │  │ KC_7 │ KC_8 │\n//           ╰──────┴──────╯  ╰──────┴──────╯",
+            formatted.display
+        );
+        assert_eq!(
+            " KC_1 , KC_2 ,                  KC_3 , KC_4 ,\n        KC_5 , KC_6 ,    KC_7 , KC_8\n",
+            formatted.code
+        );
+
+        let formatted = format(Split::Stacked);
+        assert_eq!(
+            "//    ╭──────┬──────╮\n//    │ KC_1 │ KC_2 │\n//    ╰──────┼──────┼──────╮\n//           │ KC_5 │ KC_6 │\n//           ╰──────┴──────╯\n//           ╭──────┬──────╮\n//           │ KC_3 │ KC_4 │\n//    ╭──────┼──────┼──────╯\n//    │ KC_7 │ KC_8 │\n//    ╰──────┴──────╯",
+            formatted.display
+        );
+        assert_eq!(
+            " KC_1 , KC_2 ,\n        KC_3 , KC_4 ,\n        KC_5 , KC_6 ,\n KC_7 , KC_8\n",
+            formatted.code
+        );
+    }
+
+    #[test]
+    fn test_split_gutter() {
+        let keys: Vec<String> = (1..=8).map(|x| format!("KC_{}", x)).collect();
+        let keymap = KeymapStatement {
+            token: TokenType::Ident(0, "_BASE".to_string()),
+            layout_statement: LayoutStatement::new(TokenType::Layout(0), keys.clone()),
+            index: None,
+            span: (0, 0),
+        };
+        let layout: Layout = vec![vec![K, K, B, S, B, K, K], vec![B, K, K, S, B, K, K]];
+
+        for gutter in [9, 12] {
+            let options = FormatOptions {
+                split: Some(Split::SideBySide { gutter }),
+                ..FormatOptions::default()
+            };
+            let formatted =
+                get_formatted_keymap(&keymap, &keys, &layout, &options, Compaction::default());
 
-        for (ex, res) in expected.chars().zip(keymap.chars()) {
-            assert_eq!(ex, res);
+            let gap = " ".repeat(gutter);
+            assert!(formatted
+                .display
+                .contains(&format!("│ KC_6 │{}│ KC_7 │", gap)));
+            assert!(formatted
+                .display
+                .contains(&format!("╰──────┴──────╯{}╰──────┴──────╯", gap)));
         }
     }
 }
@@ -0,0 +1,201 @@
+use crate::{
+    ast::KeymapStatement,
+    formatter::{Layout, Mark},
+    layers::{get_layer_switches, LayerSwitch},
+};
+
+/// Names each key in the layout by its half, row and position in that row, e.g. "L thumb 2"
+pub fn get_position_names(layout: &Layout) -> Vec<String> {
+    let width = layout.iter().map(|x| x.len()).max().unwrap_or(0);
+
+    let mut names: Vec<String> = vec![];
+    for (i, row) in layout.iter().enumerate() {
+        let row_name = match i == layout.len() - 1 && layout.len() > 1 {
+            true => "thumb".to_string(),
+            false => format!("row {}", i + 1),
+        };
+
+        let mut left = 0;
+        let mut right = 0;
+        for (j, col) in row.iter().enumerate() {
+            if !matches!(col, Mark::K) {
+                continue;
+            }
+
+            if j * 2 < width {
+                left += 1;
+                names.push(format!("L {} {}", row_name, left));
+            } else {
+                right += 1;
+                names.push(format!("R {} {}", row_name, right));
+            }
+        }
+    }
+
+    names
+}
+
+fn get_edge_label(
+    switch: &LayerSwitch,
+    keymaps: &[&KeymapStatement],
+    position_names: &[String],
+) -> String {
+    // Positions only mean something when the layer matches the physical layout
+    let key_count = keymaps[switch.from].layout_statement.keys.len();
+    let position = match position_names.get(switch.key) {
+        Some(x) if key_count == position_names.len() => x.to_string(),
+        _ => format!("key {}", switch.key + 1),
+    };
+
+    format!("{} on {}", switch.keycode, position)
+}
+
+pub fn to_dot(keymaps: &[&KeymapStatement], layout: &Layout) -> String {
+    let position_names = get_position_names(layout);
+
+    let mut output = String::new();
+    output += "digraph layers {\n";
+    for keymap in keymaps {
+        output += &format!("    \"{}\";\n", keymap.token.to_string());
+    }
+
+    for switch in get_layer_switches(keymaps) {
+        let style = match switch.kind.is_persistent() {
+            true => ", style=dashed",
+            false => "",
+        };
+
+        output += &format!(
+            "    \"{}\" -> \"{}\" [label=\"{}\"{}];\n",
+            keymaps[switch.from].token.to_string(),
+            keymaps[switch.to].token.to_string(),
+            get_edge_label(&switch, keymaps, &position_names).replace('"', "\\\""),
+            style
+        );
+    }
+    output += "}\n";
+
+    output
+}
+
+pub fn to_mermaid(keymaps: &[&KeymapStatement], layout: &Layout) -> String {
+    let position_names = get_position_names(layout);
+
+    let mut output = String::new();
+    output += "flowchart LR\n";
+    for (i, keymap) in keymaps.iter().enumerate() {
+        output += &format!("    layer{}[\"{}\"]\n", i, keymap.token.to_string());
+    }
+
+    for switch in get_layer_switches(keymaps) {
+        let arrow = match switch.kind.is_persistent() {
+            true => "-.->",
+            false => "-->",
+        };
+
+        output += &format!(
+            "    layer{} {}|\"{}\"| layer{}\n",
+            switch.from,
+            arrow,
+            get_edge_label(&switch, keymaps, &position_names).replace('"', "#quot;"),
+            switch.to
+        );
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{formatter::Mark::*, lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    const CONTENT: &str = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(
+  KC_A, KC_B,      KC_C, KC_D,
+     MO(_NAV),     TG(_NUM)
+),
+[_NAV] = LAYOUT(
+  KC_1, KC_2,      KC_3, KC_4,
+     _______,      KC_E
+),
+[_NUM] = LAYOUT(
+  KC_5, KC_6,      KC_7, KC_8,
+     KC_F,         _______
+),
+}"##;
+
+    fn get_layout() -> Layout {
+        vec![vec![K, K, B, K, K], vec![B, K, B, K, B]]
+    }
+
+    #[test]
+    fn test_position_names() {
+        assert_eq!(
+            vec![
+                "L row 1 1",
+                "L row 1 2",
+                "R row 1 1",
+                "R row 1 2",
+                "L thumb 1",
+                "R thumb 1"
+            ],
+            get_position_names(&get_layout())
+        );
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let ast = Parser::new(Lexer::new(CONTENT)).parse();
+
+        let expected = r##"digraph layers {
+    "_BASE";
+    "_NAV";
+    "_NUM";
+    "_BASE" -> "_NAV" [label="MO(_NAV) on L thumb 1"];
+    "_BASE" -> "_NUM" [label="TG(_NUM) on R thumb 1", style=dashed];
+}
+"##;
+        assert_eq!(expected, to_dot(&ast.keymaps(), &get_layout()));
+    }
+
+    #[test]
+    fn test_to_mermaid() {
+        let ast = Parser::new(Lexer::new(CONTENT)).parse();
+
+        let expected = r##"flowchart LR
+    layer0["_BASE"]
+    layer1["_NAV"]
+    layer2["_NUM"]
+    layer0 -->|"MO(_NAV) on L thumb 1"| layer1
+    layer0 -.->|"TG(_NUM) on R thumb 1"| layer2
+"##;
+        assert_eq!(expected, to_mermaid(&ast.keymaps(), &get_layout()));
+    }
+
+    #[test]
+    fn test_quotes_in_labels() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(LT(_NAV, '"')),
+[_NAV] = LAYOUT(_______),
+}"##;
+        let ast = Parser::new(Lexer::new(content)).parse();
+        let layout = vec![vec![K]];
+
+        let expected = r##"digraph layers {
+    "_BASE";
+    "_NAV";
+    "_BASE" -> "_NAV" [label="LT(_NAV,'\"') on L row 1 1"];
+}
+"##;
+        assert_eq!(expected, to_dot(&ast.keymaps(), &layout));
+
+        let expected = r##"flowchart LR
+    layer0["_BASE"]
+    layer1["_NAV"]
+    layer0 -->|"LT(_NAV,'#quot;') on L row 1 1"| layer1
+"##;
+        assert_eq!(expected, to_mermaid(&ast.keymaps(), &layout));
+    }
+}
@@ -0,0 +1,177 @@
+/// How many aliases deep to expand before giving up on a self-referencing `#define`
+const MAX_ALIAS_DEPTH: usize = 16;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Keycode {
+    Basic(String),
+    Function(String, Vec<Keycode>),
+}
+
+impl Keycode {
+    /// Parses a key string as stored in `LayoutStatement.keys`, e.g. `KC_A` or `LT(1,KC_SPC)`
+    pub fn parse(key: &str) -> Keycode {
+        let chars: Vec<char> = key.chars().filter(|x| !x.is_whitespace()).collect();
+        let mut position = 0;
+
+        Keycode::parse_expression(&chars, &mut position)
+    }
+
+    fn parse_expression(chars: &[char], position: &mut usize) -> Keycode {
+        let mut name = String::new();
+        while let Some(ch) = chars.get(*position) {
+            if *ch == '(' || *ch == ')' || *ch == ',' {
+                break;
+            }
+            name.push(*ch);
+            *position += 1;
+        }
+
+        if chars.get(*position) != Some(&'(') {
+            return Keycode::Basic(name);
+        }
+
+        *position += 1;
+        let mut args: Vec<Keycode> = vec![];
+        while *position < chars.len() {
+            args.push(Keycode::parse_expression(chars, position));
+
+            match chars.get(*position) {
+                Some(',') => *position += 1,
+                Some(')') => {
+                    *position += 1;
+                    break;
+                }
+                _ => break,
+            }
+        }
+
+        Keycode::Function(name, args)
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Keycode::Basic(x) => x,
+            Keycode::Function(x, _) => x,
+        }
+    }
+
+    /// Replaces names that are aliases for other keycodes with what they stand for,
+    /// including inside function arguments
+    pub fn expand<'a>(&self, resolve: &impl Fn(&str) -> Option<&'a str>) -> Keycode {
+        self.expand_to_depth(resolve, 0)
+    }
+
+    fn expand_to_depth<'a>(
+        &self,
+        resolve: &impl Fn(&str) -> Option<&'a str>,
+        depth: usize,
+    ) -> Keycode {
+        if depth > MAX_ALIAS_DEPTH {
+            return self.clone();
+        }
+
+        match self {
+            Keycode::Basic(name) => match resolve(name) {
+                Some(x) => Keycode::parse(x).expand_to_depth(resolve, depth + 1),
+                None => self.clone(),
+            },
+            Keycode::Function(name, args) => Keycode::Function(
+                name.to_string(),
+                args.iter()
+                    .map(|x| x.expand_to_depth(resolve, depth + 1))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        matches!(self, Keycode::Basic(x) if x.replace('_', "").is_empty() || x == "KC_TRNS" || x == "KC_TRANSPARENT")
+    }
+
+    /// Whether the key does nothing at all, instead of falling through to the layer below
+    pub fn is_no_op(&self) -> bool {
+        matches!(self, Keycode::Basic(x) if x == "XXXXXXX" || x == "KC_NO")
+    }
+}
+
+impl std::fmt::Display for Keycode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Keycode::Basic(x) => write!(f, "{}", x),
+            Keycode::Function(name, args) => {
+                let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+                write!(f, "{}({})", name, args.join(","))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_basic() {
+        assert_eq!(Keycode::Basic("KC_A".to_string()), Keycode::parse("KC_A"));
+    }
+
+    #[test]
+    fn test_parse_function() {
+        let expected = Keycode::Function(
+            "LT".to_string(),
+            vec![
+                Keycode::Basic("1".to_string()),
+                Keycode::Basic("KC_SPC".to_string()),
+            ],
+        );
+
+        assert_eq!(expected, Keycode::parse("LT(1, KC_SPC)"));
+        assert_eq!("LT(1,KC_SPC)", expected.to_string());
+    }
+
+    #[test]
+    fn test_parse_nested_function() {
+        let expected = Keycode::Function(
+            "LCTL".to_string(),
+            vec![Keycode::Function(
+                "LSFT".to_string(),
+                vec![Keycode::Basic("KC_T".to_string())],
+            )],
+        );
+
+        assert_eq!(expected, Keycode::parse("LCTL(LSFT(KC_T))"));
+    }
+
+    #[test]
+    fn test_transparent_and_no_op() {
+        assert!(Keycode::parse("").is_transparent());
+        assert!(Keycode::parse("_______").is_transparent());
+        assert!(Keycode::parse("KC_TRNS").is_transparent());
+        assert!(!Keycode::parse("KC_NO").is_transparent());
+        assert!(Keycode::parse("KC_NO").is_no_op());
+        assert!(Keycode::parse("XXXXXXX").is_no_op());
+        assert!(!Keycode::parse("_______").is_no_op());
+    }
+
+    #[test]
+    fn test_expand() {
+        let resolve = |name: &str| match name {
+            "HOME_A" => Some("LGUI_T(KC_A)"),
+            "NAV" => Some("1"),
+            "LOOP" => Some("LOOP"),
+            _ => None,
+        };
+
+        assert_eq!(
+            "LGUI_T(KC_A)",
+            Keycode::parse("HOME_A").expand(&resolve).to_string()
+        );
+        assert_eq!(
+            "LT(1,LGUI_T(KC_A))",
+            Keycode::parse("LT(NAV, HOME_A)")
+                .expand(&resolve)
+                .to_string()
+        );
+        assert_eq!("LOOP", Keycode::parse("LOOP").expand(&resolve).to_string());
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::{ast::KeymapStatement, keycode::Keycode, lexer::TokenType, lint::Diagnostic};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SwitchKind {
+    Momentary,
+    LayerTap,
+    LayerMod,
+    OneShot,
+    TapToggle,
+    Toggle,
+    To,
+    Default,
+}
+
+impl SwitchKind {
+    fn from_function(name: &str) -> Option<Self> {
+        match name {
+            "MO" => Some(SwitchKind::Momentary),
+            "LT" => Some(SwitchKind::LayerTap),
+            "LM" => Some(SwitchKind::LayerMod),
+            "OSL" => Some(SwitchKind::OneShot),
+            "TT" => Some(SwitchKind::TapToggle),
+            "TG" => Some(SwitchKind::Toggle),
+            "TO" => Some(SwitchKind::To),
+            "DF" => Some(SwitchKind::Default),
+            _ => None,
+        }
+    }
+
+    /// Whether the layer stays active while the key is held down
+    pub fn is_held(&self) -> bool {
+        matches!(
+            self,
+            SwitchKind::Momentary
+                | SwitchKind::LayerTap
+                | SwitchKind::LayerMod
+                | SwitchKind::OneShot
+                | SwitchKind::TapToggle
+        )
+    }
+
+    /// Whether the layer stays active after the key is released
+    pub fn is_persistent(&self) -> bool {
+        matches!(
+            self,
+            SwitchKind::TapToggle | SwitchKind::Toggle | SwitchKind::To | SwitchKind::Default
+        )
+    }
+
+    /// Whether pressing the key again on the target layer switches it back off
+    fn is_toggle(&self) -> bool {
+        matches!(self, SwitchKind::TapToggle | SwitchKind::Toggle)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LayerSwitch {
+    pub from: usize,
+    pub to: usize,
+    pub key: usize,
+    pub kind: SwitchKind,
+    pub keycode: String,
+}
+
+/// The layer number of a keymap entry, or `None` when its index can't be worked out. When no
+/// index can be worked out, e.g. the layer enum is in another file, the entries are taken to be
+/// in layer order
+pub fn get_layer_number(keymaps: &[&KeymapStatement], layer: usize) -> Option<usize> {
+    if keymaps.iter().all(|x| x.index.is_none()) {
+        return Some(layer);
+    }
+
+    keymaps[layer].index
+}
+
+/// Finds the keymap entry a keycode argument refers to, by name or by layer number
+pub fn get_layer_index(layer: &str, keymaps: &[&KeymapStatement]) -> Option<usize> {
+    match layer.parse::<usize>() {
+        Ok(x) => (0..keymaps.len()).find(|i| get_layer_number(keymaps, *i) == Some(x)),
+        Err(_) => keymaps.iter().position(|x| x.token.to_string() == layer),
+    }
+}
+
+/// The keymap entry for layer 0, which is active when no other layer is
+pub fn get_base_layer(keymaps: &[&KeymapStatement]) -> usize {
+    get_layer_index("0", keymaps).unwrap_or(0)
+}
+
+pub fn get_layer_switches(keymaps: &[&KeymapStatement]) -> Vec<LayerSwitch> {
+    let mut switches: Vec<LayerSwitch> = vec![];
+    for (from, keymap) in keymaps.iter().enumerate() {
+        for (key, keycode) in keymap.layout_statement.keys.iter().enumerate() {
+            let Keycode::Function(name, args) = Keycode::parse(keycode) else {
+                continue;
+            };
+
+            let kind = SwitchKind::from_function(&name);
+            let to = args
+                .first()
+                .and_then(|x| get_layer_index(x.name(), keymaps));
+            if let (Some(kind), Some(to)) = (kind, to) {
+                switches.push(LayerSwitch {
+                    from,
+                    to,
+                    key,
+                    kind,
+                    keycode: keycode.to_string(),
+                });
+            }
+        }
+    }
+
+    switches
+}
+
+pub fn analyse_layers(keymaps: &[&KeymapStatement]) -> Vec<Diagnostic> {
+    if keymaps.is_empty() {
+        return vec![];
+    }
+
+    let switches = get_layer_switches(keymaps);
+    let base = get_base_layer(keymaps);
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+
+    for (i, keymap) in keymaps.iter().enumerate() {
+        let Some(number) = get_layer_number(keymaps, i) else {
+            continue;
+        };
+        if let Some(first) = (0..i).find(|x| get_layer_number(keymaps, *x) == Some(number)) {
+            diagnostics.push(Diagnostic::new(
+                get_position(&keymap.token),
+                format!(
+                    "Layer {} is number {}, the same as layer {}",
+                    keymap.token.to_string(),
+                    number,
+                    keymaps[first].token.to_string()
+                ),
+            ));
+        }
+    }
+
+    // A layer without a known number could be switched to by a number that wasn't resolved,
+    // so it isn't reported
+    let reachable = get_reachable(base, &switches, |_| true);
+    for (i, keymap) in keymaps.iter().enumerate() {
+        if !reachable.contains(&i) && get_layer_number(keymaps, i).is_some() {
+            diagnostics.push(Diagnostic::new(
+                get_position(&keymap.token),
+                format!(
+                    "Layer {} can't be reached from the base layer {}",
+                    keymap.token.to_string(),
+                    keymaps[base].token.to_string()
+                ),
+            ));
+        }
+    }
+
+    for switch in switches
+        .iter()
+        .filter(|x| x.kind.is_held() && x.from != x.to)
+    {
+        let target = &keymaps[switch.to].layout_statement;
+        let Some(key) = target.keys.get(switch.key) else {
+            continue;
+        };
+
+        if !Keycode::parse(key).is_transparent() && *key != switch.keycode {
+            let position = target.positions.get(switch.key).copied().unwrap_or(0);
+            diagnostics.push(Diagnostic::new(
+                position,
+                format!(
+                    "`{}` on layer {} holds layer {}, but that key is `{}` on {} instead of KC_TRNS",
+                    switch.keycode,
+                    keymaps[switch.from].token.to_string(),
+                    keymaps[switch.to].token.to_string(),
+                    key,
+                    keymaps[switch.to].token.to_string()
+                ),
+            ));
+        }
+    }
+
+    let mut persistent: Vec<usize> = switches
+        .iter()
+        .filter(|x| x.kind.is_persistent() && x.to != base)
+        .map(|x| x.to)
+        .collect();
+    persistent.sort();
+    persistent.dedup();
+
+    for layer in persistent {
+        if !has_route_back(layer, base, keymaps, &switches) {
+            let keymap = keymaps[layer];
+            diagnostics.push(Diagnostic::new(
+                get_position(&keymap.token),
+                format!(
+                    "Layer {} has no route back to the base layer {}",
+                    keymap.token.to_string(),
+                    keymaps[base].token.to_string()
+                ),
+            ));
+        }
+    }
+
+    diagnostics
+}
+
+pub fn get_position(token: &TokenType) -> usize {
+    match token {
+        TokenType::Ident(x, _) => *x,
+        _ => 0,
+    }
+}
+
+fn get_reachable(
+    start: usize,
+    switches: &[LayerSwitch],
+    include: impl Fn(&LayerSwitch) -> bool,
+) -> HashSet<usize> {
+    let mut reachable: HashSet<usize> = HashSet::from([start]);
+    let mut queue: VecDeque<usize> = VecDeque::from([start]);
+
+    while let Some(layer) = queue.pop_front() {
+        for switch in switches.iter().filter(|x| x.from == layer && include(x)) {
+            if reachable.insert(switch.to) {
+                queue.push_back(switch.to);
+            }
+        }
+    }
+
+    reachable
+}
+
+/// Whether a layer that stays on after its key is released can be left again
+fn has_route_back(
+    layer: usize,
+    base: usize,
+    keymaps: &[&KeymapStatement],
+    switches: &[LayerSwitch],
+) -> bool {
+    let reachable = get_reachable(layer, switches, |x| x.kind.is_persistent());
+    if reachable.contains(&base) {
+        return true;
+    }
+
+    // A toggle can be switched off again from the layer it enabled, either by a key on
+    // that layer or by the original key falling through a transparent position
+    switches.iter().any(|x| {
+        if !x.kind.is_toggle() || !reachable.contains(&x.to) {
+            return false;
+        }
+
+        let toggles_itself = x.from == x.to;
+        let falls_through = keymaps[x.to]
+            .layout_statement
+            .keys
+            .get(x.key)
+            .is_some_and(|key| Keycode::parse(key).is_transparent());
+
+        toggles_itself || falls_through
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lexer::Lexer, parser::Parser};
+
+    use super::*;
+
+    fn analyse(content: &str) -> Vec<String> {
+        let ast = Parser::new(Lexer::new(content)).parse();
+
+        analyse_layers(&ast.keymaps())
+            .into_iter()
+            .map(|x| x.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_layer_switches() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, MO(_NAV), LT(2, KC_SPC)),
+[_NAV] = LAYOUT(KC_B, _______, KC_C),
+[_SYM] = LAYOUT(KC_D, KC_E, _______),
+}"##;
+        let ast = Parser::new(Lexer::new(content)).parse();
+
+        let switches = get_layer_switches(&ast.keymaps());
+
+        assert_eq!(
+            vec![
+                LayerSwitch {
+                    from: 0,
+                    to: 1,
+                    key: 1,
+                    kind: SwitchKind::Momentary,
+                    keycode: "MO(_NAV)".to_string(),
+                },
+                LayerSwitch {
+                    from: 0,
+                    to: 2,
+                    key: 2,
+                    kind: SwitchKind::LayerTap,
+                    keycode: "LT(2,KC_SPC)".to_string(),
+                },
+            ],
+            switches
+        );
+        assert_eq!(Vec::<String>::new(), analyse(content));
+    }
+
+    #[test]
+    fn test_unreachable_layer() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, MO(_NAV)),
+[_NAV] = LAYOUT(KC_B, _______),
+[_GAME] = LAYOUT(KC_C, KC_D),
+}"##;
+
+        assert_eq!(
+            vec!["Layer _GAME can't be reached from the base layer _BASE".to_string()],
+            analyse(content)
+        );
+    }
+
+    #[test]
+    fn test_unresolved_layer_number() {
+        let content = r##"enum layers { _BASE, _NAV };
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, MO(_NAV)),
+[_UNKNOWN] = LAYOUT(KC_B, KC_C),
+[_NAV] = LAYOUT(KC_D, _______),
+}"##;
+
+        assert_eq!(Vec::<String>::new(), analyse(content));
+    }
+
+    #[test]
+    fn test_momentary_key_not_transparent() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, MO(_NAV)),
+[_NAV] = LAYOUT(KC_B, KC_C),
+}"##;
+
+        assert_eq!(
+            vec!["`MO(_NAV)` on layer _BASE holds layer _NAV, but that key is `KC_C` on _NAV instead of KC_TRNS".to_string()],
+            analyse(content)
+        );
+    }
+
+    #[test]
+    fn test_no_route_back() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, TO(_GAME), TG(_NUM)),
+[_GAME] = LAYOUT(KC_B, KC_C, KC_D),
+[_NUM] = LAYOUT(KC_1, KC_2, _______),
+}"##;
+
+        assert_eq!(
+            vec!["Layer _GAME has no route back to the base layer _BASE".to_string()],
+            analyse(content)
+        );
+    }
+
+    #[test]
+    fn test_enum_layer_numbers() {
+        let content = r##"enum layers { _NAV = 1, _SYM, _BASE = 0 };
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_NAV] = LAYOUT(KC_B, _______),
+[_BASE] = LAYOUT(KC_A, MO(1)),
+[_SYM] = LAYOUT(KC_C, KC_D),
+[1] = LAYOUT(KC_E, _______),
+}"##;
+
+        assert_eq!(
+            vec![
+                "Layer 1 is number 1, the same as layer _NAV".to_string(),
+                "Layer _SYM can't be reached from the base layer _BASE".to_string(),
+                "Layer 1 can't be reached from the base layer _BASE".to_string(),
+            ],
+            analyse(content)
+        );
+    }
+
+    #[test]
+    fn test_route_back_through_to() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, TO(_GAME)),
+[_GAME] = LAYOUT(TO(_BASE), KC_C),
+}"##;
+
+        assert_eq!(Vec::<String>::new(), analyse(content));
+    }
+}
@@ -1,5 +1,8 @@
+/// The UTF-8 byte order mark, which is kept in the output but isn't part of any token
+const BOM: &str = "\u{feff}";
+
 pub struct Lexer {
-    content: Vec<u8>,
+    content: String,
     ch: u8,
     position: usize,
     read_position: usize,
@@ -8,9 +11,9 @@ pub struct Lexer {
 impl Lexer {
     pub fn new(content: &str) -> Self {
         let mut lexer = Self {
-            content: content.to_string().into_bytes(),
+            content: content.to_string(),
             position: 0,
-            read_position: 0,
+            read_position: content.starts_with(BOM) as usize * BOM.len(),
             ch: 0,
         };
 
@@ -30,26 +33,54 @@ impl Lexer {
             b'[' => TokenType::LSqBrace(self.position),
             b']' => TokenType::RSqBrace(self.position),
             b',' => TokenType::Comma(self.position),
-            b'=' => TokenType::Equals(self.position),
-            b'/' => {
+            b'=' if self.peek_char() != b'=' => TokenType::Equals(self.position),
+            b'#' => {
                 let position = self.position;
-                if self.peek_char() == b'/' {
-                    let line = self.read_to_end_of_line();
-                    return TokenType::Comment(position, self.position, line);
-                }
-
-                return TokenType::Ident(self.position, self.ch.to_string());
+                let directive = self.read_directive();
+                return TokenType::Directive(position, self.position, directive);
+            }
+            b'/' if self.peek_char() == b'/' => {
+                let position = self.position;
+                let line = self.read_to_end_of_line();
+                return TokenType::Comment(position, self.position, line);
+            }
+            b'/' if self.peek_char() == b'*' => {
+                let position = self.position;
+                let comment = self.read_block_comment();
+                return TokenType::Comment(position, self.position, comment);
+            }
+            b'"' | b'\'' => return self.read_quoted(self.position),
+            b'0'..=b'9' => {
+                let position = self.position;
+                return TokenType::Number(position, self.read_number());
+            }
+            b'.' if self.peek_char().is_ascii_digit() => {
+                let position = self.position;
+                return TokenType::Number(position, self.read_number());
+            }
+            x if PUNCTUATORS.iter().any(|p| p.as_bytes()[0] == x) => {
+                let position = self.position;
+                return TokenType::Punctuator(position, self.read_punctuator());
             }
             0 => TokenType::EOF,
             _ => {
                 let position = self.position;
                 let identifier = self.read_identifier();
 
+                // Prefixed literals such as `L"text"` or `u8'a'`
+                if matches!(identifier.as_str(), "L" | "u" | "U" | "u8")
+                    && matches!(self.ch, b'"' | b'\'')
+                {
+                    return self.read_quoted(position);
+                }
+
                 return match identifier {
                     _ if identifier == "LAYOUT".to_string() => TokenType::Layout(position),
                     _ if identifier == "const".to_string() => TokenType::Const(position),
                     _ if identifier == "PROGMEM".to_string() => TokenType::Progmem(position),
-                    _ if identifier.replace("_", "").is_empty() => TokenType::Blank(position),
+                    _ if identifier.replace("_", "").is_empty() => {
+                        TokenType::Blank(position, identifier)
+                    }
                     _ => TokenType::Ident(position, identifier),
                 };
             }
@@ -62,35 +93,158 @@ impl Lexer {
 
     fn read_to_end_of_line(&mut self) -> String {
         let pos = self.position;
-        while pos == self.position || self.ch != b'\n' {
+        while pos == self.position
+            || (self.ch != 0
+                && self.ch != b'\n'
+                && !(self.ch == b'\r' && self.peek_char() == b'\n'))
+        {
+            self.read_char();
+        }
+
+        self.read_from(pos)
+    }
+
+    fn read_block_comment(&mut self) -> String {
+        let pos = self.position;
+        self.read_char();
+        self.read_char();
+        while self.ch != 0 && !(self.ch == b'*' && self.peek_char() == b'/') {
+            self.read_char();
+        }
+        if self.ch != 0 {
+            self.read_char();
+            self.read_char();
+        }
+
+        self.read_from(pos)
+    }
+
+    /// Reads the longest punctuator starting at the current character
+    fn read_punctuator(&mut self) -> String {
+        let rest = &self.content.as_bytes()[self.position..];
+        let punctuator = PUNCTUATORS
+            .iter()
+            .find(|x| rest.starts_with(x.as_bytes()))
+            .copied()
+            .unwrap_or_default();
+
+        for _ in 0..punctuator.len() {
+            self.read_char();
+        }
+
+        punctuator.to_string()
+    }
+
+    /// Reads a preprocessor line, following any backslash line continuations
+    fn read_directive(&mut self) -> String {
+        let pos = self.position;
+        while self.ch != 0 && self.ch != b'\n' {
+            let next = self.content.as_bytes().get(self.read_position).copied();
+            if self.ch == b'\\' && matches!(next, Some(b'\n' | b'\r')) {
+                self.read_char();
+                if self.ch == b'\r' {
+                    self.read_char();
+                }
+            }
+            self.read_char();
+        }
+
+        self.read_from(pos).trim_end().to_string()
+    }
+
+    /// Reads a string or character literal including its quotes, stopping at the end of the
+    /// line when it isn't closed
+    fn read_quoted(&mut self, start: usize) -> TokenType {
+        let quote = self.ch;
+        self.read_char();
+        while self.ch != quote && self.ch != 0 && self.ch != b'\n' {
+            if self.ch == b'\\' && self.peek_char() != b'\n' {
+                self.read_char();
+            }
+            self.read_char();
+        }
+        if self.ch == quote {
+            self.read_char();
+        }
+
+        let literal = self.read_from(start);
+        match quote {
+            b'"' => TokenType::String(start, literal),
+            _ => TokenType::Char(start, literal),
+        }
+    }
+
+    /// Reads an integer or floating point literal with any prefix and suffix, e.g. `0x7E`,
+    /// `0b1010`, `100UL` or `1.5e-3f`
+    fn read_number(&mut self) -> String {
+        let pos = self.position;
+        let is_hex = self.ch == b'0' && matches!(self.peek_char(), b'x' | b'X');
+        loop {
+            let previous = self.ch;
             self.read_char();
+
+            let is_exponent = match is_hex {
+                true => matches!(previous, b'p' | b'P'),
+                false => matches!(previous, b'e' | b'E'),
+            };
+            let continues = self.ch.is_ascii_alphanumeric()
+                || self.ch == b'_'
+                || self.ch == b'.'
+                || self.ch == b'\''
+                || (is_exponent && matches!(self.ch, b'+' | b'-'));
+            if !continues {
+                break;
+            }
         }
 
-        String::from_utf8_lossy(&self.content[pos..self.position]).to_string()
+        self.read_from(pos)
     }
 
     fn read_identifier(&mut self) -> String {
         let pos = self.position;
-        while pos == self.position || self.ch.is_ascii_alphanumeric() || self.ch == b'_' {
+        // Bytes outside ASCII are part of the identifier, so a multi-byte character is never split
+        while pos == self.position
+            || self.ch.is_ascii_alphanumeric()
+            || self.ch == b'_'
+            || !self.ch.is_ascii()
+        {
             self.read_char();
         }
 
-        String::from_utf8_lossy(&self.content[pos..self.position]).to_string()
+        self.read_from(pos)
+    }
+
+    /// The source from `start` up to the current character. Tokens only ever end on an ASCII
+    /// character, so this never splits a multi-byte character.
+    fn read_from(&self, start: usize) -> String {
+        self.content
+            .get(start..self.offset())
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    /// Byte offset just past the last token that was read
+    pub fn offset(&self) -> usize {
+        self.position.min(self.content.len())
     }
 
     fn read_char(&mut self) {
-        self.ch = *self.content.get(self.read_position).unwrap_or(&0);
+        self.ch = *self
+            .content
+            .as_bytes()
+            .get(self.read_position)
+            .unwrap_or(&0);
 
         self.position = self.read_position;
         self.read_position += 1;
     }
 
     fn peek_char(&self) -> u8 {
-        if self.position >= self.content.len() {
-            return 0;
-        }
-
-        self.content[self.position]
+        *self
+            .content
+            .as_bytes()
+            .get(self.read_position)
+            .unwrap_or(&0)
     }
 
     fn skip_whitespace(&mut self) {
@@ -100,6 +254,14 @@ impl Lexer {
     }
 }
 
+/// The C punctuators that don't have a token of their own, longest first so the longest
+/// match wins
+const PUNCTUATORS: [&str; 38] = [
+    "<<=", ">>=", "...", "->", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=",
+    "/=", "%=", "+=", "-=", "&=", "^=", "|=", ";", ":", "?", ".", "*", "/", "%", "+", "-", "&",
+    "|", "^", "~", "!", "<", ">",
+];
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
     LParen(usize),
@@ -109,14 +271,59 @@ pub enum TokenType {
     Equals(usize),
     Comma(usize),
     Layout(usize),
-    Blank(usize),
+    /// An identifier made only of underscores, such as `_______`
+    Blank(usize, String),
     Ident(usize, String),
     Const(usize),
     Comment(usize, usize, String),
+    Directive(usize, usize, String),
     Progmem(usize),
     EOF,
     LBrace(usize),
     RBrace(usize),
+    Number(usize, String),
+    /// An operator or punctuation mark such as `;`, `|` or `->`
+    Punctuator(usize, String),
+    /// A string literal, including its quotes
+    String(usize, String),
+    /// A character literal, including its quotes
+    Char(usize, String),
+}
+
+impl TokenType {
+    /// Byte offset in the source where the token starts
+    pub fn start(&self) -> Option<usize> {
+        match self {
+            TokenType::LParen(x)
+            | TokenType::RParen(x)
+            | TokenType::LSqBrace(x)
+            | TokenType::RSqBrace(x)
+            | TokenType::Equals(x)
+            | TokenType::Comma(x)
+            | TokenType::Layout(x)
+            | TokenType::Blank(x, _)
+            | TokenType::Ident(x, _)
+            | TokenType::Const(x)
+            | TokenType::Comment(x, _, _)
+            | TokenType::Directive(x, _, _)
+            | TokenType::Progmem(x)
+            | TokenType::LBrace(x)
+            | TokenType::RBrace(x)
+            | TokenType::Number(x, _)
+            | TokenType::Punctuator(x, _)
+            | TokenType::String(x, _)
+            | TokenType::Char(x, _) => Some(*x),
+            TokenType::EOF => None,
+        }
+    }
+
+    /// Byte offset in the source just past the end of the token
+    pub fn end(&self) -> Option<usize> {
+        match self {
+            TokenType::Comment(_, x, _) | TokenType::Directive(_, x, _) => Some(*x),
+            x => Some(x.start()? + x.to_string().len()),
+        }
+    }
 }
 
 impl TokenType {
@@ -129,14 +336,19 @@ impl TokenType {
             TokenType::Equals(_) => "=",
             TokenType::Comma(_) => ",",
             TokenType::Layout(_) => "LAYOUT",
-            TokenType::Blank(_) => "",
+            TokenType::Blank(_, x) => x,
             TokenType::Ident(_, x) => x,
             TokenType::Const(_) => "const",
             TokenType::Comment(_, _, x) => x,
+            TokenType::Directive(_, _, x) => x,
             TokenType::Progmem(_) => "progmem",
             TokenType::EOF => "",
             TokenType::LBrace(_) => "{",
             TokenType::RBrace(_) => "}",
+            TokenType::Number(_, x)
+            | TokenType::Punctuator(_, x)
+            | TokenType::String(_, x)
+            | TokenType::Char(_, x) => x,
         }
         .to_string()
     }
@@ -168,7 +380,7 @@ mod tests {
             TokenType::Comma(49),
             TokenType::Ident(51, "KC_Q".to_string()),
             TokenType::Comma(56),
-            TokenType::Blank(58),
+            TokenType::Blank(58, "_____".to_string()),
             TokenType::Comma(64),
             TokenType::Ident(66, "KC_E".to_string()),
             TokenType::RParen(74),
@@ -204,4 +416,114 @@ mod tests {
 
         assert!(expected_types.is_empty());
     }
+
+    #[test]
+    fn test_directive() {
+        let content = "#ifdef RGB_MATRIX_ENABLE\n  #define FOO \\\n  KC_A\nKC_B";
+
+        let mut lexer = Lexer::new(content);
+
+        assert_eq!(
+            TokenType::Directive(0, 24, "#ifdef RGB_MATRIX_ENABLE".to_string()),
+            lexer.next_token()
+        );
+        assert_eq!(
+            TokenType::Directive(27, 47, "#define FOO \\\n  KC_A".to_string()),
+            lexer.next_token()
+        );
+        assert_eq!(TokenType::Ident(48, "KC_B".to_string()), lexer.next_token());
+    }
+
+    #[test]
+    fn test_literals() {
+        let content = r##"SEND_STRING("a)b\", c") '\n' ')' 0x7E 0b1010 017 100UL 1.5e-3f .5 u8"text" L'x' "open"##;
+
+        let mut lexer = Lexer::new(content);
+        let mut tokens: Vec<TokenType> = vec![];
+        let mut token = lexer.next_token();
+        while token != TokenType::EOF {
+            tokens.push(token);
+            token = lexer.next_token();
+        }
+
+        assert_eq!(
+            vec![
+                TokenType::Ident(0, "SEND_STRING".to_string()),
+                TokenType::LParen(11),
+                TokenType::String(12, r##""a)b\", c""##.to_string()),
+                TokenType::RParen(22),
+                TokenType::Char(24, r##"'\n'"##.to_string()),
+                TokenType::Char(29, "')'".to_string()),
+                TokenType::Number(33, "0x7E".to_string()),
+                TokenType::Number(38, "0b1010".to_string()),
+                TokenType::Number(45, "017".to_string()),
+                TokenType::Number(49, "100UL".to_string()),
+                TokenType::Number(55, "1.5e-3f".to_string()),
+                TokenType::Number(63, ".5".to_string()),
+                TokenType::String(66, r##"u8"text""##.to_string()),
+                TokenType::Char(75, "L'x'".to_string()),
+                TokenType::String(80, r##""open"##.to_string()),
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn test_punctuators() {
+        let content = "a->b <<= c && d...; /* block\n*/ x == y / 2 | MOD_LSFT";
+
+        let mut lexer = Lexer::new(content);
+        let mut tokens: Vec<String> = vec![];
+        let mut token = lexer.next_token();
+        while token != TokenType::EOF {
+            tokens.push(token.to_string());
+            token = lexer.next_token();
+        }
+
+        assert_eq!(
+            vec![
+                "a",
+                "->",
+                "b",
+                "<<=",
+                "c",
+                "&&",
+                "d",
+                "...",
+                ";",
+                "/* block\n*/",
+                "x",
+                "==",
+                "y",
+                "/",
+                "2",
+                "|",
+                "MOD_LSFT"
+            ],
+            tokens
+        );
+
+        let mut lexer = Lexer::new(content);
+        lexer.next_token();
+        assert_eq!(
+            TokenType::Punctuator(1, "->".to_string()),
+            lexer.next_token()
+        );
+    }
+
+    #[test]
+    fn test_unicode_bom_and_crlf() {
+        let content = "\u{feff}const // ╭─╮ naïve\r\nKC_É,\r\n";
+
+        let mut lexer = Lexer::new(content);
+
+        assert_eq!(TokenType::Const(3), lexer.next_token());
+        assert_eq!(
+            TokenType::Comment(9, 28, "// ╭─╮ naïve".to_string()),
+            lexer.next_token()
+        );
+        assert_eq!(TokenType::Ident(30, "KC_É".to_string()), lexer.next_token());
+        assert_eq!(TokenType::Comma(35), lexer.next_token());
+        assert_eq!(TokenType::EOF, lexer.next_token());
+    }
 }
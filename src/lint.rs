@@ -0,0 +1,698 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+};
+
+use crate::{
+    ast::{ComboStatement, EncoderMapStatement, KeymapStatement, StatementEnum, AST},
+    catalogue::{self, Argument},
+    keycode::Keycode,
+    layers,
+    lexer::{Lexer, TokenType},
+    parser::Parser,
+    preprocessor,
+};
+
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub position: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(position: usize, message: String) -> Self {
+        Self { position, message }
+    }
+}
+
+struct Problem {
+    message: String,
+    suggestion: Option<String>,
+}
+
+impl Problem {
+    fn new(message: String) -> Self {
+        Self {
+            message,
+            suggestion: None,
+        }
+    }
+
+    fn with_suggestion(mut self, name: &str, candidates: &[String]) -> Self {
+        // Prefer candidates sharing a longer prefix when the distances are equal
+        let best = candidates
+            .iter()
+            .map(|x| (edit_distance(name, x), x))
+            .min_by_key(|(distance, x)| (*distance, Reverse(common_prefix(name, x))));
+
+        if let Some((distance, suggestion)) = best {
+            if distance <= (name.len() / 3).max(1) {
+                self.suggestion = Some(suggestion.to_string());
+            }
+        }
+
+        self
+    }
+}
+
+struct Context {
+    user_defined: HashSet<String>,
+    layers: Vec<String>,
+    /// The indices of `tap_dance_actions`, when the file declares it
+    tap_dances: Option<Vec<String>>,
+}
+
+pub fn lint(content: &str) -> Vec<Diagnostic> {
+    let lexer = Lexer::new(content);
+    let ast = Parser::new(lexer).parse();
+    let keymaps = ast.keymaps();
+
+    let user_defined: HashSet<String> = get_user_defined_names(content).into_keys().collect();
+    let mut layers: Vec<String> = keymaps.iter().map(|x| x.token.to_string()).collect();
+    layers.extend(user_defined.iter().cloned());
+    let tap_dances = ast.tap_dances().map(|dances| {
+        dances
+            .iter()
+            .filter_map(|x| Some(x.index.as_ref()?.to_string()))
+            .collect()
+    });
+    let context = Context {
+        user_defined,
+        layers,
+        tap_dances,
+    };
+
+    // Check what aliased keys expand to, while reporting them by the name used in the layer
+    let expanded = ast.expanded_keymaps();
+    let expanded: Vec<&KeymapStatement> = expanded.iter().collect();
+
+    let mut diagnostics: Vec<Diagnostic> = layers::analyse_layers(&expanded);
+    for error in ast.errors() {
+        diagnostics.push(Diagnostic::new(error.start, error.message.clone()));
+    }
+    for (keymap, original) in expanded.iter().zip(&keymaps) {
+        let layout_statement = &keymap.layout_statement;
+        for ((key, source), position) in layout_statement
+            .keys
+            .iter()
+            .zip(original.layout_statement.keys.iter())
+            .zip(layout_statement.positions.iter())
+        {
+            let mut problems: Vec<Problem> = vec![];
+            check_keycode(&Keycode::parse(key), &context, &mut problems);
+
+            for problem in problems {
+                let mut message = problem.message;
+                if key != source {
+                    message += &format!(" in `{}`", source);
+                }
+                message += &format!(" on layer {}", keymap.token.to_string());
+                if let Some(suggestion) = problem.suggestion {
+                    message += &format!(", did you mean `{}`?", suggestion);
+                }
+                diagnostics.push(Diagnostic::new(*position, message));
+            }
+        }
+    }
+
+    if let Some(entries) = ast.encoder_map() {
+        check_encoder_map(entries, &keymaps, &context, &mut diagnostics);
+    }
+
+    if let Some(combos) = ast.combos() {
+        let base = expanded.get(layers::get_base_layer(&expanded)).copied();
+        for combo in combos {
+            check_combo(&ast, combo, base, &context, &mut diagnostics);
+        }
+    }
+
+    for statement in &ast.statements {
+        if let StatementEnum::KeyOverrides(start, end, entries) = statement {
+            check_key_overrides(content, &ast, (*start, *end), entries, &mut diagnostics);
+        }
+    }
+
+    diagnostics.sort_by_key(|x| x.position);
+    diagnostics
+}
+
+/// Checks every entry of the `key_overrides` array points at a `key_override_t` defined in
+/// the file. The overrides are only known here, they aren't keycodes a layer can use
+fn check_key_overrides(
+    content: &str,
+    ast: &AST,
+    (start, end): (usize, usize),
+    entries: &[String],
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let names: Vec<String> = ast
+        .key_overrides()
+        .iter()
+        .map(|(_, _, x)| x.token.to_string())
+        .collect();
+
+    for entry in entries {
+        let name = entry.trim_start_matches('&');
+        if names.iter().any(|x| x == name) {
+            continue;
+        }
+
+        let position = content[start..end].find(name).map_or(start, |x| start + x);
+        let problem =
+            Problem::new(format!("Unknown key override `{}`", name)).with_suggestion(name, &names);
+        let mut message = format!("{} in key_overrides", problem.message);
+        if let Some(suggestion) = problem.suggestion {
+            message += &format!(", did you mean `{}`?", suggestion);
+        }
+        diagnostics.push(Diagnostic::new(position, message));
+    }
+}
+
+/// Checks a combo's keys can be pressed on the base layer and that it sends a real keycode
+fn check_combo(
+    ast: &AST,
+    combo: &ComboStatement,
+    base: Option<&KeymapStatement>,
+    context: &Context,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let position = layers::get_position(&combo.token);
+    let Some(combo_keys) = ast.get_combo_keys(combo) else {
+        diagnostics.push(Diagnostic::new(
+            position,
+            format!(
+                "Combo `{}` has no key list ending in COMBO_END",
+                combo.keys_name()
+            ),
+        ));
+        return;
+    };
+
+    if let Some(base) = base {
+        let keys = &combo_keys.keys[..combo_keys.keys.len() - 1];
+        for (key, position) in keys.iter().zip(combo_keys.positions.iter()) {
+            if !base.layout_statement.keys.contains(&ast.expand_key(key)) {
+                diagnostics.push(Diagnostic::new(
+                    *position,
+                    format!(
+                        "Combo key `{}` in {} isn't on the base layer {}",
+                        key,
+                        combo_keys.token.to_string(),
+                        base.token.to_string()
+                    ),
+                ));
+            }
+        }
+    }
+
+    if let Some(result) = combo.result() {
+        let mut problems: Vec<Problem> = vec![];
+        check_keycode(
+            &Keycode::parse(&ast.expand_key(result)),
+            context,
+            &mut problems,
+        );
+
+        for problem in problems {
+            let mut message = format!("{} in combo {}", problem.message, combo.keys_name());
+            if let Some(suggestion) = problem.suggestion {
+                message += &format!(", did you mean `{}`?", suggestion);
+            }
+            diagnostics.push(Diagnostic::new(position, message));
+        }
+    }
+}
+
+/// Cross-checks `encoder_map` against the layers so every layer has one entry with the
+/// same number of encoders
+fn check_encoder_map(
+    entries: &[EncoderMapStatement],
+    keymaps: &[&KeymapStatement],
+    context: &Context,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for keymap in keymaps {
+        if !entries.iter().any(|x| x.is_for(keymap)) {
+            diagnostics.push(Diagnostic::new(
+                layers::get_position(&keymap.token),
+                format!(
+                    "Layer {} has no encoder_map entry",
+                    keymap.token.to_string()
+                ),
+            ));
+        }
+    }
+
+    let expected = entries.first().map(|x| x.encoders.len()).unwrap_or(0);
+    for entry in entries {
+        let position = layers::get_position(&entry.token);
+        if !keymaps.iter().any(|x| entry.is_for(x)) {
+            diagnostics.push(Diagnostic::new(
+                position,
+                format!(
+                    "encoder_map entry {} doesn't match any layer",
+                    entry.token.to_string()
+                ),
+            ));
+        }
+
+        if entry.encoders.len() != expected {
+            diagnostics.push(Diagnostic::new(
+                position,
+                format!(
+                    "encoder_map entry {} has {} encoders but {} has {}",
+                    entry.token.to_string(),
+                    entry.encoders.len(),
+                    entries[0].token.to_string(),
+                    expected
+                ),
+            ));
+        }
+
+        for encoder in &entry.encoders {
+            for key in [&encoder.ccw, &encoder.cw] {
+                let mut problems: Vec<Problem> = vec![];
+                check_keycode(&Keycode::parse(key), context, &mut problems);
+
+                for problem in problems {
+                    let mut message = format!(
+                        "{} on the encoders of layer {}",
+                        problem.message,
+                        entry.token.to_string()
+                    );
+                    if let Some(suggestion) = problem.suggestion {
+                        message += &format!(", did you mean `{}`?", suggestion);
+                    }
+                    diagnostics.push(Diagnostic::new(encoder.position, message));
+                }
+            }
+        }
+    }
+}
+
+/// Converts a byte offset into a 1-based line and column
+pub fn line_column(content: &str, position: usize) -> (usize, usize) {
+    let before = &content.as_bytes()[..position.min(content.len())];
+    let line = before.iter().filter(|x| **x == b'\n').count() + 1;
+    let line_start = before
+        .iter()
+        .rposition(|x| *x == b'\n')
+        .map(|x| x + 1)
+        .unwrap_or(0);
+    // The byte order mark isn't shown by editors, so it doesn't count as a column
+    let column = String::from_utf8_lossy(&before[line_start..])
+        .trim_start_matches('\u{feff}')
+        .chars()
+        .count()
+        + 1;
+
+    (line, column)
+}
+
+fn check_keycode(keycode: &Keycode, context: &Context, problems: &mut Vec<Problem>) {
+    match keycode {
+        Keycode::Basic(name) => {
+            if keycode.is_transparent()
+                || is_number(name)
+                || catalogue::is_basic_keycode(name)
+                || context.user_defined.contains(name)
+            {
+                return;
+            }
+
+            let mut candidates = catalogue::basic_keycodes().to_vec();
+            candidates.extend(context.user_defined.iter().cloned());
+            problems.push(
+                Problem::new(format!("Unknown keycode `{}`", name))
+                    .with_suggestion(name, &candidates),
+            );
+        }
+        Keycode::Function(name, args) => {
+            if context.user_defined.contains(name) {
+                return;
+            }
+
+            let Some(expected) = catalogue::function_arguments(name) else {
+                problems.push(
+                    Problem::new(format!("Unknown keycode function `{}`", name))
+                        .with_suggestion(name, &catalogue::function_keycodes()),
+                );
+                return;
+            };
+
+            if expected.len() != args.len() {
+                problems.push(Problem::new(format!(
+                    "`{}` expects {} argument(s) but got {}",
+                    name,
+                    expected.len(),
+                    args.len()
+                )));
+                return;
+            }
+
+            for (arg, kind) in args.iter().zip(expected.iter()) {
+                match kind {
+                    Argument::Keycode => check_keycode(arg, context, problems),
+                    Argument::Layer => check_layer(arg, context, problems),
+                    Argument::Modifier => check_modifier(arg, context, problems),
+                    Argument::TapDance => check_tap_dance(arg, context, problems),
+                    Argument::Any => {}
+                }
+            }
+        }
+    }
+}
+
+fn check_layer(layer: &Keycode, context: &Context, problems: &mut Vec<Problem>) {
+    let name = layer.name();
+    if is_number(name) || context.layers.iter().any(|x| x == name) {
+        return;
+    }
+
+    problems.push(
+        Problem::new(format!("Unknown layer `{}`", name)).with_suggestion(name, &context.layers),
+    );
+}
+
+fn check_tap_dance(tap_dance: &Keycode, context: &Context, problems: &mut Vec<Problem>) {
+    let Some(tap_dances) = &context.tap_dances else {
+        return;
+    };
+
+    let name = tap_dance.name();
+    if is_number(name) || tap_dances.iter().any(|x| x == name) {
+        return;
+    }
+
+    problems.push(
+        Problem::new(format!("Unknown tap dance `{}`", name)).with_suggestion(name, tap_dances),
+    );
+}
+
+fn check_modifier(modifier: &Keycode, context: &Context, problems: &mut Vec<Problem>) {
+    for name in modifier.to_string().split('|') {
+        if catalogue::is_modifier(name) || context.user_defined.contains(name) {
+            continue;
+        }
+
+        problems.push(Problem::new(format!("Unknown modifier `{}`", name)));
+    }
+}
+
+fn is_number(name: &str) -> bool {
+    name.chars().next().is_some_and(|x| x.is_ascii_digit())
+}
+
+fn common_prefix(a: &str, b: &str) -> usize {
+    a.chars().zip(b.chars()).take_while(|(x, y)| x == y).count()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, x) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, y) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(x != *y);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Names declared by `#define` or inside an `enum` anywhere in the file, with their position
+pub fn get_user_defined_names(content: &str) -> HashMap<String, usize> {
+    let mut lexer = Lexer::new(content);
+    let mut tokens: Vec<TokenType> = vec![];
+    let mut token = lexer.next_token();
+    while token != TokenType::EOF {
+        tokens.push(token);
+        token = lexer.next_token();
+    }
+
+    let mut names: HashMap<String, usize> = HashMap::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i] {
+            TokenType::Directive(start, _, x) => {
+                if let Some((offset, name)) = preprocessor::get_define(x) {
+                    names.entry(name.to_string()).or_insert(start + offset);
+                }
+            }
+            TokenType::Ident(_, x) if x == "enum" => {
+                while i < tokens.len() && !matches!(tokens[i], TokenType::LBrace(..)) {
+                    i += 1;
+                }
+
+                let mut expect_name = true;
+                while i < tokens.len() && !matches!(tokens[i], TokenType::RBrace(..)) {
+                    match &tokens[i] {
+                        TokenType::Ident(position, name) if expect_name => {
+                            names.entry(name.to_string()).or_insert(*position);
+                            expect_name = false;
+                        }
+                        TokenType::Comma(..) => expect_name = true,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+            }
+            _ => {}
+        }
+
+        i += 1;
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_unknown_keycode() {
+        let content = r##"enum layers { _BASE, _NAV };
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(
+  KC_A, KC_ENTR, LT(_NAV, KC_SPC)
+),
+[_NAV] = LAYOUT(
+  _______, MO(_NAVV), KC_TRNS
+),
+}"##;
+
+        let diagnostics = lint(content);
+
+        assert_eq!(
+            vec![
+                Diagnostic::new(
+                    118,
+                    "Unknown keycode `KC_ENTR` on layer _BASE, did you mean `KC_ENT`?".to_string()
+                ),
+                Diagnostic::new(
+                    175,
+                    "Unknown layer `_NAVV` on layer _NAV, did you mean `_NAV`?".to_string()
+                ),
+            ],
+            diagnostics
+        );
+        assert_eq!((4, 9), line_column(content, 118));
+    }
+
+    #[test]
+    fn test_lint_user_defined_names() {
+        let content = r##"#define HOME_A LGUI_T(KC_A)
+enum custom_keycodes {
+    FOO = SAFE_RANGE,
+    BAR,
+};
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(
+  HOME_A, FOO, BAR, OSM(MOD_LSFT|MOD_LCTL), LCTL(LSFT(KC_T))
+),
+}"##;
+
+        assert_eq!(Vec::<Diagnostic>::new(), lint(content));
+    }
+
+    #[test]
+    fn test_lint_through_aliases() {
+        let content = r##"#define HOME_A LGUI_T(KC_ESCC)
+#define NAV MO(_NAV)
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(
+  HOME_A, NAV
+),
+[_NAV] = LAYOUT(
+  KC_B, KC_C
+),
+}"##;
+
+        let messages: Vec<String> = lint(content).into_iter().map(|x| x.message).collect();
+
+        assert_eq!(
+            vec![
+                "Unknown keycode `KC_ESCC` in `HOME_A` on layer _BASE, did you mean `KC_ESC`?"
+                    .to_string(),
+                "`MO(_NAV)` on layer _BASE holds layer _NAV, but that key is `KC_C` on _NAV instead of KC_TRNS".to_string(),
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_lint_encoder_map() {
+        let content = r##"enum layers { _BASE, _NAV, _FN };
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(
+  KC_A, MO(_NAV)
+),
+[_NAV] = LAYOUT(
+  TO(_BASE), _______
+),
+};
+const uint16_t PROGMEM encoder_map[][NUM_ENCODERS][NUM_DIRECTIONS] = {
+[_BASE] = { ENCODER_CCW_CW(KC_VOLD, KC_VOLU), ENCODER_CCW_CW(KC_PGDN, KC_PGUP) },
+[_FN] = { ENCODER_CCW_CW(KC_VOLD, KC_VOLUU) },
+};"##;
+
+        let messages: Vec<String> = lint(content).into_iter().map(|x| x.message).collect();
+
+        assert_eq!(
+            vec![
+                "Layer _NAV has no encoder_map entry".to_string(),
+                "encoder_map entry _FN doesn't match any layer".to_string(),
+                "encoder_map entry _FN has 1 encoders but _BASE has 2".to_string(),
+                "Unknown keycode `KC_VOLUU` on the encoders of layer _FN, did you mean `KC_VOLU`?"
+                    .to_string(),
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_lint_combos() {
+        let content = r##"#define HOME_J RSFT_T(KC_J)
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(
+  HOME_J, KC_K, KC_L
+),
+};
+const uint16_t PROGMEM combo_jk[] = {HOME_J, KC_K, COMBO_END};
+const uint16_t PROGMEM combo_kp[] = {KC_K, KC_P, COMBO_END};
+combo_t key_combos[] = {
+    COMBO(combo_jk, KC_ESCC),
+    COMBO(combo_kp, KC_TAB),
+    COMBO(combo_xy, KC_ENT),
+};"##;
+
+        let messages: Vec<String> = lint(content).into_iter().map(|x| x.message).collect();
+
+        assert_eq!(
+            vec![
+                "Combo key `KC_P` in combo_kp isn't on the base layer _BASE".to_string(),
+                "Unknown keycode `KC_ESCC` in combo combo_jk, did you mean `KC_ESC`?".to_string(),
+                "Combo `combo_xy` has no key list ending in COMBO_END".to_string(),
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_lint_tap_dances() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(
+  TD(TD_ESC_CAPS), TD(TD_ESC_CAP), TD(0)
+),
+};
+tap_dance_action_t tap_dance_actions[] = {
+    [TD_ESC_CAPS] = ACTION_TAP_DANCE_DOUBLE(KC_ESC, KC_CAPS),
+};"##;
+
+        let messages: Vec<String> = lint(content).into_iter().map(|x| x.message).collect();
+
+        assert_eq!(
+            vec![
+                "Unknown tap dance `TD_ESC_CAP` on layer _BASE, did you mean `TD_ESC_CAPS`?"
+                    .to_string()
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_lint_key_overrides() {
+        let content = r##"enum custom_keycodes {
+    MY_MACRO = SAFE_RANGE,
+};
+const key_override_t delete_key_override = ko_make_basic(MOD_MASK_SHIFT, KC_BSPC, KC_DEL);
+const key_override_t *key_overrides[] = {
+    &delete_key_override,
+    &delete_key_overide,
+};
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(
+  MY_MACRO, delete_key_override
+),
+};"##;
+
+        let messages: Vec<String> = lint(content).into_iter().map(|x| x.message).collect();
+
+        assert_eq!(
+            vec![
+                "Unknown key override `delete_key_overide` in key_overrides, did you mean `delete_key_override`?",
+                "Unknown keycode `delete_key_override` on layer _BASE",
+            ],
+            messages
+        );
+        assert_eq!(Some(&27), get_user_defined_names(content).get("MY_MACRO"));
+        assert!(!get_user_defined_names(content).contains_key("delete_key_override"));
+    }
+
+    #[test]
+    fn test_lint_parse_errors() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, KC_B),
+[_NAV] = LAYOUT(KC_C KC_D),
+};"##;
+
+        assert_eq!(
+            vec![Diagnostic::new(
+                93,
+                "Missing comma before `KC_D` in layer _NAV".to_string()
+            )],
+            lint(content)
+        );
+    }
+
+    #[test]
+    fn test_lint_unknown_function() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(
+  LTT(_BASE, KC_A), LT(_BASE), OSM(MOD_LSHIFT)
+),
+}"##;
+
+        let messages: Vec<String> = lint(content).into_iter().map(|x| x.message).collect();
+
+        assert_eq!(
+            vec![
+                "Unknown keycode function `LTT` on layer _BASE, did you mean `LT`?".to_string(),
+                "`LT` expects 2 argument(s) but got 1 on layer _BASE".to_string(),
+                "Unknown modifier `MOD_LSHIFT` on layer _BASE".to_string(),
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(1, edit_distance("KC_ENTR", "KC_ENT"));
+        assert_eq!(0, edit_distance("KC_A", "KC_A"));
+        assert_eq!(3, edit_distance("abc", ""));
+    }
+}
@@ -0,0 +1,629 @@
+use std::{collections::HashMap, error::Error};
+
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+        PublishDiagnostics,
+    },
+    request::{Formatting, GotoDefinition, HoverRequest, Request as _},
+    Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DocumentFormattingParams, GotoDefinitionParams,
+    GotoDefinitionResponse, Hover, HoverContents, HoverParams, HoverProviderCapability, Location,
+    MarkupContent, MarkupKind, OneOf, Position, PublishDiagnosticsParams, Range,
+    ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind, TextEdit, Url,
+};
+
+use crate::{
+    ast::{KeymapStatement, AST},
+    formatter::{FormatOptions, Layout, Mark},
+    get_formatted_file_contents, graph,
+    lexer::{Lexer, TokenType},
+    lint,
+    parser::Parser,
+};
+
+type LspResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Runs the language server over stdin and stdout until the client shuts it down. Documents
+/// are checked and formatted with the same layout and options as the command line uses
+pub fn run(layout: Layout, options: FormatOptions) -> LspResult<()> {
+    let (connection, io_threads) = Connection::stdio();
+    serve(&connection, layout, options)?;
+    io_threads.join()?;
+
+    Ok(())
+}
+
+fn serve(connection: &Connection, layout: Layout, options: FormatOptions) -> LspResult<()> {
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        document_formatting_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        ..ServerCapabilities::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut documents: HashMap<Url, String> = HashMap::new();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+
+                let response = handle_request(request, &documents, &layout, &options);
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                // A notification can't be answered, so one that can't be read is skipped
+                let uri = handle_notification(notification, &mut documents).unwrap_or_else(|e| {
+                    eprintln!("Ignoring notification with invalid params: {}", e);
+                    None
+                });
+                if let Some(uri) = uri {
+                    let content = documents.get(&uri).map(|x| x.as_str()).unwrap_or("");
+                    let params = PublishDiagnosticsParams {
+                        uri,
+                        diagnostics: get_diagnostics(content, &layout, &options),
+                        version: None,
+                    };
+                    let notification = Notification::new(PublishDiagnostics::METHOD.into(), params);
+                    connection
+                        .sender
+                        .send(Message::Notification(notification))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Updates the open documents, returning the document that changed
+fn handle_notification(
+    notification: Notification,
+    documents: &mut HashMap<Url, String>,
+) -> serde_json::Result<Option<Url>> {
+    match notification.method.as_str() {
+        DidOpenTextDocument::METHOD => {
+            let params: DidOpenTextDocumentParams = serde_json::from_value(notification.params)?;
+            let uri = params.text_document.uri;
+            documents.insert(uri.clone(), params.text_document.text);
+            Ok(Some(uri))
+        }
+        DidChangeTextDocument::METHOD => {
+            let params: DidChangeTextDocumentParams = serde_json::from_value(notification.params)?;
+            let uri = params.text_document.uri;
+            if let Some(change) = params.content_changes.into_iter().last() {
+                documents.insert(uri.clone(), change.text);
+            }
+            Ok(Some(uri))
+        }
+        DidCloseTextDocument::METHOD => {
+            let params: DidCloseTextDocumentParams = serde_json::from_value(notification.params)?;
+            documents.remove(&params.text_document.uri);
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+fn handle_request(
+    request: Request,
+    documents: &HashMap<Url, String>,
+    layout: &Layout,
+    options: &FormatOptions,
+) -> Response {
+    let id: RequestId = request.id.clone();
+
+    let result = match request.method.as_str() {
+        Formatting::METHOD => {
+            serde_json::from_value(request.params).and_then(|params: DocumentFormattingParams| {
+                let content = get_document(documents, &params.text_document.uri);
+                serde_json::to_value(get_formatting(content, layout, options))
+            })
+        }
+        HoverRequest::METHOD => {
+            serde_json::from_value(request.params).and_then(|params: HoverParams| {
+                let position = params.text_document_position_params;
+                let content = get_document(documents, &position.text_document.uri);
+                serde_json::to_value(get_hover(content, position.position, layout))
+            })
+        }
+        GotoDefinition::METHOD => {
+            serde_json::from_value(request.params).and_then(|params: GotoDefinitionParams| {
+                let position = params.text_document_position_params;
+                let uri = position.text_document.uri;
+                let content = get_document(documents, &uri);
+                let definition = get_definition(content, position.position)
+                    .map(|range| GotoDefinitionResponse::Scalar(Location::new(uri, range)));
+                serde_json::to_value(definition)
+            })
+        }
+        _ => {
+            let message = format!("Unsupported request: {}", request.method);
+            return Response::new_err(id, lsp_server::ErrorCode::MethodNotFound as i32, message);
+        }
+    };
+
+    match result {
+        Ok(result) => Response {
+            id,
+            result: Some(result),
+            error: None,
+        },
+        Err(e) => Response::new_err(
+            id,
+            lsp_server::ErrorCode::InvalidParams as i32,
+            e.to_string(),
+        ),
+    }
+}
+
+fn get_document<'a>(documents: &'a HashMap<Url, String>, uri: &Url) -> &'a str {
+    documents.get(uri).map(|x| x.as_str()).unwrap_or("")
+}
+
+fn parse(content: &str) -> AST {
+    let lexer = Lexer::new(content);
+    Parser::new(lexer).parse()
+}
+
+fn get_diagnostics(content: &str, layout: &Layout, options: &FormatOptions) -> Vec<Diagnostic> {
+    let layout_keys = layout
+        .iter()
+        .flatten()
+        .filter(|x| matches!(x, Mark::K))
+        .count();
+
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+    // Only the layers that get formatted are checked against the layout
+    let mut parser = Parser::new(Lexer::new(content));
+    if let Some(defines) = &options.defines {
+        parser = parser.with_defines(defines.clone());
+    }
+    for keymap in parser.parse().keymaps() {
+        let keymap_keys = keymap.layout_statement.keys.len();
+        if keymap_keys == layout_keys {
+            continue;
+        }
+
+        let message = format!(
+            "Layer {} has {} keys but the layout expects {}",
+            keymap.token.to_string(),
+            keymap_keys,
+            layout_keys
+        );
+        diagnostics.push(to_lsp_diagnostic(
+            get_token_range(content, &keymap.token),
+            message,
+        ));
+    }
+
+    for diagnostic in lint::lint(content) {
+        let start = to_position(content, diagnostic.position);
+        let end = to_position(content, get_word_end(content, diagnostic.position));
+        diagnostics.push(to_lsp_diagnostic(
+            Range::new(start, end),
+            diagnostic.message,
+        ));
+    }
+
+    diagnostics
+}
+
+fn to_lsp_diagnostic(range: Range, message: String) -> Diagnostic {
+    Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::WARNING),
+        source: Some("qmk_formatter".to_string()),
+        message,
+        ..Diagnostic::default()
+    }
+}
+
+/// Formats the document the way the command line formats files
+fn get_formatting(content: &str, layout: &Layout, options: &FormatOptions) -> Vec<TextEdit> {
+    let formatted = get_formatted_file_contents(content, layout.clone(), options);
+    if formatted == content {
+        return vec![];
+    }
+
+    let end = to_position(content, content.len());
+    vec![TextEdit::new(
+        Range::new(Position::new(0, 0), end),
+        formatted,
+    )]
+}
+
+/// Shows the key at the same position on every layer
+fn get_hover(content: &str, position: Position, layout: &Layout) -> Option<Hover> {
+    let offset = to_offset(content, position);
+    let ast = parse(content);
+    let keymaps = ast.keymaps();
+
+    let index = keymaps
+        .iter()
+        .find_map(|keymap| get_key_index(keymap, content, offset))?;
+
+    let position_names = graph::get_position_names(layout);
+    let mut value = match position_names.get(index) {
+        Some(x) => format!("**Key {}** ({})\n\n", index + 1, x),
+        None => format!("**Key {}**\n\n", index + 1),
+    };
+
+    for keymap in &keymaps {
+        let key = match keymap.layout_statement.keys.get(index) {
+            Some(x) if x.is_empty() => "_______",
+            Some(x) => x,
+            None => "-",
+        };
+        value += &format!("- {}: `{}`\n", keymap.token.to_string(), key);
+    }
+
+    Some(Hover {
+        contents: HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value,
+        }),
+        range: None,
+    })
+}
+
+/// Finds which key of a layer the offset falls on
+fn get_key_index(keymap: &KeymapStatement, content: &str, offset: usize) -> Option<usize> {
+    let positions = &keymap.layout_statement.positions;
+    let index = positions.iter().rposition(|x| *x <= offset)?;
+
+    let end = match positions.get(index + 1) {
+        Some(x) => *x,
+        None => get_word_end(content, positions[index]) + 1,
+    };
+
+    match offset < end {
+        true => Some(index),
+        false => None,
+    }
+}
+
+/// Jumps from a layer name, custom keycode or key override to where it is declared
+fn get_definition(content: &str, position: Position) -> Option<Range> {
+    let offset = to_offset(content, position);
+    let bytes = content.as_bytes();
+    let is_word = |x: &u8| x.is_ascii_alphanumeric() || *x == b'_';
+
+    let start = bytes[..offset.min(bytes.len())]
+        .iter()
+        .rposition(|x| !is_word(x))
+        .map(|x| x + 1)
+        .unwrap_or(0);
+    let end = get_word_end(content, start);
+    let word = &content[start..end];
+    if word.is_empty() {
+        return None;
+    }
+
+    let declaration = match lint::get_user_defined_names(content).get(word) {
+        Some(x) => Some(*x),
+        None => {
+            let ast = parse(content);
+            let layer = ast
+                .keymaps()
+                .iter()
+                .find(|x| x.token.to_string() == word)
+                .map(|x| get_token_start(&x.token));
+            layer.or_else(|| {
+                ast.key_overrides()
+                    .iter()
+                    .find(|(_, _, x)| x.token.to_string() == word)
+                    .map(|(_, _, x)| get_token_start(&x.token))
+            })
+        }
+    }?;
+
+    Some(Range::new(
+        to_position(content, declaration),
+        to_position(content, declaration + word.len()),
+    ))
+}
+
+fn get_token_start(token: &TokenType) -> usize {
+    match token {
+        TokenType::Ident(x, _) => *x,
+        _ => 0,
+    }
+}
+
+fn get_token_range(content: &str, token: &TokenType) -> Range {
+    let start = get_token_start(token);
+    let end = start + token.to_string().len();
+
+    Range::new(to_position(content, start), to_position(content, end))
+}
+
+fn get_word_end(content: &str, start: usize) -> usize {
+    content.as_bytes()[start.min(content.len())..]
+        .iter()
+        .position(|x| !(x.is_ascii_alphanumeric() || *x == b'_'))
+        .map(|x| start + x)
+        .unwrap_or(content.len())
+}
+
+/// Converts a byte offset into an LSP position, which counts UTF-16 code units
+fn to_position(content: &str, offset: usize) -> Position {
+    let offset = offset.min(content.len());
+    let before = &content[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map(|x| x + 1).unwrap_or(0);
+    let character = before[line_start..].encode_utf16().count();
+
+    Position::new(line as u32, character as u32)
+}
+
+fn to_offset(content: &str, position: Position) -> usize {
+    let mut offset = 0;
+    for (i, line) in content.split_inclusive('\n').enumerate() {
+        if i == position.line as usize {
+            let mut units = 0;
+            for (j, ch) in line.char_indices() {
+                if units >= position.character as usize {
+                    return offset + j;
+                }
+                units += ch.len_utf16();
+            }
+            return offset + line.trim_end_matches('\n').len();
+        }
+        offset += line.len();
+    }
+
+    content.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, thread};
+
+    use lsp_types::{
+        notification::{Exit, Initialized},
+        request::{Initialize, Shutdown},
+        DocumentFormattingParams, FormattingOptions, InitializeParams, TextDocumentIdentifier,
+        TextDocumentItem, TextDocumentPositionParams,
+    };
+
+    use crate::formatter::Mark::*;
+
+    use super::*;
+
+    const CONTENT: &str = r##"enum layers { _BASE, _NAV };
+enum custom_keycodes { FOO = SAFE_RANGE };
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, MO(_NAV), FOO),
+[_NAV] = LAYOUT(KC_ENTR, _______, KC_C),
+}"##;
+
+    struct Client {
+        connection: Connection,
+        next_id: i32,
+    }
+
+    impl Client {
+        fn request<R: lsp_types::request::Request>(&mut self, params: R::Params) -> Response {
+            self.next_id += 1;
+            let request = Request::new(self.next_id.into(), R::METHOD.to_string(), params);
+            self.connection.sender.send(request.into()).unwrap();
+
+            loop {
+                match self.connection.receiver.recv().unwrap() {
+                    Message::Response(x) => return x,
+                    _ => continue,
+                }
+            }
+        }
+
+        fn notify<N: lsp_types::notification::Notification>(&self, params: N::Params) {
+            let notification = Notification::new(N::METHOD.to_string(), params);
+            self.connection.sender.send(notification.into()).unwrap();
+        }
+
+        fn receive_notification(&self) -> Notification {
+            loop {
+                if let Message::Notification(x) = self.connection.receiver.recv().unwrap() {
+                    return x;
+                }
+            }
+        }
+    }
+
+    fn position_params(uri: &Url, line: u32, character: u32) -> TextDocumentPositionParams {
+        TextDocumentPositionParams::new(
+            TextDocumentIdentifier::new(uri.clone()),
+            Position::new(line, character),
+        )
+    }
+
+    #[test]
+    fn test_language_server() {
+        let (server, client) = Connection::memory();
+        let layout: Layout = vec![vec![K, K, K]];
+        let options = FormatOptions::default();
+        let formatted = get_formatted_file_contents(CONTENT, layout.clone(), &options);
+        let server = thread::spawn(move || serve(&server, layout, options).unwrap());
+
+        let mut client = Client {
+            connection: client,
+            next_id: 0,
+        };
+        let uri = Url::parse("file:///keymap.c").unwrap();
+
+        #[allow(deprecated)]
+        let initialize = InitializeParams::default();
+        let response = client.request::<Initialize>(initialize);
+        assert!(response.error.is_none());
+        client.notify::<Initialized>(lsp_types::InitializedParams {});
+
+        client.notify::<DidOpenTextDocument>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(
+                uri.clone(),
+                "c".to_string(),
+                1,
+                CONTENT.to_string(),
+            ),
+        });
+
+        // Diagnostics
+        let notification = client.receive_notification();
+        let params: PublishDiagnosticsParams = serde_json::from_value(notification.params).unwrap();
+        let messages: Vec<String> = params.diagnostics.into_iter().map(|x| x.message).collect();
+        assert_eq!(
+            vec!["Unknown keycode `KC_ENTR` on layer _NAV, did you mean `KC_ENT`?".to_string()],
+            messages
+        );
+
+        // Hover
+        let response = client.request::<HoverRequest>(HoverParams {
+            text_document_position_params: position_params(&uri, 3, 24),
+            work_done_progress_params: Default::default(),
+        });
+        let hover: Hover = serde_json::from_value(response.result.unwrap()).unwrap();
+        let HoverContents::Markup(markup) = hover.contents else {
+            panic!("Expected markdown hover");
+        };
+        assert_eq!(
+            "**Key 2** (L row 1 2)\n\n- _BASE: `MO(_NAV)`\n- _NAV: `_______`\n",
+            markup.value
+        );
+
+        // Go to definition of a layer name and a custom keycode
+        let response = client.request::<GotoDefinition>(GotoDefinitionParams {
+            text_document_position_params: position_params(&uri, 3, 27),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        });
+        let definition: GotoDefinitionResponse =
+            serde_json::from_value(response.result.unwrap()).unwrap();
+        assert_eq!(
+            GotoDefinitionResponse::Scalar(Location::new(
+                uri.clone(),
+                Range::new(Position::new(0, 21), Position::new(0, 25))
+            )),
+            definition
+        );
+
+        let response = client.request::<GotoDefinition>(GotoDefinitionParams {
+            text_document_position_params: position_params(&uri, 3, 34),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        });
+        let definition: GotoDefinitionResponse =
+            serde_json::from_value(response.result.unwrap()).unwrap();
+        assert_eq!(
+            GotoDefinitionResponse::Scalar(Location::new(
+                uri.clone(),
+                Range::new(Position::new(1, 23), Position::new(1, 26))
+            )),
+            definition
+        );
+
+        // Formatting gives the same result as the command line, whatever the editor's options
+        let response = client.request::<Formatting>(DocumentFormattingParams {
+            text_document: TextDocumentIdentifier::new(uri.clone()),
+            options: FormattingOptions {
+                tab_size: 4,
+                insert_spaces: true,
+                ..FormattingOptions::default()
+            },
+            work_done_progress_params: Default::default(),
+        });
+        let edits: Vec<TextEdit> = serde_json::from_value(response.result.unwrap()).unwrap();
+        assert_eq!(1, edits.len());
+        assert_eq!(formatted, edits[0].new_text);
+
+        // Params that can't be read are answered with an error, and don't stop the server
+        let notification = Notification::new(DidChangeTextDocument::METHOD.to_string(), 1);
+        client.connection.sender.send(notification.into()).unwrap();
+        client.next_id += 1;
+        let request = Request::new(client.next_id.into(), HoverRequest::METHOD.to_string(), 1);
+        client.connection.sender.send(request.into()).unwrap();
+        let response = loop {
+            if let Message::Response(x) = client.connection.receiver.recv().unwrap() {
+                break x;
+            }
+        };
+        assert_eq!(
+            lsp_server::ErrorCode::InvalidParams as i32,
+            response.error.unwrap().code
+        );
+
+        client.request::<Shutdown>(());
+        client.notify::<Exit>(());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_key_count_diagnostic() {
+        let layout: Layout = vec![vec![K, K]];
+
+        let diagnostics = get_diagnostics(CONTENT, &layout, &FormatOptions::default());
+
+        assert_eq!(
+            "Layer _BASE has 3 keys but the layout expects 2",
+            diagnostics[0].message
+        );
+        assert_eq!(
+            Range::new(Position::new(3, 1), Position::new(3, 6)),
+            diagnostics[0].range
+        );
+    }
+
+    #[test]
+    fn test_key_count_diagnostic_follows_defines() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, KC_B),
+#ifdef GAMING
+[_GAME] = LAYOUT(KC_C),
+#endif
+}"##;
+        let layout: Layout = vec![vec![K, K]];
+        let options = FormatOptions {
+            defines: Some(HashSet::new()),
+            ..FormatOptions::default()
+        };
+
+        let messages = |options: &FormatOptions| -> Vec<String> {
+            get_diagnostics(content, &layout, options)
+                .into_iter()
+                .map(|x| x.message)
+                .filter(|x| x.contains("keys"))
+                .collect()
+        };
+
+        assert_eq!(
+            vec!["Layer _GAME has 1 keys but the layout expects 2"],
+            messages(&FormatOptions::default())
+        );
+        assert!(messages(&options).is_empty());
+    }
+
+    #[test]
+    fn test_key_override_definition() {
+        let content = r##"const key_override_t delete_key_override = ko_make_basic(MOD_MASK_SHIFT, KC_BSPC, KC_DEL);
+const key_override_t *key_overrides[] = {&delete_key_override};"##;
+
+        assert_eq!(
+            Some(Range::new(Position::new(0, 21), Position::new(0, 40))),
+            get_definition(content, Position::new(1, 45))
+        );
+    }
+
+    #[test]
+    fn test_position_conversion() {
+        let content = "// ╭──╮\nKC_A";
+
+        assert_eq!(Position::new(1, 2), to_position(content, content.len() - 2));
+        assert_eq!(content.len() - 2, to_offset(content, Position::new(1, 2)));
+        assert_eq!(3, to_offset(content, Position::new(0, 3)));
+    }
+}
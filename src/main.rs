@@ -1,74 +1,373 @@
+// The original code is written with explicit returns and its own `to_string` methods, and
+// is left the way it was written
+#![allow(
+    clippy::cmp_owned,
+    clippy::get_first,
+    clippy::inherent_to_string,
+    clippy::needless_borrow,
+    clippy::needless_borrows_for_generic_args,
+    clippy::needless_return,
+    clippy::upper_case_acronyms
+)]
+
 use std::{
+    collections::HashSet,
     fs::File,
     io::{Read, Write},
     process::ExitCode,
 };
 
-use clap::Parser as ClapParser;
+use clap::{Parser as ClapParser, Subcommand, ValueEnum};
 use formatter::Layout;
 use formatter::Mark::*;
 use lexer::Lexer;
 use parser::Parser;
 
 use crate::{
-    formatter::{get_keymap_format, get_keymap_string, Mark},
+    ast::{
+        ComboKeysStatement, ComboStatement, EncoderMapStatement, KeyOverrideStatement, KeymapNode,
+        KeymapStatement,
+    },
+    cst::SyntaxTree,
+    formatter::{
+        get_blank_label, get_block_comment, get_call_table_lines, get_combo_keys_strings,
+        get_combo_markers, get_encoder_glyphs, get_encoder_map_string, get_enum_lines,
+        get_fitted_keymap, get_key_override_strings, get_table_body, AliasDisplay, CodeAlignment,
+        DiagramPosition, FormatOptions, Indent, LineEnding, Mark, NoOpSpelling, Split,
+        TransparentSpelling,
+    },
     lexer::TokenType,
+    lint::Diagnostic,
 };
 
 mod ast;
+mod catalogue;
+mod cst;
 mod formatter;
+mod graph;
+mod keycode;
+mod layers;
 mod lexer;
+mod lint;
+mod lsp;
 mod parser;
+mod preprocessor;
+mod zmk;
 
 /// A command line formatter for qmk keymap files
 #[derive(ClapParser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
     /// Filepath to the target file
-    filepath: String,
+    #[arg(required = true)]
+    filepath: Option<String>,
 
     /// Force formatting regardless of filename
     #[arg(short, long, default_value_t = false)]
     force: bool,
+
+    /// Print a graph of the layer transitions instead of formatting the file
+    #[arg(long, value_enum)]
+    export: Option<Export>,
+
+    /// Evaluate #if and #ifdef blocks with this symbol defined, leaving branches that
+    /// aren't compiled untouched
+    #[arg(short = 'D', long = "define", value_name = "SYMBOL")]
+    defines: Vec<String>,
+
+    /// What the diagram shows for keys that use a #define alias
+    #[arg(long, value_enum, default_value_t = AliasDisplay::Name)]
+    aliases: AliasDisplay,
+
+    /// Sort the layers into the order they're declared in the layer enum
+    #[arg(long, default_value_t = false)]
+    reorder_layers: bool,
+
+    /// Draw each layer's encoder_map bindings as knobs under its diagram
+    #[arg(long, default_value_t = false)]
+    encoder_glyphs: bool,
+
+    /// List the keys each combo is made of, and where they sit, under the base layer's diagram
+    #[arg(long, default_value_t = false)]
+    combo_markers: bool,
+
+    /// Label tap dance keys in the diagrams with what a single and a double tap do
+    #[arg(long, default_value_t = false)]
+    tap_dance_labels: bool,
+
+    /// Make each layer fit within this many characters per line, by sizing columns to their
+    /// keys, swapping long labels for a legend and finally splitting rows between the halves
+    #[arg(long, value_name = "WIDTH")]
+    max_line_width: Option<usize>,
+
+    /// Draw the two halves of a split keyboard as separate blocks, either next to each other
+    /// or one above the other
+    #[arg(long, value_enum)]
+    split: Option<SplitMode>,
+
+    /// Spaces between the closest keys of the two halves when they're drawn next to each
+    /// other, which needs a split column in the layout
+    #[arg(long, default_value_t = 4)]
+    gutter: usize,
+
+    /// Where each layer's diagram goes
+    #[arg(long, value_enum, default_value_t = DiagramPosition::Above)]
+    diagram_position: DiagramPosition,
+
+    /// How the keys inside LAYOUT(...) are laid out
+    #[arg(long, value_enum, default_value_t = CodeAlignment::Grid)]
+    code_alignment: CodeAlignment,
+
+    /// How to indent each layer: `tab`, a number of spaces, or `inherit` to follow the
+    /// first layer in the file
+    #[arg(long, value_name = "INDENT", default_value = "0")]
+    indent: Indent,
+
+    /// What each line of the diagrams starts with, `//` and four spaces by default
+    #[arg(long, value_name = "TEXT")]
+    diagram_prefix: Option<String>,
+
+    /// Write every transparent key this way, instead of keeping the spelling it has
+    #[arg(long, value_enum, value_name = "SPELLING")]
+    transparent: Option<TransparentSpelling>,
+
+    /// Write every key that does nothing this way, instead of keeping the spelling it has
+    #[arg(long, value_enum, value_name = "SPELLING")]
+    no_op: Option<NoOpSpelling>,
+
+    /// Rewrite every line of the file with this line ending, instead of keeping the one it uses
+    #[arg(long, value_enum)]
+    line_ending: Option<LineEnding>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert a keymap between QMK C and ZMK devicetree and print the result
+    Convert {
+        /// Filepath to the keymap to convert
+        filepath: String,
+
+        /// Keymap syntax to convert to
+        #[arg(long, value_enum)]
+        to: Target,
+    },
+
+    /// Report unknown or misspelled keycodes and unreachable layers
+    Lint {
+        /// Filepath to the keymap to lint
+        filepath: String,
+    },
+
+    /// Run a language server for keymap files over stdio
+    Lsp,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Export {
+    Dot,
+    Mermaid,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SplitMode {
+    SideBySide,
+    Stacked,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Target {
+    Qmk,
+    Zmk,
 }
 
 fn main() -> ExitCode {
     let args = Args::parse();
+    let layout = get_layout();
 
-    if !validate_args(&args) {
-        return ExitCode::FAILURE;
-    }
+    let result = match &args.command {
+        Some(Command::Convert { filepath, to }) => convert_file(filepath, *to, layout),
+        Some(Command::Lint { filepath }) => lint_file(filepath),
+        Some(Command::Lsp) => {
+            lsp::run(layout, get_format_options(&args)).map_err(|e| eprintln!("{}", e))
+        }
+        None => {
+            if !validate_args(&args) {
+                return ExitCode::FAILURE;
+            }
 
-    let layout = get_layout();
-    match format_file(&args.filepath, layout) {
+            let filepath = args.filepath.as_deref().unwrap_or_default();
+            let options = get_format_options(&args);
+            match args.export {
+                Some(export) => export_file(filepath, export, layout, &options),
+                None => format_file(filepath, layout, &options),
+            }
+        }
+    };
+
+    match result {
         Ok(_) => ExitCode::SUCCESS,
         Err(_) => ExitCode::FAILURE,
     }
 }
 
 fn validate_args(args: &Args) -> bool {
-    if !args.force && !args.filepath.ends_with("keymap.c") {
+    let filepath = args.filepath.as_deref().unwrap_or_default();
+    if !args.force && !filepath.ends_with("keymap.c") {
         println!("Provided filepath isn't a 'keymap.c' file");
         return false;
     }
 
+    // Diagrams outside a block comment have to stay line comments to keep the file compiling
+    let prefix = args.diagram_prefix.as_deref().unwrap_or("//");
+    if args.diagram_position != DiagramPosition::BlockComment && !prefix.starts_with("//") {
+        println!("Diagram prefix has to start with '//'");
+        return false;
+    }
+
     return true;
 }
 
-fn format_file(filepath: &str, layout: Layout) -> Result<(), ()> {
-    let contents = read_file(filepath);
-    let new_contents = get_formatted_file_contents(&contents, layout);
+/// The options files are formatted with, which the language server formats with too
+fn get_format_options(args: &Args) -> FormatOptions {
+    FormatOptions {
+        defines: get_defines(args),
+        aliases: args.aliases,
+        reorder_layers: args.reorder_layers,
+        encoder_glyphs: args.encoder_glyphs,
+        combo_markers: args.combo_markers,
+        tap_dance_labels: args.tap_dance_labels,
+        line_ending: args.line_ending,
+        max_line_width: args.max_line_width,
+        split: args.split.map(|x| match x {
+            SplitMode::SideBySide => Split::SideBySide {
+                gutter: args.gutter,
+            },
+            SplitMode::Stacked => Split::Stacked,
+        }),
+        diagram_position: args.diagram_position,
+        code_alignment: args.code_alignment,
+        indent: args.indent,
+        diagram_prefix: args.diagram_prefix.clone(),
+        transparent: args.transparent,
+        no_op: args.no_op,
+    }
+}
+
+/// The symbols to evaluate conditional directives with, if any were given
+fn get_defines(args: &Args) -> Option<HashSet<String>> {
+    match args.defines.is_empty() {
+        true => None,
+        false => Some(args.defines.iter().cloned().collect()),
+    }
+}
+
+fn format_file(filepath: &str, layout: Layout, options: &FormatOptions) -> Result<(), ()> {
+    let contents = read_file(filepath)?;
+    let (new_contents, warnings) = format_file_contents(&contents, layout, options);
+    for warning in &warnings {
+        let (line, column) = lint::line_column(&contents, warning.position);
+        println!("{}:{}:{}: {}", filepath, line, column, warning.message);
+    }
     write_file(filepath, &new_contents);
 
     return Ok(());
 }
 
-fn read_file(filepath: &str) -> String {
+fn convert_file(filepath: &str, target: Target, layout: Layout) -> Result<(), ()> {
+    let contents = read_file(filepath)?;
+
+    let conversion = match target {
+        Target::Zmk => {
+            let lexer = Lexer::new(&contents);
+            let ast = Parser::new(lexer).parse();
+
+            let expanded = ast.expanded_keymaps();
+            let keymaps: Vec<&KeymapStatement> = expanded.iter().collect();
+            if keymaps.is_empty() {
+                println!("No keymaps found in '{}'", filepath);
+                return Err(());
+            }
+
+            zmk::qmk_to_zmk(&keymaps, &layout)
+        }
+        Target::Qmk => {
+            let conversion = zmk::zmk_to_qmk(&contents);
+            let (output, warnings) =
+                format_file_contents(&conversion.output, layout, &FormatOptions::default());
+            let mut conversion_warnings = conversion.warnings;
+            conversion_warnings.extend(warnings.into_iter().map(|x| x.message));
+            zmk::Conversion {
+                output,
+                warnings: conversion_warnings,
+            }
+        }
+    };
+
+    for warning in &conversion.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    print!("{}", conversion.output);
+
+    Ok(())
+}
+
+fn export_file(
+    filepath: &str,
+    export: Export,
+    layout: Layout,
+    options: &FormatOptions,
+) -> Result<(), ()> {
+    let contents = read_file(filepath)?;
+    let lexer = Lexer::new(&contents);
+    let mut parser = Parser::new(lexer);
+    if let Some(defines) = &options.defines {
+        parser = parser.with_defines(defines.clone());
+    }
+    let ast = parser.parse();
+
+    // Layer switches hidden behind an alias only show up once it's expanded
+    let expanded = ast.expanded_keymaps();
+    let keymaps: Vec<&KeymapStatement> = expanded.iter().collect();
+    let output = match export {
+        Export::Dot => graph::to_dot(&keymaps, &layout),
+        Export::Mermaid => graph::to_mermaid(&keymaps, &layout),
+    };
+    print!("{}", output);
+
+    Ok(())
+}
+
+fn lint_file(filepath: &str) -> Result<(), ()> {
+    let contents = read_file(filepath)?;
+    let diagnostics = lint::lint(&contents);
+
+    for diagnostic in &diagnostics {
+        let (line, column) = lint::line_column(&contents, diagnostic.position);
+        println!("{}:{}:{}: {}", filepath, line, column, diagnostic.message);
+    }
+
+    match diagnostics.is_empty() {
+        true => Ok(()),
+        false => Err(()),
+    }
+}
+
+/// Reads a file, reporting where it stops being valid UTF-8 instead of replacing the bytes
+fn read_file(filepath: &str) -> Result<String, ()> {
     let mut file = File::open(&filepath).expect("Failed to open file");
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)
-        .expect("Failed to read file");
-    contents
+    let mut bytes: Vec<u8> = vec![];
+    file.read_to_end(&mut bytes).expect("Failed to read file");
+
+    String::from_utf8(bytes).map_err(|error| {
+        let valid = &error.as_bytes()[..error.utf8_error().valid_up_to()];
+        let content = std::str::from_utf8(valid).unwrap_or_default();
+        let (line, column) = lint::line_column(content, content.len());
+        println!("{}:{}:{}: File isn't valid UTF-8", filepath, line, column);
+    })
 }
 
 fn write_file(filepath: &str, content: &str) {
@@ -77,74 +376,527 @@ fn write_file(filepath: &str, content: &str) {
         .expect("Failed to write file");
 }
 
-fn get_formatted_file_contents(content: &str, layout: Layout) -> String {
+fn get_formatted_file_contents(content: &str, layout: Layout, options: &FormatOptions) -> String {
+    format_file_contents(content, layout, options).0
+}
+
+/// Formats a file, along with where and why parts of it were left as they were written
+fn format_file_contents(
+    content: &str,
+    layout: Layout,
+    options: &FormatOptions,
+) -> (String, Vec<Diagnostic>) {
     let lexer = Lexer::new(&content);
     let mut parser = Parser::new(lexer);
+    if let Some(defines) = &options.defines {
+        parser = parser.with_defines(defines.clone());
+    }
 
     let ast = parser.parse();
+    let get_label = |key: &str| {
+        if let Some(label) = get_blank_label(key) {
+            return label.to_string();
+        }
 
-    let mut formatting = String::new();
-    for statement in ast.statements {
-        let (start, end, keymaps) = match statement {
-            ast::StatementEnum::KeymapStatement(..) => continue,
-            ast::StatementEnum::Keymaps(x, y, z) => (x, y, z),
+        let tap_dance = match options.tap_dance_labels {
+            true => ast.get_tap_dance_label(key),
+            false => None,
         };
+        if let Some(label) = tap_dance {
+            return label;
+        }
+
+        match options.aliases {
+            AliasDisplay::Name => key.to_string(),
+            AliasDisplay::Expanded => ast.expand_key(key),
+            AliasDisplay::Label => match ast.aliases.get(key).and_then(|x| x.label.as_ref()) {
+                Some(x) => x.to_string(),
+                None => key.to_string(),
+            },
+        }
+    };
 
-        let layout_keys = layout
+    let keymaps = ast.keymaps();
+    let context = FormatContext {
+        content,
+        tree: SyntaxTree::new(content),
+        indent: options
+            .indent
+            .get_text(content, keymaps.first().and_then(|x| x.token.start())),
+        layout: &layout,
+        options,
+        get_label: &get_label,
+        encoder_map: ast.encoder_map(),
+        base_layer: keymaps.get(layers::get_base_layer(&keymaps)).copied(),
+        combos: ast
+            .combos()
+            .unwrap_or_default()
             .iter()
-            .flatten()
-            .filter(|x| match x {
-                Mark::K => true,
-                Mark::B => false,
-            })
-            .count();
-        for keymap in keymaps {
-            // Keymap has the wrong number of keys
-            let keymap_keys = keymap.layout_statement.keys.len();
-            if keymap_keys != layout_keys {
-                println!(
-                    "Keymap has {} keys layout expected {} keys",
-                    keymap_keys, layout_keys
-                );
-                return content.to_string();
+            .filter_map(|x| Some((x, ast.get_combo_keys(x)?)))
+            .collect(),
+    };
+
+    // Every keymap array is formatted, along with the other tables QMK reads
+    let mut replacements: Vec<(usize, usize, String)> = vec![];
+    let mut warnings: Vec<Diagnostic> = vec![];
+    for statement in &ast.statements {
+        let (start, end, formatting) = match statement {
+            ast::StatementEnum::Keymaps(start, end, nodes) => {
+                // The whole inside of the array is rewritten, so the comments between its
+                // layers are replaced by the diagrams. Only the comments before a layer that
+                // is copied over as it was are kept. An array without a single layer that
+                // could be formatted is left alone
+                let mut formatting = String::new();
+                if !format_keymap_nodes(&context, nodes, *end, &mut formatting, &mut warnings) {
+                    continue;
+                }
+                (*start, *end, formatting)
+            }
+            ast::StatementEnum::EncoderMap(start, end, entries) => {
+                (*start, *end, get_encoder_map_string(entries))
+            }
+            ast::StatementEnum::Combos(start, end, combos) => {
+                let entries: Vec<_> = combos
+                    .iter()
+                    .map(|x| (&x.index, &x.token, x.args.as_slice()))
+                    .collect();
+                (
+                    *start,
+                    *end,
+                    get_call_table_body(content, *start, *end, &entries),
+                )
+            }
+            ast::StatementEnum::TapDances(start, end, dances) => {
+                let entries: Vec<_> = dances
+                    .iter()
+                    .map(|x| (&x.index, &x.token, x.args.as_slice()))
+                    .collect();
+                (
+                    *start,
+                    *end,
+                    get_call_table_body(content, *start, *end, &entries),
+                )
+            }
+            // Each entry of the `key_overrides` array goes on its own line
+            ast::StatementEnum::KeyOverrides(start, end, entries) => {
+                let (indent, trailing_comma) = get_table_style(content, *start, *end);
+                (
+                    *start,
+                    *end,
+                    get_table_body(entries, &indent, trailing_comma),
+                )
             }
+            // Enums written on one line, or with comments between members, are left alone
+            ast::StatementEnum::Enum(start, end, statement) => {
+                let body = &content[*start..*end];
+                if !body.contains('\n') || body.contains("//") || body.contains("/*") {
+                    continue;
+                }
+                let (indent, trailing_comma) = get_table_style(content, *start, *end);
+                let lines = get_enum_lines(content, statement);
+                (
+                    *start,
+                    *end,
+                    get_table_body(&lines, &indent, trailing_comma),
+                )
+            }
+            _ => continue,
+        };
 
-            let (display, keymap_formatted) = get_keymap_format(&keymap, layout.clone());
+        let formatting = formatting.trim_start_matches('\n').trim_end();
+        replacements.push((start, end, format!("\n{}\n", formatting)));
+    }
 
-            let display = get_keymap_string(display);
-            let keymap_formatted = get_keymap_string(keymap_formatted);
-            formatting += &display;
-            formatting += "\n";
+    // Combo key lists and key overrides are separate statements, so they're lined up with
+    // each other here
+    let combo_keys = ast.combo_keys();
+    let statements: Vec<&ComboKeysStatement> = combo_keys.iter().map(|x| x.2).collect();
+    for ((start, end, _), formatting) in combo_keys.iter().zip(get_combo_keys_strings(&statements))
+    {
+        replacements.push((*start, *end, formatting));
+    }
 
-            formatting += "[";
-            formatting += match &keymap.token {
-                TokenType::Ident(_, x) => x,
-                _ => "",
-            };
+    let key_overrides = ast.key_overrides();
+    let statements: Vec<&KeyOverrideStatement> = key_overrides.iter().map(|x| x.2).collect();
+    for ((start, end, _), formatting) in key_overrides
+        .iter()
+        .zip(get_key_override_strings(&statements))
+    {
+        replacements.push((*start, *end, formatting));
+    }
+
+    // Only the regions handed to the formatter are reprinted, the rest of the tree is
+    // printed back exactly as it was read
+    let line_ending = options
+        .line_ending
+        .unwrap_or_else(|| LineEnding::detect(content));
+    let mut tree = context.tree;
+    for (start, end, formatting) in replacements {
+        if tree
+            .own(start, end, line_ending.apply(&formatting))
+            .is_err()
+        {
+            eprintln!("Formatting overlaps another region at byte {}", start);
+            return (content.to_string(), warnings);
+        }
+    }
+
+    let mut output = tree.to_string();
+    if options.line_ending.is_some() {
+        output = line_ending.apply(&output);
+    }
 
-            formatting += "] = LAYOUT(\n";
-            formatting += &keymap_formatted;
+    // The file ends with a line break only if it did before
+    let trimmed = output.trim_end_matches(['\r', '\n']).len();
+    match (content.ends_with('\n'), output.ends_with('\n')) {
+        (true, false) => output += line_ending.as_str(),
+        (false, true) => output.truncate(trimmed),
+        _ => {}
+    }
+
+    (output, warnings)
+}
+
+/// Lines up a table of macro calls whose braces hold `start..end`, keeping the table's
+/// indentation and trailing comma
+fn get_call_table_body(
+    content: &str,
+    start: usize,
+    end: usize,
+    entries: &[(&Option<TokenType>, &TokenType, &[String])],
+) -> String {
+    let (indent, trailing_comma) = get_table_style(content, start, end);
+    get_table_body(&get_call_table_lines(entries), &indent, trailing_comma)
+}
+
+/// The indentation of the first entry in the table whose braces hold `start..end`, and
+/// whether a comma follows its last entry
+fn get_table_style(content: &str, start: usize, end: usize) -> (String, bool) {
+    let body = &content[start..end];
+    let first = body.find(|x: char| !x.is_whitespace()).map(|x| start + x);
+    let indent = Indent::Inherit.get_text(content, first);
 
-            formatting += "),";
-            formatting += "\n\n";
+    let mut lexer = Lexer::new(body);
+    let mut last = TokenType::EOF;
+    loop {
+        match lexer.next_token() {
+            TokenType::EOF => break,
+            TokenType::Comment(..) => {}
+            token => last = token,
         }
+    }
 
-        let (first, last) = content.split_at(start + 1);
-        let (_, ending) = last.split_at(end - start - 1);
-        let res = format!("{}\n{}\n{}", first, formatting.trim(), ending);
+    (indent, matches!(last, TokenType::Comma(..)))
+}
 
-        return res;
+/// What every layer in the file is formatted with
+struct FormatContext<'a> {
+    content: &'a str,
+    tree: SyntaxTree,
+    /// What each line of a layer starts with
+    indent: String,
+    layout: &'a Layout,
+    options: &'a FormatOptions,
+    get_label: &'a dyn Fn(&str) -> String,
+    encoder_map: Option<&'a [EncoderMapStatement]>,
+    base_layer: Option<&'a KeymapStatement>,
+    combos: Vec<(&'a ComboStatement, &'a ComboKeysStatement)>,
+}
+
+/// Formats the layers of a keymap array that closes at `end`, returning whether any of them
+/// were. Layers that can't be formatted are added to `warnings` and copied over exactly as
+/// they were written
+fn format_keymap_nodes(
+    context: &FormatContext,
+    nodes: &[KeymapNode],
+    end: usize,
+    formatting: &mut String,
+    warnings: &mut Vec<Diagnostic>,
+) -> bool {
+    let mut nodes: Vec<&KeymapNode> = nodes.iter().collect();
+    if context.options.reorder_layers {
+        sort_keymap_nodes(&mut nodes);
     }
 
-    return content.to_string();
+    let mut formatted = false;
+    for node in nodes {
+        match node {
+            KeymapNode::Keymap(keymap) => {
+                match format_keymap(context, keymap, formatting, warnings) {
+                    Ok(()) => formatted = true,
+                    Err(message) => {
+                        let (start, end) = keymap.span;
+                        let warning = Diagnostic::new(start, message);
+                        push_unformatted(context, end, warning, formatting, warnings);
+                    }
+                }
+            }
+            KeymapNode::Directive(directive) => push_directive(formatting, directive, true, true),
+            KeymapNode::Error(error) => {
+                let warning = Diagnostic::new(error.start, error.message.clone());
+                push_unformatted(context, error.end, warning, formatting, warnings);
+            }
+            KeymapNode::Conditional(conditional) => {
+                // Without its #endif the block runs to the end of the array
+                let Some(endif) = &conditional.end else {
+                    let start = conditional
+                        .branches
+                        .first()
+                        .map_or(end, |x| get_directive_start(&x.directive));
+                    let message = "Conditional block is missing its #endif".to_string();
+                    let warning = Diagnostic::new(start, message);
+                    push_unformatted(context, end, warning, formatting, warnings);
+                    continue;
+                };
+
+                for (i, branch) in conditional.branches.iter().enumerate() {
+                    push_directive(formatting, &branch.directive, i == 0, false);
+
+                    // Branches that aren't compiled are left exactly as they were written
+                    if branch.active == Some(false) {
+                        let next = match conditional.branches.get(i + 1) {
+                            Some(x) => &x.directive,
+                            None => endif,
+                        };
+                        let text = context.tree.text(
+                            get_directive_end(&branch.directive),
+                            get_directive_start(next),
+                        );
+                        *formatting += text.trim_start_matches(['\r', '\n']).trim_end();
+                        *formatting += "\n";
+                        continue;
+                    }
+
+                    formatted |=
+                        format_keymap_nodes(context, &branch.nodes, end, formatting, warnings);
+                }
+
+                push_directive(formatting, endif, false, true);
+            }
+        }
+    }
+
+    formatted
+}
+
+/// Reports why the source from the warning's position to `end` couldn't be formatted, and
+/// copies it over byte for byte along with the comments and indentation before it
+fn push_unformatted(
+    context: &FormatContext,
+    end: usize,
+    warning: Diagnostic,
+    formatting: &mut String,
+    warnings: &mut Vec<Diagnostic>,
+) {
+    let (start, end) = context.tree.get_full_span(warning.position, end);
+    warnings.push(warning);
+
+    // The copy brings its own blank lines, in place of the one left after the last entry
+    if formatting.ends_with("\n\n") {
+        formatting.pop();
+    }
+    *formatting += &context.tree.text(start, end);
+    if !formatting.ends_with('\n') {
+        *formatting += "\n";
+    }
+
+    // A blank line after it is kept too, as the entry after it doesn't keep its own
+    let rest = context.content[end..].trim_start_matches([' ', '\t', '\r']);
+    if rest.starts_with('\n') {
+        *formatting += "\n";
+    }
+}
+
+/// Sorts each run of layers between directives by layer number, so they follow the
+/// order of the layer enum
+fn sort_keymap_nodes(nodes: &mut [&KeymapNode]) {
+    let is_keymap = |x: &&KeymapNode| matches!(x, KeymapNode::Keymap(..));
+    for run in nodes.split_mut(|x| !is_keymap(x)) {
+        run.sort_by_key(|x| match x {
+            KeymapNode::Keymap(keymap) => keymap.index.unwrap_or(usize::MAX),
+            _ => usize::MAX,
+        });
+    }
+}
+
+fn format_keymap(
+    context: &FormatContext,
+    keymap: &KeymapStatement,
+    formatting: &mut String,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<(), String> {
+    let layout = context.layout;
+    let get_label = context.get_label;
+    if !keymap.layout_statement.directives.is_empty() {
+        return Err(format!(
+            "Keymap {} has preprocessor directives between its keys",
+            keymap.token.to_string()
+        ));
+    }
+
+    let layout_keys = layout
+        .iter()
+        .flatten()
+        .filter(|x| matches!(x, Mark::K))
+        .count();
+
+    // Keymap has the wrong number of keys
+    let keymap_keys = keymap.layout_statement.keys.len();
+    if keymap_keys != layout_keys {
+        return Err(format!(
+            "Keymap {} has {} keys layout expected {} keys",
+            keymap.token.to_string(),
+            keymap_keys,
+            layout_keys
+        ));
+    }
+
+    let labels: Vec<String> = keymap
+        .layout_statement
+        .keys
+        .iter()
+        .map(|x| get_label(x))
+        .collect();
+    let options = context.options;
+    // The indentation takes up part of each line, leaving less room for the layer itself
+    let indent_width = context.indent.chars().count();
+    let fit_options = FormatOptions {
+        max_line_width: options
+            .max_line_width
+            .map(|x| x.saturating_sub(indent_width)),
+        ..options.clone()
+    };
+    // Transparent and no-op keys are written the way they're configured to be
+    let mut normalized = keymap.clone();
+    for key in normalized.layout_statement.keys.iter_mut() {
+        if let Some(spelling) = options.get_blank_spelling(key) {
+            *key = spelling.to_string();
+        }
+    }
+    let formatted =
+        get_fitted_keymap(&normalized, &labels, layout, &fit_options).unwrap_or_else(|x| {
+            warnings.push(Diagnostic::new(
+                layers::get_position(&keymap.token),
+                format!(
+                "Keymap {} is {} characters wide at its most compact, more than the maximum of {}",
+                keymap.token.to_string(),
+                x.width() + indent_width,
+                options.max_line_width.unwrap_or_default()
+            ),
+            ));
+            x
+        });
+    let start = formatting.len();
+    // The diagram and the notes under it make up one comment block
+    let mut notes = String::new();
+    if options.diagram_position != DiagramPosition::None {
+        notes += &formatted.display;
+        notes += "\n";
+    }
+
+    let encoders = context
+        .encoder_map
+        .and_then(|x| x.iter().find(|x| x.is_for(keymap)));
+    if let (true, Some(encoders)) = (context.options.encoder_glyphs, encoders) {
+        notes += &get_encoder_glyphs(encoders, options.line_start());
+        notes += "\n";
+    }
+
+    let is_base_layer = context.base_layer.is_some_and(|x| std::ptr::eq(x, keymap));
+    if context.options.combo_markers && is_base_layer {
+        let position_names = graph::get_position_names(context.layout);
+        for line in get_combo_markers(
+            &context.combos,
+            keymap,
+            &position_names,
+            options.line_start(),
+        ) {
+            notes += &line;
+            notes += "\n";
+        }
+    }
+
+    if options.diagram_position == DiagramPosition::BlockComment && !notes.is_empty() {
+        notes = get_block_comment(&notes) + "\n";
+    }
+    let is_above = matches!(
+        options.diagram_position,
+        DiagramPosition::Above | DiagramPosition::None
+    );
+    if is_above {
+        *formatting += &notes;
+    }
+
+    *formatting += "[";
+    *formatting += match &keymap.token {
+        TokenType::Ident(_, x) => x,
+        _ => "",
+    };
+
+    *formatting += "] = LAYOUT(\n";
+    *formatting += &formatted.code;
+    *formatting += "),";
+    *formatting += "\n";
+    if !is_above {
+        *formatting += &notes;
+    }
+
+    let entry = formatting.split_off(start);
+    for line in entry.lines() {
+        if !line.is_empty() {
+            *formatting += &context.indent;
+        }
+        *formatting += line;
+        *formatting += "\n";
+    }
+    *formatting += "\n";
+
+    Ok(())
+}
+
+/// Writes a directive on its own line, optionally separated by a blank line from what's
+/// around it
+fn push_directive(
+    formatting: &mut String,
+    directive: &TokenType,
+    blank_before: bool,
+    blank_after: bool,
+) {
+    formatting.truncate(formatting.trim_end().len());
+    if !formatting.is_empty() {
+        *formatting += match blank_before {
+            true => "\n\n",
+            false => "\n",
+        };
+    }
+
+    *formatting += directive.to_string().trim_end();
+    *formatting += match blank_after {
+        true => "\n\n",
+        false => "\n",
+    };
+}
+
+fn get_directive_start(directive: &TokenType) -> usize {
+    match directive {
+        TokenType::Directive(x, _, _) => *x,
+        _ => 0,
+    }
+}
+
+fn get_directive_end(directive: &TokenType) -> usize {
+    match directive {
+        TokenType::Directive(_, x, _) => *x,
+        _ => 0,
+    }
 }
 
 fn get_layout() -> Layout {
     return vec![
-        vec![K, K, K, K, K, K, B, B, B, B, B, K, K, K, K, K, K],
-        vec![K, K, K, K, K, K, B, B, B, B, B, K, K, K, K, K, K],
-        vec![K, K, K, K, K, K, K, K, B, K, K, K, K, K, K, K, K],
-        vec![B, B, B, K, K, K, K, K, B, K, K, K, K, K, B, B, B],
+        vec![K, K, K, K, K, K, B, B, S, B, B, K, K, K, K, K, K],
+        vec![K, K, K, K, K, K, B, B, S, B, B, K, K, K, K, K, K],
+        vec![K, K, K, K, K, K, K, K, S, K, K, K, K, K, K, K, K],
+        vec![B, B, B, K, K, K, K, K, S, K, K, K, K, K, B, B, B],
     ];
 }
 
@@ -155,8 +907,26 @@ mod tests {
     #[test]
     fn test_args_validation_invalid_filename() {
         let args = Args {
-            filepath: "/home/path/invalid.c".to_string(),
+            filepath: Some("/home/path/invalid.c".to_string()),
             force: false,
+            export: None,
+            defines: vec![],
+            aliases: AliasDisplay::Name,
+            reorder_layers: false,
+            encoder_glyphs: false,
+            combo_markers: false,
+            tap_dance_labels: false,
+            line_ending: None,
+            max_line_width: None,
+            split: None,
+            gutter: 4,
+            diagram_position: DiagramPosition::Above,
+            code_alignment: CodeAlignment::Grid,
+            indent: Indent::default(),
+            diagram_prefix: None,
+            transparent: None,
+            no_op: None,
+            command: None,
         };
         let result = validate_args(&args);
 
@@ -166,8 +936,26 @@ mod tests {
     #[test]
     fn test_args_validation_invalid_filename_force() {
         let args = Args {
-            filepath: "/home/path/invalid.c".to_string(),
+            filepath: Some("/home/path/invalid.c".to_string()),
             force: true,
+            export: None,
+            defines: vec![],
+            aliases: AliasDisplay::Name,
+            reorder_layers: false,
+            encoder_glyphs: false,
+            combo_markers: false,
+            tap_dance_labels: false,
+            line_ending: None,
+            max_line_width: None,
+            split: None,
+            gutter: 4,
+            diagram_position: DiagramPosition::Above,
+            code_alignment: CodeAlignment::Grid,
+            indent: Indent::default(),
+            diagram_prefix: None,
+            transparent: None,
+            no_op: None,
+            command: None,
         };
         let result = validate_args(&args);
 
@@ -222,51 +1010,53 @@ const thing = other;
             vec![K, K, K, K, K, K, K, K, B, K, K, K, K, K, K, K, K],
             vec![B, B, B, K, K, K, K, K, B, K, K, K, K, K, B, B, B],
         ];
-        let result = get_formatted_file_contents(content, layout);
+        let result = get_formatted_file_contents(content, layout, &FormatOptions::default());
 
         let expected = r##"const thing = other;
 // top comments
 const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
-//    ╭─────────┬─────────┬─────────┬─────────┬─────────┬─────────╮                                                 ╭─────────┬─────────┬─────────┬─────────┬─────────┬─────────╮         
-//    │ KC_ESC  │ KC_Q    │ KC_W    │ KC_E    │ KC_R    │ KC_T    │                                                 │ KC_Y    │ KC_U    │ KC_I    │ KC_O    │ KC_P    │ KC_BSPC │         
-//    ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤                                                 ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤         
-//    │ SFT_TAB │ KC_A    │ KC_S    │ KC_D    │ KC_F    │ KC_G    │                                                 │ KC_H    │ KC_J    │ KC_K    │ KC_L    │ KC_SCLN │ SFT_QOT │         
-//    ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┼─────────┬─────────╮         ╭─────────┬─────────┼─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤         
-//    │ KC_LCTL │ KC_Z    │ KC_X    │ KC_C    │ KC_V    │ KC_B    │ KC_CPYP │ ADJUST  │         │ FKEYS   │         │ KC_N    │ KC_M    │ KC_COMM │ KC_DOT  │ KC_SLSH │ KC_RCTL │         
-//    ╰─────────┴─────────┴─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤         ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┴─────────┴─────────╯         
-//                                  │ KC_LALT │ NAV     │ KC_LGUI │ KC_ENT  │ SYM     │         │ SYM     │ KC_SPC  │ NAV     │         │         │                                       
-//                                  ╰─────────┴─────────┴─────────┴─────────┴─────────╯         ╰─────────┴─────────┴─────────┴─────────┴─────────╯                                       
+//    ╭─────────┬─────────┬─────────┬─────────┬─────────┬─────────╮                                                 ╭─────────┬─────────┬─────────┬─────────┬─────────┬─────────╮
+//    │ KC_ESC  │ KC_Q    │ KC_W    │ KC_E    │ KC_R    │ KC_T    │                                                 │ KC_Y    │ KC_U    │ KC_I    │ KC_O    │ KC_P    │ KC_BSPC │
+//    ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤                                                 ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤
+//    │ SFT_TAB │ KC_A    │ KC_S    │ KC_D    │ KC_F    │ KC_G    │                                                 │ KC_H    │ KC_J    │ KC_K    │ KC_L    │ KC_SCLN │ SFT_QOT │
+//    ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┼─────────┬─────────╮         ╭─────────┬─────────┼─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤
+//    │ KC_LCTL │ KC_Z    │ KC_X    │ KC_C    │ KC_V    │ KC_B    │ KC_CPYP │ ADJUST  │         │ FKEYS   │         │ KC_N    │ KC_M    │ KC_COMM │ KC_DOT  │ KC_SLSH │ KC_RCTL │
+//    ╰─────────┴─────────┴─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤         ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┴─────────┴─────────╯
+//                                  │ KC_LALT │ NAV     │ KC_LGUI │ KC_ENT  │ SYM     │         │ SYM     │ KC_SPC  │ NAV     │         │         │
+//                                  ╰─────────┴─────────┴─────────┴─────────┴─────────╯         ╰─────────┴─────────┴─────────┴─────────┴─────────╯
 [_QWERTY] = LAYOUT(
- KC_ESC  , KC_Q    , KC_W    , KC_E    , KC_R    , KC_T    ,                                                   KC_Y    , KC_U    , KC_I    , KC_O    , KC_P    , KC_BSPC ,          
- SFT_TAB , KC_A    , KC_S    , KC_D    , KC_F    , KC_G    ,                                                   KC_H    , KC_J    , KC_K    , KC_L    , KC_SCLN , SFT_QOT ,          
- KC_LCTL , KC_Z    , KC_X    , KC_C    , KC_V    , KC_B    , KC_CPYP , ADJUST  ,           FKEYS   , _______ , KC_N    , KC_M    , KC_COMM , KC_DOT  , KC_SLSH , KC_RCTL ,          
-                               KC_LALT , NAV     , KC_LGUI , KC_ENT  , SYM     ,           SYM     , KC_SPC  , NAV     , _______ , _______                                          
+ KC_ESC  , KC_Q    , KC_W    , KC_E    , KC_R    , KC_T    ,                                                   KC_Y    , KC_U    , KC_I    , KC_O    , KC_P    , KC_BSPC ,
+ SFT_TAB , KC_A    , KC_S    , KC_D    , KC_F    , KC_G    ,                                                   KC_H    , KC_J    , KC_K    , KC_L    , KC_SCLN , SFT_QOT ,
+ KC_LCTL , KC_Z    , KC_X    , KC_C    , KC_V    , KC_B    , KC_CPYP , ADJUST  ,           FKEYS   , _______ , KC_N    , KC_M    , KC_COMM , KC_DOT  , KC_SLSH , KC_RCTL ,
+                               KC_LALT , NAV     , KC_LGUI , KC_ENT  , SYM     ,           SYM     , KC_SPC  , NAV     , _______ , _______
 ),
 
-//    ╭─────────┬─────────┬─────────┬─────────┬─────────┬─────────╮                                                 ╭─────────┬─────────┬─────────┬─────────┬─────────┬─────────╮         
-//    │         │         │         │         │         │         │                                                 │         │         │         │         │         │ KC_DEL  │         
-//    ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤                                                 ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤         
-//    │         │ KC_LGUI │ KC_LALT │ KC_LCTL │ KC_LSFT │         │                                                 │ KC_LEFT │ KC_DOWN │ KC_UP   │ KC_RGHT │         │         │         
-//    ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┼─────────┬─────────╮         ╭─────────┬─────────┼─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤         
-//    │         │         │         │         │         │         │         │ KC_SCRL │         │         │         │         │         │         │         │         │         │         
-//    ╰─────────┴─────────┴─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤         ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┴─────────┴─────────╯         
-//                                  │         │         │         │         │         │         │         │         │         │         │         │                                       
-//                                  ╰─────────┴─────────┴─────────┴─────────┴─────────╯         ╰─────────┴─────────┴─────────┴─────────┴─────────╯                                       
+//    ╭─────────┬─────────┬─────────┬─────────┬─────────┬─────────╮                                                 ╭─────────┬─────────┬─────────┬─────────┬─────────┬─────────╮
+//    │         │         │         │         │         │         │                                                 │         │         │         │         │         │ KC_DEL  │
+//    ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤                                                 ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤
+//    │         │ KC_LGUI │ KC_LALT │ KC_LCTL │ KC_LSFT │         │                                                 │ KC_LEFT │ KC_DOWN │ KC_UP   │ KC_RGHT │         │         │
+//    ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┼─────────┬─────────╮         ╭─────────┬─────────┼─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤
+//    │         │         │         │         │         │         │         │ KC_SCRL │         │         │         │         │         │         │         │         │         │
+//    ╰─────────┴─────────┴─────────┼─────────┼─────────┼─────────┼─────────┼─────────┤         ├─────────┼─────────┼─────────┼─────────┼─────────┼─────────┴─────────┴─────────╯
+//                                  │         │         │         │         │         │         │         │         │         │         │         │
+//                                  ╰─────────┴─────────┴─────────┴─────────┴─────────╯         ╰─────────┴─────────┴─────────┴─────────┴─────────╯
 [_NAV] = LAYOUT(
- _______ , _______ , _______ , _______ , _______ , _______ ,                                                   _______ , _______ , _______ , _______ , _______ , KC_DEL  ,          
- _______ , KC_LGUI , KC_LALT , KC_LCTL , KC_LSFT , _______ ,                                                   KC_LEFT , KC_DOWN , KC_UP   , KC_RGHT , _______ , _______ ,          
- _______ , _______ , _______ , _______ , _______ , _______ , _______ , KC_SCRL ,           _______ , _______ , _______ , _______ , _______ , _______ , _______ , _______ ,          
-                               _______ , _______ , _______ , _______ , _______ ,           _______ , _______ , _______ , _______ , _______                                          
+ _______ , _______ , _______ , _______ , _______ , _______ ,                                                   _______ , _______ , _______ , _______ , _______ , KC_DEL  ,
+ _______ , KC_LGUI , KC_LALT , KC_LCTL , KC_LSFT , _______ ,                                                   KC_LEFT , KC_DOWN , KC_UP   , KC_RGHT , _______ , _______ ,
+ _______ , _______ , _______ , _______ , _______ , _______ , _______ , KC_SCRL ,           _______ , _______ , _______ , _______ , _______ , _______ , _______ , _______ ,
+                               _______ , _______ , _______ , _______ , _______ ,           _______ , _______ , _______ , _______ , _______
 ),
 }
 
 // something
-const thing = other;"##;
+const thing = other;
+"##;
 
         println!("{}", result);
         for (i, (ex, res)) in expected.chars().zip(result.chars()).enumerate() {
             assert_eq!(ex, res, "Char index: {}", i);
         }
+        assert_eq!(expected, result);
     }
 
     #[test]
@@ -283,7 +1073,7 @@ const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
 const thing = other;
 "##;
         let layout: Layout = vec![vec![Mark::K, Mark::K, Mark::K]];
-        let result = get_formatted_file_contents(content, layout);
+        let result = get_formatted_file_contents(content, layout, &FormatOptions::default());
 
         assert_eq!(content, result)
     }
@@ -302,8 +1092,504 @@ const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
 const thing = other;
 "##;
         let layout: Layout = vec![vec![K, K, K, K, K, K, K]];
-        let result = get_formatted_file_contents(content, layout);
+        let result = get_formatted_file_contents(content, layout, &FormatOptions::default());
 
         assert_eq!(content, result)
     }
+
+    #[test]
+    fn test_conditional_layers() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, MO(_NAV)),
+#ifdef RGB_MATRIX_ENABLE
+[_NAV] = LAYOUT(RGB_TOG, _______),
+#else
+    [_NAV] = LAYOUT(  KC_NO,_______ ),
+#endif // RGB_MATRIX_ENABLE
+#define FOO KC_B
+};
+"##;
+        let layout: Layout = vec![vec![K, K]];
+
+        let result =
+            get_formatted_file_contents(content, layout.clone(), &FormatOptions::default());
+
+        assert!(result.contains("),\n\n#ifdef RGB_MATRIX_ENABLE\n//    ╭─────────┬"));
+        assert!(
+            result.contains("),\n#else\n//    ╭─────────┬─────────╮\n//    │ ✕       │         │")
+        );
+        assert!(result.contains(" KC_NO   , _______"));
+        assert!(result.ends_with("),\n#endif // RGB_MATRIX_ENABLE\n\n#define FOO KC_B\n};\n"));
+
+        let options = FormatOptions {
+            defines: Some(HashSet::from(["RGB_MATRIX_ENABLE".to_string()])),
+            ..FormatOptions::default()
+        };
+        let result = get_formatted_file_contents(content, layout, &options);
+
+        assert!(result.contains(" RGB_TOG , _______"));
+        assert!(result.ends_with(
+            "),\n#else\n    [_NAV] = LAYOUT(  KC_NO,_______ ),\n#endif // RGB_MATRIX_ENABLE\n\n#define FOO KC_B\n};\n"
+        ));
+    }
+
+    #[test]
+    fn test_alias_display() {
+        let content = r##"#define HOME_A LGUI_T(KC_A) // GUI/A
+#define NAV MO(1)
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(HOME_A, NAV),
+};
+"##;
+        let layout: Layout = vec![vec![K, K]];
+        let format = |aliases: AliasDisplay| {
+            let options = FormatOptions {
+                aliases,
+                ..FormatOptions::default()
+            };
+            get_formatted_file_contents(content, layout.clone(), &options)
+        };
+
+        let result = format(AliasDisplay::Label);
+        assert!(result.starts_with("#define HOME_A LGUI_T(KC_A) // GUI/A\n#define NAV MO(1)\n"));
+        assert!(result.contains("//    │ GUI/A  │ NAV    │"));
+        assert!(result.contains(" HOME_A , NAV"));
+
+        let result = format(AliasDisplay::Expanded);
+        assert!(result.contains("//    │ LGUI_T(KC_A) │ MO(1)        │"));
+        assert!(result.contains(" HOME_A       , NAV"));
+    }
+
+    #[test]
+    fn test_reorder_layers() {
+        let content = r##"enum layers { _BASE, _NAV, _SYM };
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_SYM] = LAYOUT(KC_C),
+[_BASE] = LAYOUT(KC_A),
+#ifdef NAV_ENABLE
+[_NAV] = LAYOUT(KC_B),
+#endif
+[1] = LAYOUT(KC_D),
+};
+"##;
+        let options = FormatOptions {
+            reorder_layers: true,
+            ..FormatOptions::default()
+        };
+
+        let result = get_formatted_file_contents(content, vec![vec![K]], &options);
+
+        let order: Vec<&str> = result
+            .lines()
+            .filter(|x| x.starts_with('[') || x.starts_with('#'))
+            .collect();
+        assert_eq!(
+            vec![
+                "[_BASE] = LAYOUT(",
+                "[_SYM] = LAYOUT(",
+                "#ifdef NAV_ENABLE",
+                "[_NAV] = LAYOUT(",
+                "#endif",
+                "[1] = LAYOUT("
+            ],
+            order
+        );
+    }
+
+    #[test]
+    fn test_encoder_map() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A),
+[_NAV] = LAYOUT(KC_B),
+};
+const uint16_t PROGMEM encoder_map[][NUM_ENCODERS][NUM_DIRECTIONS] = {
+    [_BASE] = {ENCODER_CCW_CW(KC_VOLD,KC_VOLU),  ENCODER_CCW_CW(KC_PGDN, KC_PGUP)},
+  [_NAV]={ ENCODER_CCW_CW(C(KC_Z), C(KC_Y)), ENCODER_CCW_CW(_______, _______) },
+};
+"##;
+        let options = FormatOptions {
+            encoder_glyphs: true,
+            ..FormatOptions::default()
+        };
+
+        let result = get_formatted_file_contents(content, vec![vec![K]], &options);
+
+        assert!(result.contains("//    ↺ KC_VOLD ◉ KC_VOLU ↻    ↺ KC_PGDN ◉ KC_PGUP ↻\n[_BASE]"));
+        assert!(result.ends_with(
+            r##"encoder_map[][NUM_ENCODERS][NUM_DIRECTIONS] = {
+[_BASE] = { ENCODER_CCW_CW(KC_VOLD, KC_VOLU), ENCODER_CCW_CW(KC_PGDN, KC_PGUP) },
+[_NAV]  = { ENCODER_CCW_CW(C(KC_Z), C(KC_Y)), ENCODER_CCW_CW(_______, _______) },
+};
+"##
+        ));
+
+        let result = get_formatted_file_contents(content, vec![vec![K]], &FormatOptions::default());
+        assert!(!result.contains('◉'));
+    }
+
+    #[test]
+    fn test_combos() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_J, KC_K, KC_L),
+};
+const uint16_t PROGMEM combo_jk[] = {KC_J,KC_K, COMBO_END};
+const uint16_t PROGMEM combo_jkl[]={KC_J, KC_K,KC_L, COMBO_END};
+combo_t key_combos[] = {
+  COMBO(combo_jk,KC_ESC),
+  COMBO(combo_jkl,KC_TAB),
+    COMBO_ACTION(combo_jkl)
+};
+"##;
+        let options = FormatOptions {
+            combo_markers: true,
+            ..FormatOptions::default()
+        };
+
+        let result = get_formatted_file_contents(content, vec![vec![K, K, K]], &options);
+
+        assert!(result.contains(
+            "//    combo_jk → KC_ESC: KC_J (L row 1 1) + KC_K (L row 1 2)\n//    combo_jkl → KC_TAB: KC_J (L row 1 1) + KC_K (L row 1 2) + KC_L (R row 1 1)\n//    combo_jkl: KC_J"
+        ));
+        assert!(result.ends_with(
+            r##"
+const uint16_t PROGMEM combo_jk[]  = {KC_J, KC_K, COMBO_END};
+const uint16_t PROGMEM combo_jkl[] = {KC_J, KC_K, KC_L, COMBO_END};
+combo_t key_combos[] = {
+  COMBO(combo_jk,  KC_ESC),
+  COMBO(combo_jkl, KC_TAB),
+  COMBO_ACTION(combo_jkl)
+};
+"##
+        ));
+    }
+
+    #[test]
+    fn test_tap_dances() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(TD(TD_ESC_CAPS), TD(TD_NAV)),
+};
+tap_dance_action_t tap_dance_actions[] = {
+  [TD_ESC_CAPS]=ACTION_TAP_DANCE_DOUBLE(KC_ESC,KC_CAPS),
+    [TD_NAV] = ACTION_TAP_DANCE_LAYER_MOVE(KC_TAB, _NAV),
+};
+"##;
+        let options = FormatOptions {
+            tap_dance_labels: true,
+            ..FormatOptions::default()
+        };
+
+        let result = get_formatted_file_contents(content, vec![vec![K, K]], &options);
+
+        assert!(result.contains("│ KC_ESC/KC_CAPS  │ KC_TAB/TO(_NAV) │"));
+        assert!(result.ends_with(
+            r##"tap_dance_action_t tap_dance_actions[] = {
+  [TD_ESC_CAPS] = ACTION_TAP_DANCE_DOUBLE(KC_ESC, KC_CAPS),
+  [TD_NAV]      = ACTION_TAP_DANCE_LAYER_MOVE(KC_TAB, _NAV),
+};
+"##
+        ));
+    }
+
+    #[test]
+    fn test_initializer_tables() {
+        let content = r##"enum layers { _BASE, _NAV };
+enum custom_keycodes {
+  MY_MACRO = SAFE_RANGE,
+    OTHER,
+  LAST_ONE = (1 << 3)
+};
+const key_override_t delete_key_override = ko_make_basic(MOD_MASK_SHIFT,KC_BSPC, KC_DEL);
+const key_override_t vol_override = ko_make_with_layers(MOD_MASK_CTRL, KC_VOLU, KC_MNXT, 1);
+const key_override_t tab_override = ko_make_basic(MOD_MASK_CTRL, KC_TAB,KC_ESC);
+const key_override_t **key_overrides = (const key_override_t *[]){
+    &delete_key_override, &vol_override,
+    NULL
+};
+"##;
+
+        let result = get_formatted_file_contents(content, vec![vec![K]], &FormatOptions::default());
+
+        assert_eq!(
+            r##"enum layers { _BASE, _NAV };
+enum custom_keycodes {
+  MY_MACRO = SAFE_RANGE,
+  OTHER,
+  LAST_ONE = (1 << 3)
+};
+const key_override_t delete_key_override = ko_make_basic(MOD_MASK_SHIFT, KC_BSPC, KC_DEL);
+const key_override_t vol_override        = ko_make_with_layers(MOD_MASK_CTRL, KC_VOLU, KC_MNXT, 1);
+const key_override_t tab_override        = ko_make_basic(MOD_MASK_CTRL,  KC_TAB,  KC_ESC);
+const key_override_t **key_overrides = (const key_override_t *[]){
+    &delete_key_override,
+    &vol_override,
+    NULL
+};
+"##,
+            result
+        );
+    }
+
+    #[test]
+    fn test_multiple_keymap_arrays() {
+        let content = r##"const uint16_t keymaps[][MATRIX_ROWS][MATRIX_COLS] PROGMEM = {
+[0] = LAYOUT(KC_A),
+};
+uint16_t const PROGMEM keymaps_alt[2][MATRIX_ROWS][MATRIX_COLS] = {
+[0] = LAYOUT(KC_B),
+};
+"##;
+
+        let result = get_formatted_file_contents(content, vec![vec![K]], &FormatOptions::default());
+
+        assert!(result.starts_with(
+            "const uint16_t keymaps[][MATRIX_ROWS][MATRIX_COLS] PROGMEM = {\n//    ╭"
+        ));
+        assert!(result.contains(
+            "uint16_t const PROGMEM keymaps_alt[2][MATRIX_ROWS][MATRIX_COLS] = {\n//    ╭"
+        ));
+        assert!(result.contains("//    │ KC_A │") && result.contains("//    │ KC_B │"));
+    }
+
+    #[test]
+    fn test_broken_layers_are_left_alone() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A,    KC_B),
+[_NAV] = LAYOUT(KC_C  KC_D  ),
+[_SYM] = LAYOUT(KC_E,KC_F),
+};
+"##;
+
+        let result =
+            get_formatted_file_contents(content, vec![vec![K, K]], &FormatOptions::default());
+
+        assert!(result.contains(" KC_A , KC_B"));
+        assert!(result.contains("\n[_NAV] = LAYOUT(KC_C  KC_D  ),\n"));
+        assert!(result.contains(" KC_E , KC_F"));
+    }
+
+    #[test]
+    fn test_broken_layers_keep_their_comments_and_indentation() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+    [_BASE] = LAYOUT(KC_A,    KC_B),
+    // ╭──────┬──────╮
+    // │ KC_C │ KC_D │
+    // ╰──────┴──────╯
+    [_NAV] = LAYOUT(KC_C  KC_D  ),  // broken
+    [_SYM] = LAYOUT(KC_E,KC_F),
+};
+"##;
+
+        let options = FormatOptions {
+            indent: Indent::Inherit,
+            ..FormatOptions::default()
+        };
+        let (result, warnings) = format_file_contents(content, vec![vec![K, K]], &options);
+
+        let expected = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+    //    ╭──────┬──────╮
+    //    │ KC_A │ KC_B │
+    //    ╰──────┴──────╯
+    [_BASE] = LAYOUT(
+     KC_A , KC_B
+    ),
+    // ╭──────┬──────╮
+    // │ KC_C │ KC_D │
+    // ╰──────┴──────╯
+    [_NAV] = LAYOUT(KC_C  KC_D  ),  // broken
+    //    ╭──────┬──────╮
+    //    │ KC_E │ KC_F │
+    //    ╰──────┴──────╯
+    [_SYM] = LAYOUT(
+     KC_E , KC_F
+    ),
+};
+"##;
+        assert_eq!(expected, result);
+        assert_eq!(
+            vec![Diagnostic::new(
+                content.find("[_NAV]").unwrap(),
+                "Missing comma before `KC_D` in layer _NAV".to_string()
+            )],
+            warnings
+        );
+    }
+
+    #[test]
+    fn test_comments_between_layers_are_replaced() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+// Letters
+[_BASE] = LAYOUT(KC_A, KC_B),
+/* Navigation */
+[_NAV] = LAYOUT(KC_C, KC_D),  // arrows
+};
+"##;
+
+        let result =
+            get_formatted_file_contents(content, vec![vec![K, K]], &FormatOptions::default());
+
+        assert!(!result.contains("// Letters"));
+        assert!(!result.contains("/* Navigation */"));
+        assert!(!result.contains("// arrows"));
+        assert!(result.contains(" KC_C , KC_D"));
+    }
+
+    #[test]
+    fn test_unformattable_layers_are_left_alone() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A,    KC_B),
+[_NAV] = LAYOUT(KC_C,  KC_D,  KC_E),
+[_SYM] = LAYOUT(KC_F,
+#ifdef FOO
+  KC_G
+#endif
+),
+[_FN] = LAYOUT(KC_I,KC_J),
+#ifdef BAR
+[_ADJ] = LAYOUT(KC_K,KC_L),
+};
+"##;
+
+        let result =
+            get_formatted_file_contents(content, vec![vec![K, K]], &FormatOptions::default());
+
+        assert!(result.contains(" KC_A , KC_B"));
+        assert!(result.contains("\n[_NAV] = LAYOUT(KC_C,  KC_D,  KC_E),\n"));
+        assert!(result.contains("\n[_SYM] = LAYOUT(KC_F,\n#ifdef FOO\n  KC_G\n#endif\n),\n"));
+        assert!(result.contains(" KC_I , KC_J"));
+        assert!(result.contains("\n#ifdef BAR\n[_ADJ] = LAYOUT(KC_K,KC_L),\n};"));
+    }
+
+    #[test]
+    fn test_bom_and_crlf_are_kept() {
+        let content = "\u{feff}// ╭ résumé ╮\r\nconst uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {\r\n[0] = LAYOUT(KC_A,  KC_B),\r\n};\r\n";
+
+        let result =
+            get_formatted_file_contents(content, vec![vec![K, K]], &FormatOptions::default());
+
+        assert!(result.starts_with("\u{feff}// ╭ résumé ╮\r\nconst uint16_t"));
+        assert!(result.contains("[0] = LAYOUT(\r\n"));
+        assert!(result.contains(" KC_A , KC_B"));
+        assert!(!result.replace("\r\n", "").contains('\n'));
+    }
+
+    #[test]
+    fn test_line_ending_override_and_final_newline() {
+        let content = "// top\r\nconst uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {\r\n[0] = LAYOUT(KC_A, KC_B),\r\n}";
+
+        let result =
+            get_formatted_file_contents(content, vec![vec![K, K]], &FormatOptions::default());
+        assert!(result.ends_with("),\r\n}"));
+
+        let options = FormatOptions {
+            line_ending: Some(LineEnding::Lf),
+            ..FormatOptions::default()
+        };
+        let result = get_formatted_file_contents(content, vec![vec![K, K]], &options);
+        assert!(result.starts_with("// top\nconst uint16_t"));
+        assert!(result.ends_with("),\n}"));
+        assert!(!result.contains('\r'));
+
+        let options = FormatOptions {
+            line_ending: Some(LineEnding::Crlf),
+            ..FormatOptions::default()
+        };
+        let result =
+            get_formatted_file_contents(&format!("{}\n", content), vec![vec![K, K]], &options);
+        assert!(result.ends_with("),\r\n}\r\n"));
+        assert!(!result.replace("\r\n", "").contains('\n'));
+    }
+
+    #[test]
+    fn test_diagram_position_and_code_alignment() {
+        let content = "const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {\n[0] = LAYOUT(KC_A, KC_B, KC_ENTER),\n};\n";
+        let layout = vec![vec![K, B, K], vec![B, K, B]];
+        let format = |diagram_position, code_alignment| {
+            let options = FormatOptions {
+                diagram_position,
+                code_alignment,
+                ..FormatOptions::default()
+            };
+            get_formatted_file_contents(content, layout.clone(), &options)
+        };
+
+        let body = "[0] = LAYOUT(\n KC_A     ,            KC_B     ,\n            KC_ENTER\n),\n";
+        let diagram = "//    ╭──────────╮          ╭──────────╮\n//    │ KC_A     │          │ KC_B     │\n//    ╰──────────┼──────────┼──────────╯\n//               │ KC_ENTER │\n//               ╰──────────╯\n";
+
+        let result = format(DiagramPosition::Above, CodeAlignment::Grid);
+        assert!(result.contains(&format!("{{\n{}{}}};", diagram, body)));
+
+        let result = format(DiagramPosition::Below, CodeAlignment::Grid);
+        assert!(result.contains(&format!("{{\n{}{}}};", body, diagram)));
+
+        let result = format(DiagramPosition::None, CodeAlignment::Grid);
+        assert!(result.contains(&format!("{{\n{}}};", body)));
+
+        let result = format(DiagramPosition::BlockComment, CodeAlignment::Grid);
+        assert!(result.contains(&format!(
+            "{{\n{}/*\n *    ╭──────────╮          ╭──────────╮\n",
+            body
+        )));
+        assert!(result.contains(" *               ╰──────────╯\n */\n};"));
+
+        let result = format(DiagramPosition::None, CodeAlignment::Compact);
+        assert!(result.contains("{\n[0] = LAYOUT(\nKC_A, KC_B,\nKC_ENTER\n),\n};"));
+    }
+
+    #[test]
+    fn test_indent_and_diagram_prefix() {
+        let content = "const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {\n  [0] = LAYOUT(KC_A),\n};\n";
+        let format = |indent, diagram_prefix: Option<&str>| {
+            let options = FormatOptions {
+                indent,
+                diagram_prefix: diagram_prefix.map(|x| x.to_string()),
+                ..FormatOptions::default()
+            };
+            get_formatted_file_contents(content, vec![vec![K]], &options)
+        };
+
+        let result = format(Indent::Spaces(4), None);
+        assert!(result.contains("{\n    //    ╭──────╮\n    //    │ KC_A │\n    //    ╰──────╯\n    [0] = LAYOUT(\n     KC_A\n    ),\n};"));
+
+        let result = format(Indent::Tab, Some("// "));
+        assert!(result.contains(
+            "{\n\t// ╭──────╮\n\t// │ KC_A │\n\t// ╰──────╯\n\t[0] = LAYOUT(\n\t KC_A\n\t),\n};"
+        ));
+
+        let result = format(Indent::Inherit, None);
+        assert!(result.contains("{\n  //    ╭──────╮\n"));
+        assert!(result.contains("\n  [0] = LAYOUT(\n   KC_A\n  ),\n};"));
+
+        assert_eq!(Ok(Indent::Tab), "tab".parse());
+        assert_eq!(Ok(Indent::Spaces(2)), "2".parse());
+        assert!("two".parse::<Indent>().is_err());
+    }
+
+    #[test]
+    fn test_blank_key_spelling() {
+        let content = "const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {\n[0] = LAYOUT(________, KC_TRNS, XXXXXXX, KC_NO),\n};\n";
+        let format = |transparent, no_op| {
+            let options = FormatOptions {
+                transparent,
+                no_op,
+                ..FormatOptions::default()
+            };
+            get_formatted_file_contents(content, vec![vec![K, K, K, K]], &options)
+        };
+
+        let result = format(None, None);
+        assert!(result.contains("//    │          │          │ ✕        │ ✕        │\n"));
+        assert!(result.contains(" ________ , KC_TRNS  , XXXXXXX  , KC_NO\n"));
+
+        let result = format(
+            Some(TransparentSpelling::Underscores),
+            Some(NoOpSpelling::KcNo),
+        );
+        assert!(result.contains(" _______ , _______ , KC_NO   , KC_NO\n"));
+
+        let result = format(
+            Some(TransparentSpelling::KcTrns),
+            Some(NoOpSpelling::Crosses),
+        );
+        assert!(result.contains(" KC_TRNS , KC_TRNS , XXXXXXX , XXXXXXX\n"));
+    }
 }
@@ -1,12 +1,61 @@
+use std::collections::{HashMap, HashSet};
+
 use crate::{
-    ast::{KeymapStatement, LayoutStatement, StatementEnum, AST},
+    ast::{
+        Alias, ComboKeysStatement, ComboStatement, Conditional, ConditionalBranch, Encoder,
+        EncoderMapStatement, EnumMember, EnumStatement, KeyOverrideStatement, KeymapNode,
+        KeymapStatement, LayoutStatement, ParseError, StatementEnum, TapDanceStatement, AST,
+    },
     lexer::{Lexer, TokenType},
+    preprocessor,
 };
 
+/// The parts of an array declaration such as `const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] =`
+struct Declaration {
+    /// What comes before the name, such as `const`, `uint16_t` and `PROGMEM`
+    specifiers: Vec<String>,
+    name: TokenType,
+    /// What's between the square brackets of each dimension, empty when the size is left out
+    dimensions: Vec<String>,
+    /// Whether `PROGMEM` comes after the dimensions instead of before the name
+    trailing_progmem: bool,
+}
+
+impl Declaration {
+    /// The name and dimensions as written, e.g. `combo_jk[] PROGMEM`
+    fn get_declarator(&self) -> String {
+        let mut declarator = self.name.to_string();
+        for dimension in &self.dimensions {
+            declarator += &format!("[{}]", dimension);
+        }
+        if self.trailing_progmem {
+            declarator += " PROGMEM";
+        }
+
+        declarator
+    }
+}
+
+/// The keys of a layer with the byte offset of each, and any directives between them
+type LayoutKeys = (Vec<String>, Vec<usize>, Vec<TokenType>);
+
+/// The text of a token that can come before the name in a declaration
+fn get_specifier(token: &TokenType) -> Option<String> {
+    match token {
+        TokenType::Const(..) => Some("const".to_string()),
+        TokenType::Progmem(..) => Some("PROGMEM".to_string()),
+        TokenType::Ident(_, x) => Some(x.to_string()),
+        _ => None,
+    }
+}
+
 pub struct Parser {
     lexer: Lexer,
     curr_token: TokenType,
     next_token: TokenType,
+    defines: Option<HashSet<String>>,
+    aliases: HashMap<String, Alias>,
+    errors: Vec<ParseError>,
 }
 
 impl Parser {
@@ -14,16 +63,42 @@ impl Parser {
         let curr_token = lexer.next_token();
         let next_token = lexer.next_token();
 
-        Self {
+        let mut parser = Self {
             lexer,
             curr_token,
             next_token,
-        }
+            defines: None,
+            aliases: HashMap::new(),
+            errors: vec![],
+        };
+        parser.record_alias();
+
+        parser
+    }
+
+    /// Evaluates conditional directives with these symbols defined
+    pub fn with_defines(mut self, defines: HashSet<String>) -> Self {
+        self.defines = Some(defines);
+        self
     }
 
     fn next_token(&mut self) {
         self.curr_token = self.next_token.clone();
         self.next_token = self.lexer.next_token();
+        self.record_alias();
+    }
+
+    /// Adds the current token to the symbol table if it's an object-like `#define`
+    fn record_alias(&mut self) {
+        let TokenType::Directive(_, _, directive) = &self.curr_token else {
+            return;
+        };
+
+        if let Some((name, value, label)) = preprocessor::get_object_define(directive) {
+            self.aliases
+                .entry(name.to_string())
+                .or_insert(Alias { value, label });
+        }
     }
 
     pub fn parse(&mut self) -> AST {
@@ -36,25 +111,61 @@ impl Parser {
             self.next_token();
         }
 
+        ast.aliases = std::mem::take(&mut self.aliases);
+        ast.errors = std::mem::take(&mut self.errors);
+
+        let symbols = ast.layer_symbols();
+        for statement in ast.statements.iter_mut() {
+            match statement {
+                StatementEnum::KeymapStatement(x) => resolve_layer_index(x, &symbols),
+                StatementEnum::Keymaps(_, _, nodes) => resolve_layer_indices(nodes, &symbols),
+                StatementEnum::EncoderMap(_, _, entries) => {
+                    for entry in entries.iter_mut() {
+                        entry.index = evaluate_layer_index(&entry.token, &symbols);
+                    }
+                }
+                StatementEnum::Enum(..)
+                | StatementEnum::ComboKeys(..)
+                | StatementEnum::Combos(..)
+                | StatementEnum::TapDances(..)
+                | StatementEnum::KeyOverride(..)
+                | StatementEnum::KeyOverrides(..) => {}
+            }
+        }
+
         ast
     }
 
     fn parse_statement(&mut self) -> Option<StatementEnum> {
-        match self.curr_token {
+        match &self.curr_token {
             TokenType::LParen(..) => None,
             TokenType::RParen(..) => None,
-            TokenType::LSqBrace(..) => self.parse_keymap_statement(),
+            TokenType::LSqBrace(..) => self
+                .parse_keymap_statement()
+                .ok()
+                .map(StatementEnum::KeymapStatement),
             TokenType::RSqBrace(..) => None,
             TokenType::Equals(..) => None,
             TokenType::Comma(..) => None,
             TokenType::Layout(..) => None,
             TokenType::Blank(..) => None,
+            TokenType::Ident(_, x) if x == "enum" => self.parse_enum(),
+            TokenType::Ident(_, x) if x == "combo_t" => self.parse_combos(),
+            TokenType::Ident(_, x) if x == "tap_dance_action_t" || x == "qk_tap_dance_action_t" => {
+                self.parse_tap_dances()
+            }
+            TokenType::Ident(_, x) if x == "uint16_t" || x == "static" => self.parse_assignment(),
             TokenType::Ident(..) => None,
             TokenType::Comment(..) => None,
+            TokenType::Directive(..) => None,
             TokenType::Const(..) => self.parse_assignment(),
-            TokenType::Progmem(_) => None,
+            TokenType::Progmem(_) => self.parse_assignment(),
             TokenType::LBrace(_) => None,
             TokenType::RBrace(_) => None,
+            TokenType::Number(..)
+            | TokenType::Punctuator(..)
+            | TokenType::String(..)
+            | TokenType::Char(..) => None,
             TokenType::EOF => None,
         }
     }
@@ -73,95 +184,651 @@ impl Parser {
     }
 
     fn parse_assignment(&mut self) -> Option<StatementEnum> {
-        let expected = [
-            TokenType::Ident(0, "uint16_t".to_string()),
-            TokenType::Progmem(0),
-            TokenType::Ident(0, "keymaps".to_string()),
-            TokenType::LSqBrace(0),
-            TokenType::RSqBrace(0),
-            TokenType::LSqBrace(0),
-            TokenType::Ident(0, "MATRIX_ROWS".to_string()),
-            TokenType::RSqBrace(0),
-            TokenType::LSqBrace(0),
-            TokenType::Ident(0, "MATRIX_COLS".to_string()),
-            TokenType::RSqBrace(0),
-            TokenType::Equals(0),
-        ];
+        if let TokenType::Const(..) = self.curr_token {
+            if self.expect_peek(TokenType::Ident(0, "key_override_t".to_string())) {
+                return self.parse_key_override();
+            }
+        }
 
-        for e in expected {
+        let declaration = self.parse_declaration()?;
+        if !declaration.specifiers.iter().any(|x| x == "uint16_t") {
+            return None;
+        }
+
+        match (
+            declaration.name.to_string().as_str(),
+            declaration.dimensions.len(),
+        ) {
+            ("encoder_map", 3) => self.parse_encoder_map(),
+            (_, 3) => self.parse_keymaps(),
+            (_, 1) => self.parse_combo_keys(declaration),
+            _ => None,
+        }
+    }
+
+    /// Parses an array declaration up to its `=`, accepting the specifiers and `PROGMEM` in
+    /// any order and with or without sizes, e.g. `uint16_t const keymaps[4][MATRIX_ROWS][MATRIX_COLS] PROGMEM =`
+    fn parse_declaration(&mut self) -> Option<Declaration> {
+        let mut specifiers: Vec<String> = vec![get_specifier(&self.curr_token)?];
+        while let Some(specifier) = get_specifier(&self.next_token) {
+            self.next_token();
+            specifiers.push(specifier);
+        }
+
+        let TokenType::Ident(..) = self.curr_token else {
+            return None;
+        };
+        let name = self.curr_token.clone();
+        specifiers.pop();
+
+        let mut dimensions: Vec<String> = vec![];
+        while self.expect_peek(TokenType::LSqBrace(0)) {
+            let dimension = match self.next_token {
+                TokenType::RSqBrace(..) => {
+                    self.next_token();
+                    String::new()
+                }
+                _ => self.parse_layer_index()?.to_string(),
+            };
+            dimensions.push(dimension);
+        }
+
+        if dimensions.is_empty() {
+            return None;
+        }
+
+        let trailing_progmem = self.expect_peek(TokenType::Progmem(0));
+        if !self.expect_peek(TokenType::Equals(0)) {
+            return None;
+        }
+
+        Some(Declaration {
+            specifiers,
+            name,
+            dimensions,
+            trailing_progmem,
+        })
+    }
+
+    fn parse_keymaps(&mut self) -> Option<StatementEnum> {
+        let start: usize = match self.next_token {
+            TokenType::LBrace(..) => self.next_token.end()?,
+            _ => return None,
+        };
+
+        self.next_token();
+        let keymaps = self.parse_keymap_nodes();
+
+        // An array that never closes isn't formatted, but its broken layers are still reported
+        let end: usize = match self.next_token {
+            TokenType::RBrace(x) => x,
+            _ => {
+                take_errors(keymaps, &mut self.errors);
+                return None;
+            }
+        };
+
+        Some(StatementEnum::Keymaps(start, end, keymaps))
+    }
+
+    fn parse_encoder_map(&mut self) -> Option<StatementEnum> {
+        let start: usize = match self.next_token {
+            TokenType::LBrace(..) => self.next_token.end()?,
+            _ => return None,
+        };
+        self.next_token();
+
+        // Directives between entries aren't supported, so the whole map is left as it is
+        let mut entries: Vec<EncoderMapStatement> = vec![];
+        loop {
+            match self.next_token {
+                TokenType::RBrace(..) => break,
+                TokenType::EOF | TokenType::Directive(..) => return None,
+                TokenType::LSqBrace(..) => {
+                    self.next_token();
+                    entries.push(self.parse_encoder_map_statement()?);
+                }
+                _ => self.next_token(),
+            }
+        }
+
+        let end: usize = match self.next_token {
+            TokenType::RBrace(x) => x,
+            _ => return None,
+        };
+
+        Some(StatementEnum::EncoderMap(start, end, entries))
+    }
+
+    fn parse_encoder_map_statement(&mut self) -> Option<EncoderMapStatement> {
+        let token = self.parse_layer_index()?;
+
+        for e in [TokenType::Equals(0), TokenType::LBrace(0)] {
             if !self.expect_peek(e) {
                 return None;
             }
         }
 
-        let start: usize;
-        match self.next_token {
-            TokenType::LBrace(x) => start = x,
+        let mut encoders: Vec<Encoder> = vec![];
+        loop {
+            self.next_token();
+            match &self.curr_token {
+                TokenType::RBrace(..) => break,
+                TokenType::Comma(..) => {}
+                TokenType::Ident(position, x) if x == "ENCODER_CCW_CW" => {
+                    let position = *position;
+                    if !self.expect_peek(TokenType::LParen(0)) {
+                        return None;
+                    }
+
+                    let [ccw, cw]: [String; 2] = self.parse_arguments()?.try_into().ok()?;
+                    encoders.push(Encoder { position, ccw, cw });
+                }
+                _ => return None,
+            }
+        }
+
+        Some(EncoderMapStatement {
+            token,
+            encoders,
+            index: None,
+        })
+    }
+
+    /// Parses a key list ending in `COMBO_END`, leaving any other array alone
+    fn parse_combo_keys(&mut self, declaration: Declaration) -> Option<StatementEnum> {
+        let start = match declaration.name {
+            TokenType::Ident(x, _) => x,
             _ => return None,
+        };
+        if !self.expect_peek(TokenType::LBrace(0)) {
+            return None;
         }
 
-        let mut keymaps: Vec<KeymapStatement> = vec![];
-        while match self.next_token {
-            TokenType::RBrace(..) => false,
-            _ => true,
-        } {
+        let mut keys: Vec<String> = vec![];
+        let mut positions: Vec<usize> = vec![];
+        loop {
             self.next_token();
+            match &self.curr_token {
+                TokenType::RBrace(..) => break,
+                TokenType::Comma(..) => {}
+                TokenType::Ident(position, x) => {
+                    positions.push(*position);
+                    keys.push(x.to_string());
+                    if let TokenType::LParen(..) = self.next_token {
+                        self.next_token();
+                        let args = self.parse_arguments()?;
+                        *keys.last_mut()? += &format!("({})", args.join(","));
+                    }
+                }
+                _ => return None,
+            }
+        }
 
-            match self.next_token {
+        let TokenType::RBrace(..) = self.curr_token else {
+            return None;
+        };
+        let end = self.curr_token.end()?;
+        if keys.last().map(|x| x.as_str()) != Some("COMBO_END") {
+            return None;
+        }
+
+        Some(StatementEnum::ComboKeys(
+            start,
+            end,
+            ComboKeysStatement {
+                declarator: declaration.get_declarator(),
+                token: declaration.name,
+                keys,
+                positions,
+            },
+        ))
+    }
+
+    fn parse_combos(&mut self) -> Option<StatementEnum> {
+        if !self.expect_peek(TokenType::Ident(0, "key_combos".to_string())) {
+            return None;
+        }
+
+        let (start, end, combos) =
+            self.parse_call_table(|index, token, args| ComboStatement { index, token, args })?;
+
+        Some(StatementEnum::Combos(start, end, combos))
+    }
+
+    fn parse_tap_dances(&mut self) -> Option<StatementEnum> {
+        if !self.expect_peek(TokenType::Ident(0, "tap_dance_actions".to_string())) {
+            return None;
+        }
+
+        let (start, end, dances) =
+            self.parse_call_table(|index, token, args| TapDanceStatement { index, token, args })?;
+
+        Some(StatementEnum::TapDances(start, end, dances))
+    }
+
+    /// Parses either a key override definition, e.g.
+    /// `const key_override_t delete_key_override = ko_make_basic(MOD_MASK_SHIFT, KC_BSPC, KC_DEL);`,
+    /// or the `key_overrides` array listing them
+    fn parse_key_override(&mut self) -> Option<StatementEnum> {
+        let start = match &self.next_token {
+            TokenType::Punctuator(_, x) if x == "*" => return self.parse_key_overrides(),
+            TokenType::Ident(x, _) => *x,
+            _ => return None,
+        };
+        self.next_token();
+        let token = self.curr_token.clone();
+
+        if !self.expect_peek(TokenType::Equals(0)) {
+            return None;
+        }
+        let TokenType::Ident(..) = self.next_token else {
+            return None;
+        };
+        self.next_token();
+        let call = self.curr_token.clone();
+        if !self.expect_peek(TokenType::LParen(0)) {
+            return None;
+        }
+
+        let args = self.parse_arguments()?;
+        let TokenType::RParen(..) = self.curr_token else {
+            return None;
+        };
+        let end = self.curr_token.end()?;
+
+        Some(StatementEnum::KeyOverride(
+            start,
+            end,
+            KeyOverrideStatement { token, call, args },
+        ))
+    }
+
+    /// Parses `*key_overrides[] = { ... }` or the compound literal form
+    /// `**key_overrides = (const key_override_t *[]){ ... }`
+    fn parse_key_overrides(&mut self) -> Option<StatementEnum> {
+        while !matches!(self.next_token, TokenType::Equals(..) | TokenType::EOF) {
+            self.next_token();
+            if let TokenType::Ident(_, x) = &self.curr_token {
+                if x != "key_overrides" {
+                    return None;
+                }
+            }
+        }
+        while !matches!(
+            self.next_token,
+            TokenType::LBrace(..) | TokenType::Comma(..) | TokenType::EOF
+        ) {
+            self.next_token();
+        }
+
+        let start: usize = match self.next_token {
+            TokenType::LBrace(..) => self.next_token.end()?,
+            _ => return None,
+        };
+        self.next_token();
+
+        // Comments and directives between entries aren't supported, so the array is left as it is
+        let mut entries: Vec<String> = vec![];
+        let mut entry = String::new();
+        loop {
+            self.next_token();
+            match &self.curr_token {
+                TokenType::RBrace(..) | TokenType::Comma(..) => {
+                    if !entry.is_empty() {
+                        entries.push(std::mem::take(&mut entry));
+                    }
+                    if let TokenType::RBrace(..) = self.curr_token {
+                        break;
+                    }
+                }
+                TokenType::Ident(_, x) | TokenType::Punctuator(_, x) => entry += x,
+                _ => return None,
+            }
+        }
+
+        let TokenType::RBrace(end) = self.curr_token else {
+            return None;
+        };
+
+        Some(StatementEnum::KeyOverrides(start, end, entries))
+    }
+
+    /// Parses an array of macro calls, each with an optional designated index, e.g.
+    /// `name[SIZE] = { [A] = CALL(x, y), CALL(z) }`, from its name onwards
+    fn parse_call_table<T>(
+        &mut self,
+        entry: impl Fn(Option<TokenType>, TokenType, Vec<String>) -> T,
+    ) -> Option<(usize, usize, Vec<T>)> {
+        // Skip the size, e.g. `key_combos[COMBO_COUNT]`
+        while !matches!(
+            self.next_token,
+            TokenType::Equals(..) | TokenType::Comma(..) | TokenType::EOF
+        ) {
+            self.next_token();
+        }
+        if !self.expect_peek(TokenType::Equals(0)) {
+            return None;
+        }
+
+        let start: usize = match self.next_token {
+            TokenType::LBrace(..) => self.next_token.end()?,
+            _ => return None,
+        };
+        self.next_token();
+
+        // Directives between entries aren't supported, so the whole array is left as it is
+        let mut entries: Vec<T> = vec![];
+        let mut index: Option<TokenType> = None;
+        loop {
+            self.next_token();
+            match &self.curr_token {
+                TokenType::RBrace(..) => break,
+                TokenType::Comma(..) => {}
                 TokenType::LSqBrace(..) => {
+                    index = Some(self.parse_layer_index()?);
+                    if !self.expect_peek(TokenType::Equals(0)) {
+                        return None;
+                    }
+                }
+                TokenType::Ident(..) => {
+                    let token = self.curr_token.clone();
+                    if !self.expect_peek(TokenType::LParen(0)) {
+                        return None;
+                    }
+
+                    let args = self.parse_arguments()?;
+                    entries.push(entry(index.take(), token, args));
+                }
+                _ => return None,
+            }
+        }
+
+        let TokenType::RBrace(end) = self.curr_token else {
+            return None;
+        };
+
+        Some((start, end, entries))
+    }
+
+    /// Reads the comma separated arguments of a call, leaving the current token on its `)`
+    fn parse_arguments(&mut self) -> Option<Vec<String>> {
+        let mut args: Vec<String> = vec![String::new()];
+        let mut depth = 0;
+
+        loop {
+            self.next_token();
+            let text = self.curr_token.to_string();
+            match self.curr_token {
+                TokenType::RParen(..) if depth == 0 => break,
+                TokenType::Comma(..) if depth == 0 => {
+                    args.push(String::new());
+                    continue;
+                }
+                TokenType::LParen(..) => depth += 1,
+                TokenType::RParen(..) => depth -= 1,
+                TokenType::EOF => return None,
+                _ => {}
+            }
+
+            args.last_mut()?.push_str(&text);
+        }
+
+        Some(args)
+    }
+
+    /// Parses layers and directives up to the closing brace or the directive that ends
+    /// the current conditional branch
+    fn parse_keymap_nodes(&mut self) -> Vec<KeymapNode> {
+        let mut nodes: Vec<KeymapNode> = vec![];
+
+        loop {
+            match &self.next_token {
+                TokenType::RBrace(..) | TokenType::EOF => break,
+                TokenType::Directive(_, _, x) => {
+                    if preprocessor::is_conditional_branch(x) || preprocessor::is_conditional_end(x)
+                    {
+                        break;
+                    }
+
+                    self.next_token();
+                    let node =
+                        match preprocessor::is_conditional_start(&self.curr_token.to_string()) {
+                            true => KeymapNode::Conditional(self.parse_conditional()),
+                            false => KeymapNode::Directive(self.curr_token.clone()),
+                        };
+                    nodes.push(node);
+                }
+                TokenType::LSqBrace(start) => {
+                    let start = *start;
                     self.next_token();
                     match self.parse_keymap_statement() {
-                        Some(x) => match x {
-                            StatementEnum::KeymapStatement(x) => keymaps.push(x),
-                            StatementEnum::Keymaps(..) => {}
-                        },
-                        None => {}
+                        Ok(x) => nodes.push(KeymapNode::Keymap(x)),
+                        Err(message) => {
+                            let end = self.skip_broken_layer();
+                            nodes.push(KeymapNode::Error(ParseError {
+                                start,
+                                end,
+                                message,
+                            }));
+                        }
                     }
                 }
-                _ => {}
+                _ => self.next_token(),
             }
         }
 
-        let end: usize;
-        match self.next_token {
-            TokenType::RBrace(x) => end = x,
-            _ => return None,
+        nodes
+    }
+
+    /// Skips to the end of a layer that couldn't be parsed, which is the `),` closing it or
+    /// the last token before whatever comes next, returning the byte offset just past the
+    /// layer
+    fn skip_broken_layer(&mut self) -> usize {
+        loop {
+            match (&self.curr_token, &self.next_token) {
+                (TokenType::RParen(..), x @ TokenType::Comma(..)) => {
+                    let end = x.end().unwrap_or_default();
+                    self.next_token();
+                    return end;
+                }
+                (
+                    x,
+                    TokenType::LSqBrace(next)
+                    | TokenType::RBrace(next)
+                    | TokenType::Directive(next, ..),
+                ) => {
+                    return x.end().unwrap_or(*next);
+                }
+                (_, TokenType::EOF) => return self.lexer.offset(),
+                _ => self.next_token(),
+            }
         }
+    }
+
+    fn parse_conditional(&mut self) -> Conditional {
+        let mut branches: Vec<ConditionalBranch> = vec![];
+        let mut directive = self.curr_token.clone();
+        let mut taken = false;
+
+        loop {
+            let active = self.defines.as_ref().map(|defines| {
+                let active =
+                    !taken && preprocessor::is_branch_active(&directive.to_string(), defines);
+                taken |= active;
+                active
+            });
 
-        return Some(StatementEnum::Keymaps(start, end, keymaps));
+            let nodes = self.parse_keymap_nodes();
+            branches.push(ConditionalBranch {
+                directive,
+                nodes,
+                active,
+            });
+
+            let TokenType::Directive(_, _, x) = &self.next_token else {
+                return Conditional {
+                    branches,
+                    end: None,
+                };
+            };
+
+            let is_end = preprocessor::is_conditional_end(x);
+            self.next_token();
+            if is_end {
+                return Conditional {
+                    branches,
+                    end: Some(self.curr_token.clone()),
+                };
+            }
+
+            directive = self.curr_token.clone();
+        }
     }
 
-    fn parse_keymap_statement(&mut self) -> Option<StatementEnum> {
-        match self.next_token {
-            TokenType::Ident(..) => self.next_token(),
-            _ => return None,
+    fn parse_enum(&mut self) -> Option<StatementEnum> {
+        let token = match &self.next_token {
+            TokenType::Ident(..) => {
+                self.next_token();
+                Some(self.curr_token.clone())
+            }
+            _ => None,
+        };
+
+        // Skip over an underlying type such as `enum layers : uint8_t {`
+        while !matches!(
+            self.next_token,
+            TokenType::LBrace(..) | TokenType::Comma(..) | TokenType::EOF
+        ) {
+            self.next_token();
         }
+        if !self.expect_peek(TokenType::LBrace(0)) {
+            return None;
+        }
+        let TokenType::LBrace(..) = self.curr_token else {
+            return None;
+        };
+        let start = self.curr_token.end()?;
 
-        let token: TokenType;
-        match self.next_token {
-            TokenType::RSqBrace(..) => {
-                token = self.curr_token.clone();
+        let mut members: Vec<EnumMember> = vec![];
+        let mut symbols: HashMap<String, i64> = self
+            .aliases
+            .iter()
+            .filter_map(|(name, alias)| Some((name.to_string(), evaluate_constant(&alias.value)?)))
+            .collect();
+        let mut next_value: Option<i64> = Some(0);
+
+        while !matches!(self.next_token, TokenType::RBrace(..) | TokenType::EOF) {
+            self.next_token();
+            let TokenType::Ident(..) = self.curr_token else {
+                continue;
+            };
+
+            let token = self.curr_token.clone();
+            let mut value = next_value;
+            let mut expression_span: Option<(usize, usize)> = None;
+            if let TokenType::Equals(equals) = self.next_token {
+                self.next_token();
+
+                let mut expression = String::new();
+                while !matches!(
+                    self.next_token,
+                    TokenType::Comma(..) | TokenType::RBrace(..) | TokenType::EOF
+                ) {
+                    self.next_token();
+                    expression += &self.curr_token.to_string();
+                    expression += " ";
+                }
+                value = evaluate_index(&expression, &symbols);
+                expression_span = match self.next_token {
+                    TokenType::Comma(x) | TokenType::RBrace(x) => Some((equals + 1, x)),
+                    _ => None,
+                };
+            }
+
+            if let Some(x) = value {
+                symbols.insert(token.to_string(), x);
+            }
+            next_value = value.map(|x| x + 1);
+            members.push(EnumMember {
+                token,
+                value,
+                expression: expression_span,
+            });
+
+            while !matches!(
+                self.next_token,
+                TokenType::Comma(..) | TokenType::RBrace(..) | TokenType::EOF
+            ) {
                 self.next_token();
             }
-            _ => return None,
         }
 
-        let layout_statement = match self.parse_layout_statement() {
-            Some(x) => x,
-            None => return None,
+        let TokenType::RBrace(end) = self.next_token else {
+            return None;
+        };
+
+        Some(StatementEnum::Enum(
+            start,
+            end,
+            EnumStatement { token, members },
+        ))
+    }
+
+    fn parse_keymap_statement(&mut self) -> Result<KeymapStatement, String> {
+        let start = self.curr_token.start().unwrap_or(0);
+        let token = self
+            .parse_layer_index()
+            .ok_or("Couldn't read the layer index")?;
+        let layout_statement = self
+            .parse_layout_statement()
+            .map_err(|x| format!("{} in layer {}", x, token.to_string()))?;
+
+        let end = match (&self.curr_token, &self.next_token) {
+            (_, x @ TokenType::Comma(..)) | (x, _) => x.end().unwrap_or(start),
         };
 
-        let statement = KeymapStatement::new(token, layout_statement);
-        let statement = StatementEnum::KeymapStatement(statement);
-        Some(statement)
+        Ok(KeymapStatement::new(token, layout_statement).with_span(start, end))
     }
 
-    fn parse_layout_statement(&mut self) -> Option<LayoutStatement> {
+    /// Parses the index between `[` and `]`, which can be a name, a number or a simple
+    /// expression such as `_BASE + 1`, leaving the current token on the `]`
+    fn parse_layer_index(&mut self) -> Option<TokenType> {
+        let position = match self.next_token {
+            TokenType::Ident(x, _) | TokenType::Number(x, _) | TokenType::LParen(x) => x,
+            _ => return None,
+        };
+
+        let mut index = String::new();
+        while !matches!(self.next_token, TokenType::RSqBrace(..)) {
+            let part = match &self.next_token {
+                TokenType::Ident(_, x) | TokenType::Number(_, x) | TokenType::Punctuator(_, x) => {
+                    x.to_string()
+                }
+                TokenType::LParen(..) | TokenType::RParen(..) => self.next_token.to_string(),
+                _ => return None,
+            };
+
+            if !index.is_empty() && !index.ends_with('(') && part != ")" {
+                index.push(' ');
+            }
+            index += &part;
+            self.next_token();
+        }
+        self.next_token();
+
+        Some(TokenType::Ident(position, index))
+    }
+
+    fn parse_layout_statement(&mut self) -> Result<LayoutStatement, String> {
         let expected = [TokenType::Equals(0), TokenType::Layout(0)];
 
         for e in expected {
             if !self.expect_peek(e) {
-                return None;
+                return Err(format!(
+                    "Expected `= LAYOUT(` but found `{}`",
+                    self.next_token.to_string()
+                ));
             }
         }
 
@@ -171,54 +838,150 @@ impl Parser {
                 token = self.curr_token.clone();
                 self.next_token(); // Curr: (
             }
-            _ => return None,
+            _ => {
+                return Err(format!(
+                    "Expected `(` but found `{}`",
+                    self.next_token.to_string()
+                ))
+            }
         }
 
-        let keys = self.parse_layout_keys();
-        let statement = LayoutStatement::new(token, keys);
+        let (keys, positions, directives) = self.parse_layout_keys()?;
+        let mut statement = LayoutStatement::new(token, keys).with_positions(positions);
+        statement.directives = directives;
 
-        return Some(statement);
+        Ok(statement)
     }
 
-    fn parse_layout_keys(&mut self) -> Vec<String> {
-        self.next_token();
-
+    fn parse_layout_keys(&mut self) -> Result<LayoutKeys, String> {
         let mut keys: Vec<String> = vec![];
+        let mut positions: Vec<usize> = vec![];
+        let mut directives: Vec<TokenType> = vec![];
+
+        // For each open conditional, whether a branch has been taken and whether the
+        // current branch is active
+        let mut conditionals: Vec<(bool, bool)> = vec![];
+        let mut expect_comma = false;
+        // Whether the next key continues an expression such as `MOD_LSFT | MOD_LCTL`
+        let mut joining = false;
+
+        loop {
+            // Stop before anything that can't be part of a layer, so the next layer can still
+            // be parsed
+            match self.next_token {
+                TokenType::LSqBrace(..)
+                | TokenType::RSqBrace(..)
+                | TokenType::LBrace(..)
+                | TokenType::RBrace(..)
+                | TokenType::Equals(..) => {
+                    return Err(format!(
+                        "Unexpected `{}` between the keys",
+                        self.next_token.to_string()
+                    ));
+                }
+                TokenType::EOF => return Err("Missing the closing `)`".to_string()),
+                _ => self.next_token(),
+            }
+            if let TokenType::RParen(..) = self.curr_token {
+                break;
+            }
+
+            let skipped = conditionals.iter().any(|(_, active)| !active);
 
-        while match self.curr_token {
-            TokenType::RParen(..) => false,
-            _ => true,
-        } {
             match &self.curr_token {
-                TokenType::Ident(_, _) => {
-                    let key_string = match &self.next_token {
-                        TokenType::LParen(..) => self.parse_function_keymap(),
+                TokenType::Directive(_, _, x) => {
+                    if let Some(defines) = &self.defines {
+                        if preprocessor::is_conditional_start(x) {
+                            let active = preprocessor::is_branch_active(x, defines);
+                            conditionals.push((active, active));
+                        } else if preprocessor::is_conditional_branch(x) {
+                            if let Some((taken, active)) = conditionals.last_mut() {
+                                *active = !*taken && preprocessor::is_branch_active(x, defines);
+                                *taken |= *active;
+                            }
+                        } else if preprocessor::is_conditional_end(x) {
+                            conditionals.pop();
+                        }
+                    }
+
+                    directives.push(self.curr_token.clone());
+                }
+                _ if skipped => {}
+                TokenType::Comma(..) => {
+                    expect_comma = false;
+                    joining = false;
+                }
+                TokenType::Punctuator(_, x) if expect_comma => {
+                    *keys.last_mut().ok_or("Expected a key")? += x;
+                    expect_comma = false;
+                    joining = true;
+                }
+                TokenType::Punctuator(..) | TokenType::String(..) | TokenType::Char(..) => {
+                    return Err(format!(
+                        "Unexpected `{}` between the keys",
+                        self.curr_token.to_string()
+                    ));
+                }
+                TokenType::Ident(..)
+                | TokenType::Number(..)
+                | TokenType::Blank(..)
+                | TokenType::LParen(..)
+                    if expect_comma =>
+                {
+                    return Err(format!(
+                        "Missing comma before `{}`",
+                        self.curr_token.to_string()
+                    ));
+                }
+                TokenType::Ident(position, _)
+                | TokenType::Number(position, _)
+                | TokenType::LParen(position) => {
+                    expect_comma = true;
+                    let position = *position;
+                    let key_string = match (&self.curr_token, &self.next_token) {
+                        (TokenType::LParen(..), _) | (_, TokenType::LParen(..)) => {
+                            self.parse_function_keymap()
+                        }
                         _ => self.curr_token.to_string(),
                     };
-                    keys.push(key_string);
+                    match std::mem::take(&mut joining) {
+                        true => *keys.last_mut().ok_or("Expected a key")? += &key_string,
+                        false => {
+                            positions.push(position);
+                            keys.push(key_string);
+                        }
+                    }
                 }
-                TokenType::Blank(..) => keys.push("".to_string()),
-                TokenType::LParen(..) => {
-                    keys.push("(".to_string());
+                TokenType::Blank(position, x) => {
+                    expect_comma = true;
+                    positions.push(*position);
+                    keys.push(x.to_string());
                 }
                 _ => {}
             };
-
-            self.next_token();
         }
 
-        keys
+        Ok((keys, positions, directives))
     }
 
+    /// Reads a key up to its closing parenthesis, either a call such as `LCTL(KC_1)` or an
+    /// expression in parentheses such as `(KC_A)`
     fn parse_function_keymap(&mut self) -> String {
         let mut key_string = "".to_string();
+        let mut depth = match self.curr_token {
+            TokenType::LParen(..) => 1,
+            _ => 0,
+        };
 
         loop {
             key_string.push_str(&self.curr_token.to_string());
             self.next_token();
 
             match self.curr_token {
-                TokenType::RParen(..) => break,
+                TokenType::LParen(..) => depth += 1,
+                TokenType::RParen(..) if depth == 1 => break,
+                TokenType::RParen(..) => depth -= 1,
+                TokenType::EOF => return key_string,
                 _ => {}
             }
         }
@@ -228,6 +991,133 @@ impl Parser {
     }
 }
 
+/// Moves the broken layers out of `nodes`, including those inside conditional blocks
+fn take_errors(nodes: Vec<KeymapNode>, errors: &mut Vec<ParseError>) {
+    for node in nodes {
+        match node {
+            KeymapNode::Error(x) => errors.push(x),
+            KeymapNode::Conditional(x) => {
+                for branch in x.branches {
+                    take_errors(branch.nodes, errors);
+                }
+            }
+            KeymapNode::Keymap(..) | KeymapNode::Directive(..) => {}
+        }
+    }
+}
+
+fn resolve_layer_indices(nodes: &mut [KeymapNode], symbols: &HashMap<String, i64>) {
+    for node in nodes {
+        match node {
+            KeymapNode::Keymap(x) => resolve_layer_index(x, symbols),
+            KeymapNode::Conditional(x) => {
+                for branch in x.branches.iter_mut() {
+                    resolve_layer_indices(&mut branch.nodes, symbols);
+                }
+            }
+            KeymapNode::Directive(..) | KeymapNode::Error(..) => {}
+        }
+    }
+}
+
+fn resolve_layer_index(keymap: &mut KeymapStatement, symbols: &HashMap<String, i64>) {
+    keymap.index = evaluate_layer_index(&keymap.token, symbols);
+}
+
+fn evaluate_layer_index(token: &TokenType, symbols: &HashMap<String, i64>) -> Option<usize> {
+    evaluate_index(&token.to_string(), symbols).and_then(|x| usize::try_from(x).ok())
+}
+
+/// Evaluates a sum of numbers on their own, such as `0x1` or `(1)`
+pub fn evaluate_constant(expression: &str) -> Option<i64> {
+    evaluate_index(expression, &HashMap::new())
+}
+
+/// Evaluates a sum of numbers and known names, such as `_BASE + 1`
+fn evaluate_index(expression: &str, symbols: &HashMap<String, i64>) -> Option<i64> {
+    let mut tokens: Vec<String> = vec![];
+    for ch in expression.chars() {
+        let is_word = ch.is_ascii_alphanumeric() || ch == '_';
+        match tokens.last_mut() {
+            Some(x) if is_word && x.chars().all(|x| x.is_ascii_alphanumeric() || x == '_') => {
+                x.push(ch)
+            }
+            _ if ch.is_whitespace() => tokens.push(String::new()),
+            _ => tokens.push(ch.to_string()),
+        }
+    }
+    tokens.retain(|x| !x.is_empty());
+
+    let mut position = 0;
+    let value = evaluate_sum(&tokens, &mut position, symbols)?;
+    match position == tokens.len() {
+        true => Some(value),
+        false => None,
+    }
+}
+
+fn evaluate_sum(
+    tokens: &[String],
+    position: &mut usize,
+    symbols: &HashMap<String, i64>,
+) -> Option<i64> {
+    let mut value = evaluate_term(tokens, position, symbols)?;
+    while let Some(operator) = tokens.get(*position) {
+        let sign = match operator.as_str() {
+            "+" => 1,
+            "-" => -1,
+            _ => break,
+        };
+
+        *position += 1;
+        value += sign * evaluate_term(tokens, position, symbols)?;
+    }
+
+    Some(value)
+}
+
+fn evaluate_term(
+    tokens: &[String],
+    position: &mut usize,
+    symbols: &HashMap<String, i64>,
+) -> Option<i64> {
+    let token = tokens.get(*position)?;
+    *position += 1;
+
+    match token.as_str() {
+        "(" => {
+            let value = evaluate_sum(tokens, position, symbols)?;
+            match tokens.get(*position).map(|x| x.as_str()) {
+                Some(")") => {
+                    *position += 1;
+                    Some(value)
+                }
+                _ => None,
+            }
+        }
+        "-" => Some(-evaluate_term(tokens, position, symbols)?),
+        _ => parse_integer(token).or_else(|| symbols.get(token).copied()),
+    }
+}
+
+/// Reads a C integer literal, e.g. `12`, `0x7E`, `0b101`, `017` or `100UL`
+fn parse_integer(literal: &str) -> Option<i64> {
+    let literal = literal
+        .trim_end_matches(['u', 'U', 'l', 'L'])
+        .replace('\'', "");
+    let lower = literal.to_ascii_lowercase();
+
+    if let Some(digits) = lower.strip_prefix("0x") {
+        i64::from_str_radix(digits, 16).ok()
+    } else if let Some(digits) = lower.strip_prefix("0b") {
+        i64::from_str_radix(digits, 2).ok()
+    } else if lower.len() > 1 && lower.starts_with('0') {
+        i64::from_str_radix(&lower[1..], 8).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::ast::LayoutStatement;
@@ -248,18 +1138,22 @@ mod tests {
         let ast = parser.parse();
 
         assert_eq!(
-            &StatementEnum::KeymapStatement(KeymapStatement::new(
-                TokenType::Ident(1, "_QWERTY".to_string()),
-                LayoutStatement::new(
-                    TokenType::Layout(12),
-                    vec![
-                        "KC_ESC".to_string(),
-                        "KC_Q".to_string(),
-                        "".to_string(),
-                        "KC_E".to_string()
-                    ]
+            &StatementEnum::KeymapStatement(
+                KeymapStatement::new(
+                    TokenType::Ident(1, "_QWERTY".to_string()),
+                    LayoutStatement::new(
+                        TokenType::Layout(12),
+                        vec![
+                            "KC_ESC".to_string(),
+                            "KC_Q".to_string(),
+                            "_____".to_string(),
+                            "KC_E".to_string()
+                        ]
+                    )
+                    .with_positions(vec![22, 32, 39, 47])
                 )
-            )),
+                .with_span(0, 57)
+            ),
             ast.statements
                 .get(0)
                 .expect("Failed to find statement in ast")
@@ -279,24 +1173,58 @@ mod tests {
         let ast = parser.parse();
 
         assert_eq!(
-            &StatementEnum::KeymapStatement(KeymapStatement::new(
-                TokenType::Ident(1, "_QWERTY".to_string()),
-                LayoutStatement::new(
-                    TokenType::Layout(12),
-                    vec![
-                        "KC_ESC".to_string(),
-                        "LCTL(KC_1)".to_string(),
-                        "".to_string(),
-                        "KC_E".to_string()
-                    ]
+            &StatementEnum::KeymapStatement(
+                KeymapStatement::new(
+                    TokenType::Ident(1, "_QWERTY".to_string()),
+                    LayoutStatement::new(
+                        TokenType::Layout(12),
+                        vec![
+                            "KC_ESC".to_string(),
+                            "LCTL(KC_1)".to_string(),
+                            "_____".to_string(),
+                            "KC_E".to_string()
+                        ]
+                    )
+                    .with_positions(vec![22, 32, 44, 52])
                 )
-            )),
+                .with_span(0, 62)
+            ),
             ast.statements
                 .get(0)
                 .expect("Failed to find statement in ast")
         );
     }
 
+    #[test]
+    fn test_parse_parenthesized_keys_and_literals() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT((KC_A), ((KC_B)) | MOD_BIT(KC_C)),
+[_NAV] = LAYOUT(KC_D, "KC_E"),
+[_SYM] = LAYOUT('f', KC_G),
+};"##;
+
+        let ast = Parser::new(Lexer::new(content)).parse();
+
+        let keys: Vec<&[String]> = ast
+            .keymaps()
+            .iter()
+            .map(|x| x.layout_statement.keys.as_slice())
+            .collect();
+        assert_eq!(
+            vec![&["(KC_A)".to_string(), "((KC_B))|MOD_BIT(KC_C)".to_string()][..]],
+            keys
+        );
+
+        let messages: Vec<&str> = ast.errors().iter().map(|x| x.message.as_str()).collect();
+        assert_eq!(
+            vec![
+                "Unexpected `\"KC_E\"` between the keys in layer _NAV",
+                "Unexpected `'f'` between the keys in layer _SYM"
+            ],
+            messages
+        );
+    }
+
     #[test]
     fn test_parse_keymap_init() {
         let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
@@ -316,32 +1244,40 @@ mod tests {
 
         assert_eq!(
             &StatementEnum::Keymaps(
-                61,
+                62,
                 210,
                 vec![
-                    KeymapStatement::new(
-                        TokenType::Ident(80, "_QWERTY".to_string()),
-                        LayoutStatement::new(
-                            TokenType::Layout(91),
-                            vec![
-                                "KC_ESC".to_string(),
-                                "KC_Q".to_string(),
-                                "".to_string(),
-                                "KC_E".to_string()
-                            ]
+                    KeymapNode::Keymap(
+                        KeymapStatement::new(
+                            TokenType::Ident(80, "_QWERTY".to_string()),
+                            LayoutStatement::new(
+                                TokenType::Layout(91),
+                                vec![
+                                    "KC_ESC".to_string(),
+                                    "KC_Q".to_string(),
+                                    "_____".to_string(),
+                                    "KC_E".to_string()
+                                ]
+                            )
+                            .with_positions(vec![101, 111, 118, 126])
                         )
+                        .with_span(79, 136)
                     ),
-                    KeymapStatement::new(
-                        TokenType::Ident(154, "_SYM".to_string()),
-                        LayoutStatement::new(
-                            TokenType::Layout(162),
-                            vec![
-                                "KC_ESC".to_string(),
-                                "KC_Q".to_string(),
-                                "".to_string(),
-                                "KC_E".to_string()
-                            ]
+                    KeymapNode::Keymap(
+                        KeymapStatement::new(
+                            TokenType::Ident(154, "_SYM".to_string()),
+                            LayoutStatement::new(
+                                TokenType::Layout(162),
+                                vec![
+                                    "KC_ESC".to_string(),
+                                    "KC_Q".to_string(),
+                                    "_____".to_string(),
+                                    "KC_E".to_string()
+                                ]
+                            )
+                            .with_positions(vec![172, 182, 189, 197])
                         )
+                        .with_span(153, 207)
                     )
                 ]
             ),
@@ -350,4 +1286,420 @@ mod tests {
                 .expect("Failed to find statement in ast")
         );
     }
+
+    #[test]
+    fn test_parse_conditional_layers() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, MO(_NAV)),
+#ifdef RGB_MATRIX_ENABLE
+[_NAV] = LAYOUT(RGB_TOG, _______),
+#else
+[_NAV] = LAYOUT(KC_NO, _______),
+#endif
+}"##;
+
+        let ast = Parser::new(Lexer::new(content)).parse();
+
+        let Some(StatementEnum::Keymaps(_, _, nodes)) = ast.statements.first() else {
+            panic!("Failed to find keymaps in ast");
+        };
+        let KeymapNode::Conditional(conditional) = &nodes[1] else {
+            panic!("Expected a conditional node");
+        };
+        assert_eq!(2, conditional.branches.len());
+        assert_eq!("#else", conditional.branches[1].directive.to_string());
+        assert_eq!(None, conditional.branches[0].active);
+        assert_eq!(
+            Some("#endif".to_string()),
+            conditional.end.as_ref().map(|x| x.to_string())
+        );
+        assert_eq!(3, ast.keymaps().len());
+
+        let ast = Parser::new(Lexer::new(content))
+            .with_defines(HashSet::from(["RGB_MATRIX_ENABLE".to_string()]))
+            .parse();
+        let keys: Vec<&Vec<String>> = ast
+            .keymaps()
+            .iter()
+            .map(|x| &x.layout_statement.keys)
+            .collect();
+        assert_eq!(vec!["RGB_TOG", "_______"], *keys[1]);
+        assert_eq!(2, keys.len());
+    }
+
+    #[test]
+    fn test_parse_directives_between_keys() {
+        let content = r##"[_BASE] = LAYOUT(
+  KC_A,
+#ifdef RGB_MATRIX_ENABLE
+  RGB_TOG,
+#else
+  KC_NO,
+#endif
+  KC_B
+),"##;
+
+        let ast = Parser::new(Lexer::new(content))
+            .with_defines(HashSet::new())
+            .parse();
+
+        let Some(StatementEnum::KeymapStatement(keymap)) = ast.statements.first() else {
+            panic!("Failed to find statement in ast");
+        };
+        assert_eq!(vec!["KC_A", "KC_NO", "KC_B"], keymap.layout_statement.keys);
+        assert_eq!(3, keymap.layout_statement.directives.len());
+    }
+
+    #[test]
+    fn test_parse_layer_enum_and_indices() {
+        let content = r##"#define _GAME 7
+#define _FN 0x8
+#define _MEDIA (9)
+enum layers : uint8_t { _BASE, _NAV = 3, _SYM, _ADJ = _SYM + 2 };
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_NAV] = LAYOUT(KC_B),
+[0] = LAYOUT(KC_A),
+[_BASE + 1] = LAYOUT(KC_C),
+[(_ADJ - 1)] = LAYOUT(KC_D),
+[_GAME] = LAYOUT(KC_E),
+[_FN] = LAYOUT(KC_G),
+[_MEDIA] = LAYOUT(KC_H),
+[_UNKNOWN] = LAYOUT(KC_F),
+}"##;
+
+        let ast = Parser::new(Lexer::new(content)).parse();
+
+        let Some(StatementEnum::Enum(_, _, layers)) = ast.statements.first() else {
+            panic!("Failed to find enum in ast");
+        };
+        let members: Vec<(String, Option<i64>)> = layers
+            .members
+            .iter()
+            .map(|x| (x.token.to_string(), x.value))
+            .collect();
+        assert_eq!(
+            Some("layers".to_string()),
+            layers.token.as_ref().map(|x| x.to_string())
+        );
+        assert_eq!(
+            vec![
+                ("_BASE".to_string(), Some(0)),
+                ("_NAV".to_string(), Some(3)),
+                ("_SYM".to_string(), Some(4)),
+                ("_ADJ".to_string(), Some(6)),
+            ],
+            members
+        );
+
+        let indices: Vec<(String, Option<usize>)> = ast
+            .keymaps()
+            .iter()
+            .map(|x| (x.token.to_string(), x.index))
+            .collect();
+        assert_eq!(
+            vec![
+                ("_NAV".to_string(), Some(3)),
+                ("0".to_string(), Some(0)),
+                ("_BASE + 1".to_string(), Some(1)),
+                ("(_ADJ - 1)".to_string(), Some(5)),
+                ("_GAME".to_string(), Some(7)),
+                ("_FN".to_string(), Some(8)),
+                ("_MEDIA".to_string(), Some(9)),
+                ("_UNKNOWN".to_string(), None),
+            ],
+            indices
+        );
+    }
+
+    #[test]
+    fn test_parse_literals_and_operators() {
+        let content = r##"const char *greeting = "hello, world }; [";
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[0x1] = LAYOUT(SEND_STRING("a)b"), KC_A, 0x7E),
+[02] = LAYOUT(MT(MOD_LCTL | MOD_LSFT, KC_B), KC_C, UC(')')),
+[_BASE + 3] = LAYOUT(QK_MODS | KC_A, KC_B, KC_C),
+};
+const key_override_t *key_overrides[] = { &delete_key_override };"##;
+
+        let ast = Parser::new(Lexer::new(content)).parse();
+
+        let keymaps: Vec<(Option<usize>, Vec<String>)> = ast
+            .keymaps()
+            .iter()
+            .map(|x| (x.index, x.layout_statement.keys.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    Some(1),
+                    vec![
+                        "SEND_STRING(\"a)b\")".to_string(),
+                        "KC_A".to_string(),
+                        "0x7E".to_string()
+                    ]
+                ),
+                (
+                    Some(2),
+                    vec![
+                        "MT(MOD_LCTL|MOD_LSFT,KC_B)".to_string(),
+                        "KC_C".to_string(),
+                        "UC(')')".to_string()
+                    ]
+                ),
+                (
+                    None,
+                    vec![
+                        "QK_MODS|KC_A".to_string(),
+                        "KC_B".to_string(),
+                        "KC_C".to_string()
+                    ]
+                ),
+            ],
+            keymaps
+        );
+        assert!(matches!(
+            ast.statements.last(),
+            Some(StatementEnum::KeyOverrides(_, _, entries)) if entries == &["&delete_key_override"]
+        ));
+        assert_eq!(Some(126), parse_integer("0x7E"));
+        assert_eq!(Some(5), parse_integer("0b101"));
+        assert_eq!(Some(15), parse_integer("017"));
+        assert_eq!(Some(100), parse_integer("100UL"));
+    }
+
+    #[test]
+    fn test_parse_encoder_map() {
+        let content = r##"enum layers { _BASE, _NAV };
+const uint16_t PROGMEM encoder_map[][NUM_ENCODERS][NUM_DIRECTIONS] = {
+    [_BASE] = { ENCODER_CCW_CW(KC_VOLD, KC_VOLU), ENCODER_CCW_CW(C(KC_Z), _______) },
+    [1] = { ENCODER_CCW_CW(KC_PGDN, KC_PGUP) },
+};"##;
+
+        let ast = Parser::new(Lexer::new(content)).parse();
+
+        let Some(entries) = ast.encoder_map() else {
+            panic!("Failed to find encoder_map in ast");
+        };
+        let indices: Vec<(String, Option<usize>)> = entries
+            .iter()
+            .map(|x| (x.token.to_string(), x.index))
+            .collect();
+        assert_eq!(
+            vec![("_BASE".to_string(), Some(0)), ("1".to_string(), Some(1))],
+            indices
+        );
+
+        let encoders: Vec<Vec<(&str, &str)>> = entries
+            .iter()
+            .map(|x| {
+                x.encoders
+                    .iter()
+                    .map(|x| (x.ccw.as_str(), x.cw.as_str()))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                vec![("KC_VOLD", "KC_VOLU"), ("C(KC_Z)", "_______")],
+                vec![("KC_PGDN", "KC_PGUP")],
+            ],
+            encoders
+        );
+    }
+
+    #[test]
+    fn test_parse_combos() {
+        let content = r##"const uint16_t PROGMEM combo_jk[] = {KC_J, KC_K, COMBO_END};
+const uint16_t PROGMEM not_a_combo[] = {KC_A, KC_B};
+const uint16_t PROGMEM combo_df[3] = {LSFT_T(KC_D), KC_F, COMBO_END};
+combo_t key_combos[COMBO_COUNT] = {
+    [JK_ESC] = COMBO(combo_jk, KC_ESC),
+    COMBO_ACTION(combo_df),
+};"##;
+
+        let ast = Parser::new(Lexer::new(content)).parse();
+
+        let combo_keys: Vec<(String, &str, &[String])> = ast
+            .combo_keys()
+            .iter()
+            .map(|(_, _, x)| {
+                (
+                    x.token.to_string(),
+                    x.declarator.as_str(),
+                    x.keys.as_slice(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "combo_jk".to_string(),
+                    "combo_jk[]",
+                    &[
+                        "KC_J".to_string(),
+                        "KC_K".to_string(),
+                        "COMBO_END".to_string()
+                    ][..]
+                ),
+                (
+                    "combo_df".to_string(),
+                    "combo_df[3]",
+                    &[
+                        "LSFT_T(KC_D)".to_string(),
+                        "KC_F".to_string(),
+                        "COMBO_END".to_string()
+                    ][..]
+                ),
+            ],
+            combo_keys
+        );
+
+        let Some(combos) = ast.combos() else {
+            panic!("Failed to find key_combos in ast");
+        };
+        assert_eq!(
+            Some("JK_ESC".to_string()),
+            combos[0].index.as_ref().map(|x| x.to_string())
+        );
+        assert_eq!(Some("KC_ESC"), combos[0].result());
+        assert_eq!("combo_df", combos[1].keys_name());
+        assert_eq!(None, combos[1].result());
+        assert!(ast.get_combo_keys(&combos[1]).is_some());
+    }
+
+    #[test]
+    fn test_parse_tap_dances() {
+        let content = r##"tap_dance_action_t tap_dance_actions[] = {
+    [TD_ESC_CAPS] = ACTION_TAP_DANCE_DOUBLE(KC_ESC, KC_CAPS),
+    [TD_NAV] = ACTION_TAP_DANCE_LAYER_TOGGLE(KC_TAB, _NAV),
+    [TD_CUSTOM] = ACTION_TAP_DANCE_FN(dance_finished),
+};"##;
+
+        let ast = Parser::new(Lexer::new(content)).parse();
+
+        let Some(dances) = ast.tap_dances() else {
+            panic!("Failed to find tap_dance_actions in ast");
+        };
+        let actions: Vec<Option<(String, String)>> = dances.iter().map(|x| x.actions()).collect();
+        assert_eq!(
+            vec![
+                Some(("KC_ESC".to_string(), "KC_CAPS".to_string())),
+                Some(("KC_TAB".to_string(), "TG(_NAV)".to_string())),
+                None,
+            ],
+            actions
+        );
+        assert_eq!(
+            Some("KC_ESC/KC_CAPS".to_string()),
+            ast.get_tap_dance_label("TD(TD_ESC_CAPS)")
+        );
+        assert_eq!(None, ast.get_tap_dance_label("TD(TD_CUSTOM)"));
+    }
+
+    #[test]
+    fn test_parse_declaration_variants() {
+        let declarations = [
+            "const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {",
+            "const uint16_t keymaps[][MATRIX_ROWS][MATRIX_COLS] PROGMEM = {",
+            "uint16_t const PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {",
+            "const uint16_t keymaps[][MATRIX_ROWS][MATRIX_COLS] = {",
+            "static const uint16_t PROGMEM keymaps[_LAYER_COUNT][MATRIX_ROWS][MATRIX_COLS] = {",
+            "PROGMEM const uint16_t keymaps[2][MATRIX_ROWS][MATRIX_COLS] = {",
+        ];
+
+        for declaration in declarations {
+            let content = format!("{}\n[_BASE] = LAYOUT(KC_A),\n}};", declaration);
+            let ast = Parser::new(Lexer::new(&content)).parse();
+
+            let keys: Vec<&[String]> = ast
+                .keymaps()
+                .iter()
+                .map(|x| x.layout_statement.keys.as_slice())
+                .collect();
+            assert_eq!(vec![&["KC_A".to_string()][..]], keys, "{}", declaration);
+        }
+
+        let content = "uint16_t keycode, keyrecord_t *record) {\nconst uint16_t *other[] = {KC_A};";
+        assert!(Parser::new(Lexer::new(content))
+            .parse()
+            .statements
+            .is_empty());
+    }
+
+    #[test]
+    fn test_parse_recovers_from_broken_layers() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A KC_B),
+[_NAV] = LAYOUT(LT(1, LCTL(KC_A)), KC_C),
+[_SYM] = LAYOUT(KC_D, KC_E
+[_FN] = LAYOUT(KC_F, KC_G),
+[_ADJ] LAYOUT(KC_H, KC_I)
+};"##;
+
+        let ast = Parser::new(Lexer::new(content)).parse();
+
+        let keys: Vec<(String, Vec<String>)> = ast
+            .keymaps()
+            .iter()
+            .map(|x| (x.token.to_string(), x.layout_statement.keys.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "_NAV".to_string(),
+                    vec!["LT(1,LCTL(KC_A))".to_string(), "KC_C".to_string()]
+                ),
+                (
+                    "_FN".to_string(),
+                    vec!["KC_F".to_string(), "KC_G".to_string()]
+                ),
+            ],
+            keys
+        );
+
+        let errors: Vec<(&str, &str)> = ast
+            .errors()
+            .iter()
+            .map(|x| (&content[x.start..x.end], x.message.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "[_BASE] = LAYOUT(KC_A KC_B),",
+                    "Missing comma before `KC_B` in layer _BASE"
+                ),
+                (
+                    "[_SYM] = LAYOUT(KC_D, KC_E",
+                    "Unexpected `[` between the keys in layer _SYM"
+                ),
+                (
+                    "[_ADJ] LAYOUT(KC_H, KC_I)",
+                    "Expected `= LAYOUT(` but found `LAYOUT` in layer _ADJ"
+                ),
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_parse_broken_layer_at_end_of_file() {
+        let content = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_BASE] = LAYOUT(KC_A, KC_B),
+[_NAV] = LAYOUT(KC_C KC_D"##;
+
+        let ast = Parser::new(Lexer::new(content)).parse();
+
+        let errors: Vec<(&str, &str)> = ast
+            .errors()
+            .iter()
+            .map(|x| (&content[x.start..x.end], x.message.as_str()))
+            .collect();
+        assert_eq!(
+            vec![(
+                "[_NAV] = LAYOUT(KC_C KC_D",
+                "Missing comma before `KC_D` in layer _NAV"
+            )],
+            errors
+        );
+    }
 }
@@ -0,0 +1,314 @@
+use std::collections::HashSet;
+
+/// The directive keyword, e.g. "ifdef" for `#  ifdef RGB_MATRIX_ENABLE`
+pub fn get_directive_name(directive: &str) -> &str {
+    let directive = directive.trim_start_matches('#').trim_start();
+    let end = directive
+        .find(|x: char| !(x.is_ascii_alphanumeric() || x == '_'))
+        .unwrap_or(directive.len());
+
+    &directive[..end]
+}
+
+/// Everything after the directive keyword with comments and line continuations removed
+fn get_directive_body(directive: &str) -> String {
+    let name = get_directive_name(directive);
+    let start = directive.find(name).unwrap_or(0) + name.len();
+    let body = strip_block_comments(&directive[start..]);
+    let body = match body.find("//") {
+        Some(x) => &body[..x],
+        None => &body,
+    };
+
+    body.replace("\\\n", " ")
+        .replace("\\\r\n", " ")
+        .trim()
+        .to_string()
+}
+
+/// Swaps each `/* */` comment for a space, dropping the rest of the text after one that
+/// isn't closed
+fn strip_block_comments(text: &str) -> String {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("/*") {
+        output += &rest[..start];
+        output += " ";
+        rest = match rest[start + 2..].find("*/") {
+            Some(end) => &rest[start + 2 + end + 2..],
+            None => "",
+        };
+    }
+
+    output + rest
+}
+
+/// Whether the directive opens a conditional block
+pub fn is_conditional_start(directive: &str) -> bool {
+    matches!(get_directive_name(directive), "if" | "ifdef" | "ifndef")
+}
+
+/// Whether the directive continues a conditional block with another branch
+pub fn is_conditional_branch(directive: &str) -> bool {
+    matches!(get_directive_name(directive), "elif" | "else")
+}
+
+pub fn is_conditional_end(directive: &str) -> bool {
+    get_directive_name(directive) == "endif"
+}
+
+/// The name declared by a `#define` and its byte offset within the directive
+pub fn get_define(directive: &str) -> Option<(usize, &str)> {
+    if get_directive_name(directive) != "define" {
+        return None;
+    }
+
+    let after_keyword = directive.find("define")? + "define".len();
+    let start =
+        after_keyword + directive[after_keyword..].find(|x: char| !x.is_ascii_whitespace())?;
+    let end = directive[start..]
+        .find(|x: char| !(x.is_ascii_alphanumeric() || x == '_'))
+        .map(|x| start + x)
+        .unwrap_or(directive.len());
+
+    match start < end {
+        true => Some((start, &directive[start..end])),
+        false => None,
+    }
+}
+
+/// The name, replacement and trailing comment of an object-like `#define`, e.g.
+/// `#define HOME_A LGUI_T(KC_A) // GUI/A`
+pub fn get_object_define(directive: &str) -> Option<(&str, String, Option<String>)> {
+    let (offset, name) = get_define(directive)?;
+    let rest = &directive[offset + name.len()..];
+    if rest.starts_with('(') {
+        return None;
+    }
+
+    let (value, comment) = match (rest.find("//"), rest.find("/*")) {
+        (Some(x), _) => (&rest[..x], Some(&rest[x + 2..])),
+        (None, Some(x)) => (&rest[..x], Some(rest[x + 2..].trim_end_matches("*/"))),
+        (None, None) => (rest, None),
+    };
+
+    let value = value.replace("\\\r\n", " ").replace("\\\n", " ");
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+
+    let comment = comment
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty());
+
+    Some((name, value.to_string(), comment))
+}
+
+/// Whether the branch a conditional directive opens is compiled with the given symbols defined
+pub fn is_branch_active(directive: &str, defines: &HashSet<String>) -> bool {
+    let body = get_directive_body(directive);
+    let symbol = body.split_whitespace().next().unwrap_or("");
+
+    match get_directive_name(directive) {
+        "ifdef" => defines.contains(symbol),
+        "ifndef" => !defines.contains(symbol),
+        "if" | "elif" => Evaluator::new(&body, defines).evaluate() != 0,
+        "else" => true,
+        _ => false,
+    }
+}
+
+/// Evaluates the integer expression of an `#if`, treating defined symbols as 1 and
+/// everything else as 0
+struct Evaluator<'a> {
+    tokens: Vec<String>,
+    position: usize,
+    defines: &'a HashSet<String>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(expression: &str, defines: &'a HashSet<String>) -> Self {
+        let chars: Vec<char> = expression.chars().collect();
+        let mut tokens: Vec<String> = vec![];
+
+        let mut i = 0;
+        while i < chars.len() {
+            let ch = chars[i];
+            if ch.is_ascii_whitespace() {
+                i += 1;
+                continue;
+            }
+
+            let start = i;
+            if ch.is_ascii_alphanumeric() || ch == '_' {
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+            } else if i + 1 < chars.len()
+                && ["&&", "||", "==", "!=", "<=", ">="]
+                    .contains(&chars[i..i + 2].iter().collect::<String>().as_str())
+            {
+                i += 2;
+            } else {
+                i += 1;
+            }
+
+            tokens.push(chars[start..i].iter().collect());
+        }
+
+        Self {
+            tokens,
+            position: 0,
+            defines,
+        }
+    }
+
+    fn peek(&self) -> &str {
+        self.tokens
+            .get(self.position)
+            .map(|x| x.as_str())
+            .unwrap_or("")
+    }
+
+    fn advance(&mut self) -> String {
+        let token = self.peek().to_string();
+        self.position += 1;
+        token
+    }
+
+    fn evaluate(&mut self) -> i64 {
+        self.parse_or()
+    }
+
+    fn parse_or(&mut self) -> i64 {
+        let mut value = self.parse_and();
+        while self.peek() == "||" {
+            self.advance();
+            let right = self.parse_and();
+            value = i64::from(value != 0 || right != 0);
+        }
+
+        value
+    }
+
+    fn parse_and(&mut self) -> i64 {
+        let mut value = self.parse_comparison();
+        while self.peek() == "&&" {
+            self.advance();
+            let right = self.parse_comparison();
+            value = i64::from(value != 0 && right != 0);
+        }
+
+        value
+    }
+
+    fn parse_comparison(&mut self) -> i64 {
+        let mut value = self.parse_unary();
+        while matches!(self.peek(), "==" | "!=" | "<" | ">" | "<=" | ">=") {
+            let operator = self.advance();
+            let right = self.parse_unary();
+            value = i64::from(match operator.as_str() {
+                "==" => value == right,
+                "!=" => value != right,
+                "<" => value < right,
+                ">" => value > right,
+                "<=" => value <= right,
+                _ => value >= right,
+            });
+        }
+
+        value
+    }
+
+    fn parse_unary(&mut self) -> i64 {
+        match self.peek() {
+            "!" => {
+                self.advance();
+                i64::from(self.parse_unary() == 0)
+            }
+            "-" => {
+                self.advance();
+                -self.parse_unary()
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> i64 {
+        let token = self.advance();
+        match token.as_str() {
+            "(" => {
+                let value = self.parse_or();
+                if self.peek() == ")" {
+                    self.advance();
+                }
+                value
+            }
+            "defined" => {
+                let parenthesised = self.peek() == "(";
+                if parenthesised {
+                    self.advance();
+                }
+
+                let symbol = self.advance();
+                if parenthesised && self.peek() == ")" {
+                    self.advance();
+                }
+
+                i64::from(self.defines.contains(&symbol))
+            }
+            _ => match token.trim_end_matches(['u', 'U', 'l', 'L']).parse::<i64>() {
+                Ok(x) => x,
+                Err(_) => i64::from(self.defines.contains(&token)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directive_parts() {
+        assert_eq!("ifdef", get_directive_name("#  ifdef RGB_MATRIX_ENABLE"));
+        assert_eq!(
+            Some((8, "HOME_A")),
+            get_define("#define HOME_A LGUI_T(KC_A)")
+        );
+        assert_eq!(None, get_define("#include QMK_KEYBOARD_H"));
+        assert_eq!(
+            Some((
+                "HOME_A",
+                "LGUI_T(KC_A)".to_string(),
+                Some("GUI/A".to_string())
+            )),
+            get_object_define("#define HOME_A LGUI_T(KC_A) // GUI/A")
+        );
+        assert_eq!(None, get_object_define("#define HRM(k) LGUI_T(k)"));
+        assert_eq!(None, get_object_define("#define RGB_MATRIX_ENABLE"));
+        assert!(is_conditional_start("#if defined(FOO)"));
+        assert!(is_conditional_branch("#else // FOO"));
+        assert!(is_conditional_end("# endif"));
+    }
+
+    #[test]
+    fn test_branch_active() {
+        let defines = HashSet::from(["RGB_MATRIX_ENABLE".to_string(), "FOO".to_string()]);
+
+        assert!(is_branch_active("#ifdef RGB_MATRIX_ENABLE", &defines));
+        assert!(!is_branch_active("#ifndef FOO // comment", &defines));
+        assert!(is_branch_active(
+            "#if defined(FOO) && !defined(BAR)",
+            &defines
+        ));
+        assert!(is_branch_active("#elif defined BAR || FOO", &defines));
+        assert!(!is_branch_active("#if 0", &defines));
+        assert!(is_branch_active("#if (FOO) == 1 && 2 > 1", &defines));
+        assert!(is_branch_active("#else", &defines));
+        assert!(is_branch_active("#if FOO /* note */", &defines));
+        assert!(is_branch_active("#ifdef /* note */ FOO", &defines));
+        assert!(!is_branch_active("#if BAR /* FOO", &defines));
+    }
+}
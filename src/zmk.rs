@@ -0,0 +1,710 @@
+use crate::{
+    ast::KeymapStatement,
+    formatter::{Layout, Mark},
+    keycode::Keycode,
+};
+
+// (QMK keycode, ZMK key) pairs. The first entry for a key is the one used when converting back.
+const KEY_TABLE: &[(&str, &str)] = &[
+    ("KC_A", "A"),
+    ("KC_B", "B"),
+    ("KC_C", "C"),
+    ("KC_D", "D"),
+    ("KC_E", "E"),
+    ("KC_F", "F"),
+    ("KC_G", "G"),
+    ("KC_H", "H"),
+    ("KC_I", "I"),
+    ("KC_J", "J"),
+    ("KC_K", "K"),
+    ("KC_L", "L"),
+    ("KC_M", "M"),
+    ("KC_N", "N"),
+    ("KC_O", "O"),
+    ("KC_P", "P"),
+    ("KC_Q", "Q"),
+    ("KC_R", "R"),
+    ("KC_S", "S"),
+    ("KC_T", "T"),
+    ("KC_U", "U"),
+    ("KC_V", "V"),
+    ("KC_W", "W"),
+    ("KC_X", "X"),
+    ("KC_Y", "Y"),
+    ("KC_Z", "Z"),
+    ("KC_1", "N1"),
+    ("KC_2", "N2"),
+    ("KC_3", "N3"),
+    ("KC_4", "N4"),
+    ("KC_5", "N5"),
+    ("KC_6", "N6"),
+    ("KC_7", "N7"),
+    ("KC_8", "N8"),
+    ("KC_9", "N9"),
+    ("KC_0", "N0"),
+    ("KC_F1", "F1"),
+    ("KC_F2", "F2"),
+    ("KC_F3", "F3"),
+    ("KC_F4", "F4"),
+    ("KC_F5", "F5"),
+    ("KC_F6", "F6"),
+    ("KC_F7", "F7"),
+    ("KC_F8", "F8"),
+    ("KC_F9", "F9"),
+    ("KC_F10", "F10"),
+    ("KC_F11", "F11"),
+    ("KC_F12", "F12"),
+    ("KC_ENT", "RET"),
+    ("KC_ENTER", "RET"),
+    ("KC_ENT", "ENTER"),
+    ("KC_ENT", "RETURN"),
+    ("KC_ESC", "ESC"),
+    ("KC_ESCAPE", "ESC"),
+    ("KC_BSPC", "BSPC"),
+    ("KC_BACKSPACE", "BSPC"),
+    ("KC_TAB", "TAB"),
+    ("KC_SPC", "SPACE"),
+    ("KC_SPACE", "SPACE"),
+    ("KC_SPC", "SPC"),
+    ("KC_MINS", "MINUS"),
+    ("KC_MINUS", "MINUS"),
+    ("KC_EQL", "EQUAL"),
+    ("KC_EQUAL", "EQUAL"),
+    ("KC_LBRC", "LBKT"),
+    ("KC_RBRC", "RBKT"),
+    ("KC_BSLS", "BSLH"),
+    ("KC_SCLN", "SEMI"),
+    ("KC_SEMICOLON", "SEMI"),
+    ("KC_QUOT", "SQT"),
+    ("KC_QUOTE", "SQT"),
+    ("KC_GRV", "GRAVE"),
+    ("KC_GRAVE", "GRAVE"),
+    ("KC_COMM", "COMMA"),
+    ("KC_COMMA", "COMMA"),
+    ("KC_DOT", "DOT"),
+    ("KC_SLSH", "FSLH"),
+    ("KC_SLASH", "FSLH"),
+    ("KC_CAPS", "CAPS"),
+    ("KC_PSCR", "PSCRN"),
+    ("KC_SCRL", "SLCK"),
+    ("KC_PAUS", "PAUSE_BREAK"),
+    ("KC_INS", "INS"),
+    ("KC_HOME", "HOME"),
+    ("KC_PGUP", "PG_UP"),
+    ("KC_DEL", "DEL"),
+    ("KC_END", "END"),
+    ("KC_PGDN", "PG_DN"),
+    ("KC_RGHT", "RIGHT"),
+    ("KC_RIGHT", "RIGHT"),
+    ("KC_LEFT", "LEFT"),
+    ("KC_DOWN", "DOWN"),
+    ("KC_UP", "UP"),
+    ("KC_APP", "K_APP"),
+    ("KC_LCTL", "LCTRL"),
+    ("KC_LSFT", "LSHFT"),
+    ("KC_LALT", "LALT"),
+    ("KC_LGUI", "LGUI"),
+    ("KC_RCTL", "RCTRL"),
+    ("KC_RSFT", "RSHFT"),
+    ("KC_RALT", "RALT"),
+    ("KC_RGUI", "RGUI"),
+    ("KC_EXLM", "EXCL"),
+    ("KC_AT", "AT"),
+    ("KC_HASH", "HASH"),
+    ("KC_DLR", "DOLLAR"),
+    ("KC_PERC", "PERCENT"),
+    ("KC_CIRC", "CARET"),
+    ("KC_AMPR", "AMPS"),
+    ("KC_ASTR", "STAR"),
+    ("KC_LPRN", "LPAR"),
+    ("KC_RPRN", "RPAR"),
+    ("KC_UNDS", "UNDER"),
+    ("KC_PLUS", "PLUS"),
+    ("KC_LCBR", "LBRC"),
+    ("KC_RCBR", "RBRC"),
+    ("KC_PIPE", "PIPE"),
+    ("KC_COLN", "COLON"),
+    ("KC_DQUO", "DQT"),
+    ("KC_TILD", "TILDE"),
+    ("KC_LT", "LT"),
+    ("KC_GT", "GT"),
+    ("KC_QUES", "QMARK"),
+    ("KC_MUTE", "C_MUTE"),
+    ("KC_VOLU", "C_VOL_UP"),
+    ("KC_VOLD", "C_VOL_DN"),
+    ("KC_MNXT", "C_NEXT"),
+    ("KC_MPRV", "C_PREV"),
+    ("KC_MPLY", "C_PP"),
+    ("KC_MSTP", "C_STOP"),
+    ("KC_BRIU", "C_BRI_UP"),
+    ("KC_BRID", "C_BRI_DN"),
+];
+
+// (QMK keycode, ZMK behavior) pairs for keys that don't go through `&kp`
+const BEHAVIOR_TABLE: &[(&str, &str)] = &[
+    ("_______", "&trans"),
+    ("KC_TRNS", "&trans"),
+    ("KC_TRANSPARENT", "&trans"),
+    ("XXXXXXX", "&none"),
+    ("KC_NO", "&none"),
+    ("QK_BOOT", "&bootloader"),
+    ("QK_BOOTLOADER", "&bootloader"),
+    ("RESET", "&bootloader"),
+    ("QK_RBT", "&sys_reset"),
+    ("QK_REBOOT", "&sys_reset"),
+    ("CW_TOGG", "&caps_word"),
+    ("QK_CAPS_WORD_TOGGLE", "&caps_word"),
+    ("QK_GESC", "&gresc"),
+];
+
+// (QMK layer function, ZMK behavior)
+const LAYER_TABLE: &[(&str, &str)] =
+    &[("MO", "&mo"), ("TG", "&tog"), ("TO", "&to"), ("OSL", "&sl")];
+
+struct Modifier {
+    qmk_function: &'static str,
+    qmk_mod_tap: &'static str,
+    qmk_mod: &'static str,
+    zmk_function: &'static str,
+    zmk_key: &'static str,
+}
+
+const MODIFIERS: &[Modifier] = &[
+    Modifier {
+        qmk_function: "LCTL",
+        qmk_mod_tap: "LCTL_T",
+        qmk_mod: "MOD_LCTL",
+        zmk_function: "LC",
+        zmk_key: "LCTRL",
+    },
+    Modifier {
+        qmk_function: "LSFT",
+        qmk_mod_tap: "LSFT_T",
+        qmk_mod: "MOD_LSFT",
+        zmk_function: "LS",
+        zmk_key: "LSHFT",
+    },
+    Modifier {
+        qmk_function: "LALT",
+        qmk_mod_tap: "LALT_T",
+        qmk_mod: "MOD_LALT",
+        zmk_function: "LA",
+        zmk_key: "LALT",
+    },
+    Modifier {
+        qmk_function: "LGUI",
+        qmk_mod_tap: "LGUI_T",
+        qmk_mod: "MOD_LGUI",
+        zmk_function: "LG",
+        zmk_key: "LGUI",
+    },
+    Modifier {
+        qmk_function: "RCTL",
+        qmk_mod_tap: "RCTL_T",
+        qmk_mod: "MOD_RCTL",
+        zmk_function: "RC",
+        zmk_key: "RCTRL",
+    },
+    Modifier {
+        qmk_function: "RSFT",
+        qmk_mod_tap: "RSFT_T",
+        qmk_mod: "MOD_RSFT",
+        zmk_function: "RS",
+        zmk_key: "RSHFT",
+    },
+    Modifier {
+        qmk_function: "RALT",
+        qmk_mod_tap: "RALT_T",
+        qmk_mod: "MOD_RALT",
+        zmk_function: "RA",
+        zmk_key: "RALT",
+    },
+    Modifier {
+        qmk_function: "RGUI",
+        qmk_mod_tap: "RGUI_T",
+        qmk_mod: "MOD_RGUI",
+        zmk_function: "RG",
+        zmk_key: "RGUI",
+    },
+];
+
+#[derive(Debug, PartialEq)]
+pub struct Conversion {
+    pub output: String,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ZmkLayer {
+    pub name: String,
+    pub bindings: Vec<String>,
+}
+
+pub fn qmk_to_zmk(keymaps: &[&KeymapStatement], layout: &Layout) -> Conversion {
+    let layers: Vec<String> = keymaps.iter().map(|x| x.token.to_string()).collect();
+    let row_sizes: Vec<usize> = layout
+        .iter()
+        .map(|row| row.iter().filter(|x| matches!(x, Mark::K)).count())
+        .collect();
+
+    let mut warnings: Vec<String> = vec![];
+    let mut output = String::new();
+    output += "#include <behaviors.dtsi>\n";
+    output += "#include <dt-bindings/zmk/keys.h>\n\n";
+    output += "/ {\n";
+    output += "    keymap {\n";
+    output += "        compatible = \"zmk,keymap\";\n";
+
+    for keymap in keymaps {
+        let layer = keymap.token.to_string();
+        let bindings: Vec<String> = keymap
+            .layout_statement
+            .keys
+            .iter()
+            .map(|key| match qmk_key_to_zmk(&Keycode::parse(key), &layers) {
+                Ok(x) => x,
+                Err(x) => {
+                    warnings.push(format!("No ZMK equivalent for `{}` on layer {}", x, layer));
+                    "&none".to_string()
+                }
+            })
+            .collect();
+
+        let width = bindings.iter().map(|x| x.len()).max().unwrap_or(0);
+
+        output += "\n";
+        output += &format!("        {} {{\n", to_zmk_layer_name(&layer));
+        output += "            bindings = <\n";
+        for row in chunk_rows(&bindings, &row_sizes) {
+            let row: Vec<String> = row.iter().map(|x| format!("{:width$}", x)).collect();
+            output += &format!("                {}\n", row.join("  ").trim_end());
+        }
+        output += "            >;\n";
+        output += "        };\n";
+    }
+
+    output += "    };\n";
+    output += "};\n";
+
+    Conversion { output, warnings }
+}
+
+pub fn zmk_to_qmk(content: &str) -> Conversion {
+    let zmk_layers = parse_zmk_keymap(content);
+    let layers: Vec<String> = zmk_layers
+        .iter()
+        .map(|x| to_qmk_layer_name(&x.name))
+        .collect();
+
+    let mut warnings: Vec<String> = vec![];
+    let mut output = String::new();
+    output += "const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {\n";
+
+    for (layer, zmk_layer) in layers.iter().zip(zmk_layers.iter()) {
+        let keys: Vec<String> = group_bindings(&zmk_layer.bindings)
+            .iter()
+            .map(|binding| match zmk_binding_to_qmk(binding, &layers) {
+                Ok(x) => x,
+                Err(x) => {
+                    warnings.push(format!("No QMK equivalent for `{}` on layer {}", x, layer));
+                    "XXXXXXX".to_string()
+                }
+            })
+            .collect();
+
+        output += &format!("[{}] = LAYOUT(\n", layer);
+        output += &keys.join(", ");
+        output += "\n),\n";
+    }
+
+    output += "};\n";
+
+    Conversion { output, warnings }
+}
+
+fn chunk_rows<'a>(bindings: &'a [String], row_sizes: &[usize]) -> Vec<&'a [String]> {
+    let mut rows: Vec<&[String]> = vec![];
+    let mut rest = bindings;
+    for size in row_sizes {
+        if rest.len() < *size {
+            break;
+        }
+        let (row, remainder) = rest.split_at(*size);
+        rows.push(row);
+        rest = remainder;
+    }
+
+    if !rest.is_empty() {
+        rows.push(rest);
+    }
+
+    rows
+}
+
+fn to_zmk_layer_name(layer: &str) -> String {
+    format!("{}_layer", layer.trim_start_matches('_').to_lowercase())
+}
+
+fn to_qmk_layer_name(node: &str) -> String {
+    let name = node.strip_suffix("_layer").unwrap_or(node);
+    format!("_{}", name.replace('-', "_").to_uppercase())
+}
+
+fn qmk_layer_to_zmk(layer: &Keycode, layers: &[String]) -> Option<String> {
+    let name = layer.name();
+    if name.parse::<usize>().is_ok() {
+        return Some(name.to_string());
+    }
+
+    layers.iter().position(|x| x == name).map(|x| x.to_string())
+}
+
+fn qmk_kp_to_zmk(key: &Keycode) -> Option<String> {
+    match key {
+        Keycode::Basic(x) => KEY_TABLE
+            .iter()
+            .find(|(qmk, _)| qmk == x)
+            .map(|(_, zmk)| zmk.to_string()),
+        Keycode::Function(name, args) if args.len() == 1 => {
+            let modifier = MODIFIERS.iter().find(|x| x.qmk_function == name)?;
+            let inner = qmk_kp_to_zmk(&args[0])?;
+            Some(format!("{}({})", modifier.zmk_function, inner))
+        }
+        Keycode::Function(..) => None,
+    }
+}
+
+fn qmk_key_to_zmk(key: &Keycode, layers: &[String]) -> Result<String, String> {
+    if key.is_transparent() {
+        return Ok("&trans".to_string());
+    }
+
+    if let Some((_, behavior)) = BEHAVIOR_TABLE.iter().find(|(qmk, _)| qmk == &key.name()) {
+        if let Keycode::Basic(..) = key {
+            return Ok(behavior.to_string());
+        }
+    }
+
+    if let Some(x) = qmk_kp_to_zmk(key) {
+        return Ok(format!("&kp {}", x));
+    }
+
+    let converted = match key {
+        Keycode::Basic(..) => None,
+        Keycode::Function(name, args) => match (name.as_str(), args.as_slice()) {
+            ("LT", [layer, tap]) => qmk_layer_to_zmk(layer, layers)
+                .zip(qmk_kp_to_zmk(tap))
+                .map(|(layer, tap)| format!("&lt {} {}", layer, tap)),
+            ("OSM", [modifier]) => MODIFIERS
+                .iter()
+                .find(|x| x.qmk_mod == modifier.name())
+                .map(|x| format!("&sk {}", x.zmk_key)),
+            (name, [arg]) => match MODIFIERS.iter().find(|x| x.qmk_mod_tap == name) {
+                Some(modifier) => {
+                    qmk_kp_to_zmk(arg).map(|tap| format!("&mt {} {}", modifier.zmk_key, tap))
+                }
+                None => LAYER_TABLE
+                    .iter()
+                    .find(|(qmk, _)| qmk == &name)
+                    .and_then(|(_, zmk)| {
+                        qmk_layer_to_zmk(arg, layers).map(|layer| format!("{} {}", zmk, layer))
+                    }),
+            },
+            _ => None,
+        },
+    };
+
+    converted.ok_or(key.to_string())
+}
+
+fn zmk_layer_to_qmk(layer: &str, layers: &[String]) -> String {
+    match layer.parse::<usize>().ok().and_then(|x| layers.get(x)) {
+        Some(x) => x.to_string(),
+        None => layer.to_string(),
+    }
+}
+
+fn zmk_kp_to_qmk(key: &Keycode) -> Option<String> {
+    match key {
+        Keycode::Basic(x) => KEY_TABLE
+            .iter()
+            .find(|(_, zmk)| zmk == x)
+            .map(|(qmk, _)| qmk.to_string()),
+        Keycode::Function(name, args) if args.len() == 1 => {
+            let modifier = MODIFIERS.iter().find(|x| x.zmk_function == name)?;
+            let inner = zmk_kp_to_qmk(&args[0])?;
+            Some(format!("{}({})", modifier.qmk_function, inner))
+        }
+        Keycode::Function(..) => None,
+    }
+}
+
+fn zmk_binding_to_qmk(binding: &[String], layers: &[String]) -> Result<String, String> {
+    let behavior = binding[0].as_str();
+    let params: Vec<Keycode> = binding[1..].iter().map(|x| Keycode::parse(x)).collect();
+
+    let converted = match (behavior, params.as_slice()) {
+        ("&kp", [key]) => zmk_kp_to_qmk(key),
+        ("&lt", [layer, tap]) => zmk_kp_to_qmk(tap)
+            .map(|tap| format!("LT({}, {})", zmk_layer_to_qmk(layer.name(), layers), tap)),
+        ("&mt", [modifier, tap]) => MODIFIERS
+            .iter()
+            .find(|x| x.zmk_key == modifier.name())
+            .zip(zmk_kp_to_qmk(tap))
+            .map(|(modifier, tap)| format!("{}({})", modifier.qmk_mod_tap, tap)),
+        ("&sk", [modifier]) => MODIFIERS
+            .iter()
+            .find(|x| x.zmk_key == modifier.name())
+            .map(|x| format!("OSM({})", x.qmk_mod)),
+        (behavior, [layer]) => LAYER_TABLE
+            .iter()
+            .find(|(_, zmk)| zmk == &behavior)
+            .map(|(qmk, _)| format!("{}({})", qmk, zmk_layer_to_qmk(layer.name(), layers))),
+        (behavior, []) => BEHAVIOR_TABLE
+            .iter()
+            .find(|(_, zmk)| zmk == &behavior)
+            .map(|(qmk, _)| qmk.to_string()),
+        _ => None,
+    };
+
+    converted.ok_or(binding.join(" "))
+}
+
+fn group_bindings(words: &[String]) -> Vec<Vec<String>> {
+    let mut bindings: Vec<Vec<String>> = vec![];
+    for word in words {
+        match bindings.last_mut() {
+            Some(binding) if !word.starts_with('&') => binding.push(word.to_string()),
+            _ => bindings.push(vec![word.to_string()]),
+        }
+    }
+
+    bindings
+}
+
+/// Reads the layers of a ZMK devicetree keymap, returning the raw binding words of each layer
+pub fn parse_zmk_keymap(content: &str) -> Vec<ZmkLayer> {
+    let words = tokenize_devicetree(content);
+
+    let mut layers: Vec<ZmkLayer> = vec![];
+    let mut nodes: Vec<String> = vec![];
+    let mut i = 0;
+    while i < words.len() {
+        match words[i].as_str() {
+            "{" => nodes.push(if i > 0 {
+                words[i - 1].clone()
+            } else {
+                String::new()
+            }),
+            "}" => {
+                nodes.pop();
+            }
+            "bindings" if words.get(i + 1).map(|x| x.as_str()) == Some("=") => {
+                let in_keymap = nodes.len() > 1 && nodes[nodes.len() - 2] == "keymap";
+                let mut bindings: Vec<String> = vec![];
+                i += 2;
+                if words.get(i).map(|x| x.as_str()) == Some("<") {
+                    i += 1;
+                    while i < words.len() && words[i] != ">" {
+                        bindings.push(words[i].clone());
+                        i += 1;
+                    }
+                }
+
+                if in_keymap {
+                    layers.push(ZmkLayer {
+                        name: nodes[nodes.len() - 1].clone(),
+                        bindings,
+                    });
+                }
+            }
+            _ => {}
+        }
+
+        i += 1;
+    }
+
+    layers
+}
+
+fn tokenize_devicetree(content: &str) -> Vec<String> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut words: Vec<String> = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        match ch {
+            _ if ch.is_whitespace() => i += 1,
+            '/' if chars.get(i + 1) == Some(&'/') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i += 2;
+            }
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '"' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += 1;
+                }
+                i += 1;
+                words.push(chars[start..i.min(chars.len())].iter().collect());
+            }
+            '{' | '}' | '<' | '>' | ';' | '=' => {
+                words.push(ch.to_string());
+                i += 1;
+            }
+            _ => {
+                let start = i;
+                while i < chars.len() && !chars[i].is_whitespace() && !"{}<>;=\"".contains(chars[i])
+                {
+                    i += 1;
+                }
+                words.push(chars[start..i].iter().collect());
+            }
+        }
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ast::LayoutStatement, formatter::Mark::*, lexer::TokenType};
+
+    use super::*;
+
+    fn keymap(name: &str, keys: Vec<&str>) -> KeymapStatement {
+        KeymapStatement::new(
+            TokenType::Ident(0, name.to_string()),
+            LayoutStatement::new(
+                TokenType::Layout(0),
+                keys.iter().map(|x| x.to_string()).collect(),
+            ),
+        )
+    }
+
+    #[test]
+    fn test_qmk_key_to_zmk() {
+        let layers = vec!["_BASE".to_string(), "_NAV".to_string()];
+
+        let cases = [
+            ("KC_A", "&kp A"),
+            ("LT(1,KC_SPC)", "&lt 1 SPACE"),
+            ("MO(_NAV)", "&mo 1"),
+            ("KC_TRNS", "&trans"),
+            ("", "&trans"),
+            ("LCTL(KC_C)", "&kp LC(C)"),
+            ("LSFT_T(KC_A)", "&mt LSHFT A"),
+            ("OSM(MOD_LSFT)", "&sk LSHFT"),
+        ];
+
+        for (qmk, zmk) in cases {
+            assert_eq!(
+                Ok(zmk.to_string()),
+                qmk_key_to_zmk(&Keycode::parse(qmk), &layers)
+            );
+        }
+    }
+
+    #[test]
+    fn test_qmk_to_zmk_warns_on_unknown_keys() {
+        let keymaps = [keymap("_BASE", vec!["KC_A", "ADJUST", "TT(_BASE)"])];
+        let layout: Layout = vec![vec![K, K, K]];
+
+        let conversion = qmk_to_zmk(&keymaps.iter().collect::<Vec<_>>(), &layout);
+
+        assert!(conversion.output.contains("base_layer {"));
+        assert!(conversion.output.contains("&kp A  &none  &none"));
+        assert_eq!(
+            vec![
+                "No ZMK equivalent for `ADJUST` on layer _BASE".to_string(),
+                "No ZMK equivalent for `TT(_BASE)` on layer _BASE".to_string(),
+            ],
+            conversion.warnings
+        );
+    }
+
+    #[test]
+    fn test_zmk_to_qmk() {
+        let content = r##"#include <behaviors.dtsi>
+#include <dt-bindings/zmk/keys.h>
+
+/ {
+    combos {
+        compatible = "zmk,combos";
+        combo_esc {
+            bindings = <&kp ESC>;
+        };
+    };
+
+    keymap {
+        compatible = "zmk,keymap";
+
+        default_layer {
+            // Comment
+            bindings = <
+                &kp A  &lt 1 SPACE  &mo 1
+            >;
+        };
+
+        nav_layer {
+            bindings = <
+                &trans  &kp LC(C)  &foo
+            >;
+        };
+    };
+};
+"##;
+
+        let conversion = zmk_to_qmk(content);
+
+        let expected = r##"const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+[_DEFAULT] = LAYOUT(
+KC_A, LT(_NAV, KC_SPC), MO(_NAV)
+),
+[_NAV] = LAYOUT(
+_______, LCTL(KC_C), XXXXXXX
+),
+};
+"##;
+        assert_eq!(expected, conversion.output);
+        assert_eq!(
+            vec!["No QMK equivalent for `&foo` on layer _NAV".to_string()],
+            conversion.warnings
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let keymaps = [
+            keymap("_BASE", vec!["KC_ESC", "LT(_NAV,KC_SPC)", "LGUI_T(KC_A)"]),
+            keymap("_NAV", vec!["_______", "KC_LEFT", "TG(_NAV)"]),
+        ];
+        let layout: Layout = vec![vec![K, K, K]];
+
+        let zmk = qmk_to_zmk(&keymaps.iter().collect::<Vec<_>>(), &layout);
+        let qmk = zmk_to_qmk(&zmk.output);
+
+        assert!(zmk.warnings.is_empty());
+        assert!(qmk.warnings.is_empty());
+        assert!(qmk
+            .output
+            .contains("[_BASE] = LAYOUT(\nKC_ESC, LT(_NAV, KC_SPC), LGUI_T(KC_A)\n),"));
+        assert!(qmk
+            .output
+            .contains("[_NAV] = LAYOUT(\n_______, KC_LEFT, TG(_NAV)\n),"));
+    }
+}